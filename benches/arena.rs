@@ -0,0 +1,251 @@
+//! Builds the same synthetic tree with the streaming arena builder and with the collect-then-link
+//! `Arc<Mutex<_>>` per node layout it replaced, and prints the time and heap each one takes.
+//!
+//!     cargo bench --bench arena              # 5,000,000 entries
+//!     cargo bench --bench arena -- 1000000   # any other size
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use rust::scanner::{DirectoryListing, ScanEntry, ScanError, ScanOptions, ScanProgress, ScanSink, Scanner};
+use rust::system::{build_fs_model_blocking, FileMetadata, MountInfo, SymlinkInfo};
+
+// Keeps a running total of live heap bytes and the highest it got
+struct CountingAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+        if !pointer.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_pointer = System.realloc(pointer, layout, new_size);
+        if !new_pointer.is_null() {
+            if new_size > layout.size() {
+                let current = CURRENT.fetch_add(new_size - layout.size(), Ordering::Relaxed) + new_size - layout.size();
+                PEAK.fetch_max(current, Ordering::Relaxed);
+            } else {
+                CURRENT.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_pointer
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ROOT: &str = "/synthetic";
+const DEFAULT_ENTRIES: usize = 5_000_000;
+// Every directory gets this many subdirectories and files, breadth first, until the count is reached
+const SUBDIRECTORIES: usize = 8;
+const FILES: usize = 40;
+
+struct SyntheticScanner {
+    entries: usize,
+}
+
+impl Scanner for SyntheticScanner {
+    fn name(&self) -> &'static str {
+        "synthetic"
+    }
+
+    fn scan(&self, _path: &str, _options: &ScanOptions, _progress: &ScanProgress, sink: &dyn ScanSink) {
+        let mut directories = VecDeque::from([ROOT.to_string()]);
+        let mut inode = 1;
+        let mut remaining = self.entries;
+
+        while remaining > 0 {
+            let Some(directory) = directories.pop_front() else {
+                break;
+            };
+            let mut listing = DirectoryListing::default();
+            for i in 0..SUBDIRECTORIES {
+                inode += 1;
+                let path = format!("{}/dir{}", directory, i);
+                listing.entries.push(ScanEntry::new(path.clone(), 4096, false).with_metadata(metadata(inode)));
+                directories.push_back(path);
+            }
+            for i in 0..FILES {
+                inode += 1;
+                let size = inode * 2_654_435_761 % 1_000_000;
+                let path = format!("{}/file{}.dat", directory, i);
+                listing.entries.push(ScanEntry::new(path, size, true).with_metadata(metadata(inode)));
+            }
+            listing.entries.truncate(remaining);
+            remaining -= listing.entries.len();
+            sink.directory(&directory, listing);
+        }
+    }
+}
+
+// Gathers every entry into one list, the way scanners used to return them
+#[derive(Default)]
+struct Collector(Mutex<Vec<ScanEntry>>);
+
+impl ScanSink for Collector {
+    fn add(&self, _directory: &str, listing: DirectoryListing) {
+        self.0.lock().unwrap().extend(listing.entries);
+    }
+
+    fn finish(&self, _directory: &str) {}
+}
+
+fn metadata(inode: u64) -> FileMetadata {
+    FileMetadata { inode, device: 1, nlink: 1, mode: 0o644, ..FileMetadata::default() }
+}
+
+// The layout before the arena: one allocation per node, each with its own name and full path
+#[allow(dead_code)]
+struct LegacyNode {
+    name: String,
+    path: PathBuf,
+    is_file: bool,
+    size: u64,
+    allocated_size: u64,
+    parent: Option<Weak<Mutex<LegacyNode>>>,
+    children: Vec<Arc<Mutex<LegacyNode>>>,
+    to_be_deleted: bool,
+    metadata: FileMetadata,
+    symlink: Option<SymlinkInfo>,
+    excluded: u64,
+    mount: Option<MountInfo>,
+    scan_errors: Vec<ScanError>,
+    incomplete: bool,
+}
+
+impl LegacyNode {
+    fn new(name: String, entry: ScanEntry) -> Self {
+        Self {
+            name,
+            path: PathBuf::from(entry.path),
+            is_file: entry.is_file,
+            size: entry.size,
+            allocated_size: entry.allocated_size,
+            parent: None,
+            children: Vec::new(),
+            to_be_deleted: false,
+            metadata: entry.metadata,
+            symlink: entry.symlink,
+            excluded: 0,
+            mount: None,
+            scan_errors: Vec::new(),
+            incomplete: false,
+        }
+    }
+}
+
+// What `build_fs_model` used to do: collect the whole scan, then a map of every path, a linking
+// pass and a recursive size pass
+fn legacy_build(scanner: &SyntheticScanner) -> Arc<Mutex<LegacyNode>> {
+    let collector = Collector::default();
+    scanner.scan(ROOT, &ScanOptions::default(), &ScanProgress::new(), &collector);
+    let mut nodes: HashMap<String, Arc<Mutex<LegacyNode>>> = HashMap::new();
+    let root = Arc::new(Mutex::new(LegacyNode::new(ROOT.to_string(), ScanEntry::new(ROOT.to_string(), 0, false))));
+    nodes.insert(ROOT.to_string(), root.clone());
+
+    for entry in collector.0.into_inner().unwrap() {
+        let name = Path::new(&entry.path).file_name().unwrap().to_string_lossy().into_owned();
+        nodes.insert(entry.path.clone(), Arc::new(Mutex::new(LegacyNode::new(name, entry))));
+    }
+
+    for (path, node) in &nodes {
+        if let Some(parent) = Path::new(path).parent().and_then(|parent| nodes.get(parent.to_string_lossy().as_ref())) {
+            node.lock().unwrap().parent = Some(Arc::downgrade(parent));
+            parent.lock().unwrap().children.push(node.clone());
+        }
+    }
+
+    legacy_populate_size(&root);
+    root
+}
+
+fn legacy_populate_size(node: &Arc<Mutex<LegacyNode>>) -> (u64, u64) {
+    let children = node.lock().unwrap().children.clone();
+    let mut totals = {
+        let locked_node = node.lock().unwrap();
+        (locked_node.size, locked_node.allocated_size)
+    };
+    for child in &children {
+        let (size, allocated_size) = legacy_populate_size(child);
+        totals.0 += size;
+        totals.1 += allocated_size;
+    }
+    let mut locked_node = node.lock().unwrap();
+    locked_node.size = totals.0;
+    locked_node.allocated_size = totals.1;
+    totals
+}
+
+struct Measurement {
+    elapsed: Duration,
+    retained: usize,
+    peak: usize,
+}
+
+// Runs `build` and reports how long it took, what it left allocated and how high the heap went
+fn measure<T>(build: impl FnOnce() -> T) -> (T, Measurement) {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let started = Instant::now();
+    let result = build();
+    let measurement = Measurement {
+        elapsed: started.elapsed(),
+        retained: CURRENT.load(Ordering::Relaxed) - before,
+        peak: PEAK.load(Ordering::Relaxed) - before,
+    };
+    (result, measurement)
+}
+
+fn report(label: &str, measurement: &Measurement) {
+    println!(
+        "{:<8} {:>10.2?}  retained {:>10}  peak {:>10}",
+        label,
+        measurement.elapsed,
+        humansize::format_size(measurement.retained, humansize::BINARY),
+        humansize::format_size(measurement.peak, humansize::BINARY)
+    );
+}
+
+fn main() {
+    // `cargo bench` passes `--bench` along; the first number is the entry count
+    let entries = std::env::args().skip(1).find_map(|arg| arg.replace('_', "").parse().ok()).unwrap_or(DEFAULT_ENTRIES);
+    let scanner = SyntheticScanner { entries };
+    println!("Synthetic tree of {} entries", entries);
+
+    let (tree, arena) = measure(|| build_fs_model_blocking(ROOT.to_string(), &scanner, ScanOptions::default(), &ScanProgress::new()));
+    let tree = tree.expect("synthetic scan cannot be cancelled");
+    assert_eq!(tree.node_count(), entries + 1);
+    let arena_size = tree.node(tree.root()).size();
+    drop(tree);
+
+    let (root, legacy) = measure(|| legacy_build(&scanner));
+    assert_eq!(root.lock().unwrap().size, arena_size);
+    drop(root);
+
+    report("arena", &arena);
+    report("legacy", &legacy);
+    println!(
+        "arena uses {:.1}x less memory once built, {:.1}x less at peak, and loads {:.1}x faster",
+        legacy.retained as f64 / arena.retained as f64,
+        legacy.peak as f64 / arena.peak as f64,
+        legacy.elapsed.as_secs_f64() / arena.elapsed.as_secs_f64()
+    );
+}
@@ -105,7 +105,7 @@ pub async fn ask(question: String, context: String) -> Option<String> {
     let response = client.chat().create(request).await.ok()?;
     
     // Extract the response content
-    let content = response.choices.get(0)?.message.content.clone();
+    let content = response.choices.first()?.message.content.clone();
     
     content
 }
@@ -0,0 +1,257 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use regex::Regex;
+
+/// Name of the ignore file picked up from the root of every scan.
+pub const IGNORE_FILE: &str = ".fsmignore";
+
+#[derive(Debug, Clone)]
+struct Rule {
+    regex: Regex,
+    negated: bool,
+    directory_only: bool,
+}
+
+/// A list of gitignore-style patterns, matched against paths relative to the scan root.
+///
+/// `*`, `?` and `[...]` stay within one path component and `**` spans any number of them.
+/// A pattern containing a `/` is anchored at the root, otherwise it matches a name at any
+/// depth. A trailing `/` only matches directories, and a leading `!` re-includes what an
+/// earlier pattern excluded. The last matching pattern wins.
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeSet {
+    rules: Vec<Rule>,
+}
+
+impl ExcludeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn add(&mut self, pattern: &str) -> Result<(), String> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return Ok(());
+        }
+
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (directory_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return Err("Empty exclude pattern".to_string());
+        }
+
+        let prefix = if anchored { "^" } else { "^(?:.*/)?" };
+        let regex = Regex::new(&format!("{}{}$", prefix, glob_to_regex(pattern)))
+            .map_err(|err| format!("Invalid exclude pattern '{}': {}", pattern, err))?;
+
+        self.rules.push(Rule { regex, negated, directory_only });
+        Ok(())
+    }
+
+    /// Adds every line of a gitignore-style file.
+    pub fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines() {
+            if let Err(err) = self.add(line) {
+                eprintln!("{}: {}", path.display(), err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_excluded(&self, relative_path: &str, is_dir: bool) -> bool {
+        let relative_path = relative_path.trim_start_matches('/');
+        let mut excluded = false;
+        for rule in &self.rules {
+            if rule.directory_only && !is_dir {
+                continue;
+            }
+            if rule.regex.is_match(relative_path) {
+                excluded = !rule.negated;
+            }
+        }
+        excluded
+    }
+
+    /// Like `is_excluded`, but also true when any directory between `root` and `path` is excluded.
+    pub fn is_excluded_below(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        if self.is_excluded(&relative.to_string_lossy(), is_dir) {
+            return true;
+        }
+        relative
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.is_excluded(&ancestor.to_string_lossy(), true))
+    }
+}
+
+/// Path of `entry_path` below `root`, which is what exclude patterns are matched against.
+pub fn relative_to<'a>(root: &str, entry_path: &'a str) -> &'a str {
+    entry_path
+        .strip_prefix(root)
+        .unwrap_or(entry_path)
+        .trim_start_matches('/')
+}
+
+/// The body of a regular expression matching what `glob` does. `*`, `?` and `[...]` stay within one
+/// path component and `**` spans any number of them.
+pub fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match chars[i..].iter().position(|&c| c == ']') {
+                Some(end) if end > 1 => {
+                    let class: String = chars[i + 1..i + end].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => class,
+                    };
+                    regex.push('[');
+                    regex.push_str(&class.replace('\\', "\\\\"));
+                    regex.push(']');
+                    i += end + 1;
+                    continue;
+                }
+                _ => regex.push_str("\\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{MemoryScanner, ScanEntry, ScanOptions, ScanProgress};
+    use crate::system::build_fs_model_blocking;
+
+    fn rules(patterns: &[&str]) -> ExcludeSet {
+        let mut set = ExcludeSet::new();
+        for pattern in patterns {
+            set.add(pattern).unwrap();
+        }
+        set
+    }
+
+    #[test]
+    fn negation_re_includes_what_an_earlier_pattern_excluded() {
+        let set = rules(&["*.log", "!keep.log"]);
+        assert!(set.is_excluded("debug.log", false));
+        assert!(set.is_excluded("nested/debug.log", false));
+        assert!(!set.is_excluded("keep.log", false));
+        assert!(!set.is_excluded("nested/keep.log", false));
+
+        // The last matching pattern wins
+        let set = rules(&["!keep.log", "*.log"]);
+        assert!(set.is_excluded("keep.log", false));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_are_anchored_at_the_root() {
+        let set = rules(&["build/output", "/target", "cache"]);
+        assert!(set.is_excluded("build/output", true));
+        assert!(!set.is_excluded("src/build/output", true));
+        assert!(set.is_excluded("target", true));
+        assert!(!set.is_excluded("src/target", true));
+        assert!(set.is_excluded("cache", true));
+        assert!(set.is_excluded("deep/down/cache", false));
+    }
+
+    #[test]
+    fn double_star_spans_components_and_single_star_does_not() {
+        let set = rules(&["docs/**/*.tmp", "src/*.bak"]);
+        assert!(set.is_excluded("docs/a.tmp", false));
+        assert!(set.is_excluded("docs/a/b/c.tmp", false));
+        assert!(!set.is_excluded("other/docs/a.tmp", false));
+        assert!(set.is_excluded("src/old.bak", false));
+        assert!(!set.is_excluded("src/nested/old.bak", false));
+
+        let set = rules(&["logs/**"]);
+        assert!(set.is_excluded("logs/today/app.txt", false));
+        assert!(!set.is_excluded("logs", true));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        let set = rules(&["node_modules/"]);
+        assert!(set.is_excluded("node_modules", true));
+        assert!(set.is_excluded("web/node_modules", true));
+        assert!(!set.is_excluded("node_modules", false));
+    }
+
+    #[test]
+    fn everything_below_an_excluded_directory_is_excluded() {
+        let set = rules(&["target/"]);
+        let root = Path::new("/root");
+        assert!(set.is_excluded_below(root, Path::new("/root/target/debug/app"), false));
+        assert!(!set.is_excluded_below(root, Path::new("/root/src/main.rs"), false));
+        assert!(!set.is_excluded_below(root, Path::new("/elsewhere/target/app"), false));
+    }
+
+    #[test]
+    fn comments_blank_lines_and_empty_patterns() {
+        let mut set = ExcludeSet::new();
+        set.add("# a comment").unwrap();
+        set.add("   ").unwrap();
+        assert!(set.is_empty());
+        assert!(set.add("/").is_err());
+    }
+
+    #[test]
+    fn ignore_file_in_the_root_overrides_exclude_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy().into_owned();
+        let exclude_from = dir.path().join("patterns");
+        fs::write(&exclude_from, "*.log\n!wanted.txt\n").unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "!keep.log\n*.txt\n").unwrap();
+
+        // Loaded the way the command line does, before the scan picks up the ignore file
+        let mut exclude = ExcludeSet::new();
+        exclude.add_file(&exclude_from).unwrap();
+        let options = ScanOptions { exclude, ..ScanOptions::default() };
+
+        let names = ["keep.log", "drop.log", "wanted.txt"];
+        let entries = names.iter().map(|name| ScanEntry::new(format!("{}/{}", root, name), 1, true)).collect();
+        let tree = build_fs_model_blocking(root, &MemoryScanner::new(entries), options, &ScanProgress::new()).unwrap();
+
+        assert!(tree.child_named(tree.root(), "keep.log").is_some());
+        assert!(tree.child_named(tree.root(), "drop.log").is_none());
+        assert!(tree.child_named(tree.root(), "wanted.txt").is_none());
+    }
+}
@@ -4,6 +4,9 @@ use std::rc::Rc;
 use tokio::sync::mpsc;
 
 use crate::threads::*;
+use crate::listing::{DisplayOptions, SortKey};
+use crate::query::SavedQueries;
+use crate::scanner::ScanSnapshot;
 
 pub fn run_app(to_backend: mpsc::Sender<Command>, from_backend: mpsc::Receiver<BackendResponse>) -> Result<(), eframe::Error> {
     let options = eframe::NativeOptions::default();
@@ -17,8 +20,11 @@ pub fn run_app(to_backend: mpsc::Sender<Command>, from_backend: mpsc::Receiver<B
 #[derive(Clone)]
 enum PageState {
     Home,
-    Load { directory: String, display_text: String },
-    Files { display_text: String, input_text: String, response_text: String, ai_input: String, ai_output: String},
+    // `progress` is set while a scan is running
+    Load { directory: String, display_text: String, progress: Option<ScanSnapshot> },
+    // `display_options` is how the listing was last asked for, which the column headers show and change;
+    // `saved_queries` are the names of the queries there is a button for
+    Files { display_text: String, input_text: String, response_text: String, ai_input: String, ai_output: String, display_options: DisplayOptions, saved_queries: Vec<String> },
 }
 
 struct AppState {
@@ -72,6 +78,7 @@ impl MyApp {
             self.state.borrow_mut().current_page = PageState::Load {
                 directory: String::new(),
                 display_text: String::new(),
+                progress: None,
             };
         }
         if ui.button("Go to Files Page").clicked() {
@@ -81,6 +88,8 @@ impl MyApp {
                 response_text: String::new(),
                 ai_input: String::new(),
                 ai_output: String::new(),
+                display_options: DisplayOptions::default(),
+                saved_queries: saved_query_names(),
             };
         }
     }
@@ -88,14 +97,19 @@ impl MyApp {
     fn show_load_page(&mut self, ui: &mut egui::Ui) {
         ui.heading("Load Page");
     
-        let (directory, display_text) = {
+        let (directory, display_text, progress) = {
             let mut state = self.state.borrow_mut();
-            if let PageState::Load { directory, display_text } = &mut state.current_page {
-                (directory.clone(), display_text.clone())
+            if let PageState::Load { directory, display_text, progress } = &mut state.current_page {
+                (directory.clone(), display_text.clone(), progress.clone())
             } else {
                 return; // Exit if not on the Load page
             }
         };
+
+        if let Some(progress) = progress {
+            self.show_load_progress(ui, &directory, &progress);
+            return;
+        }
     
         // Work with extracted values
         let mut updated_directory = directory;
@@ -103,6 +117,7 @@ impl MyApp {
             self.state.borrow_mut().current_page = PageState::Load {
                 directory: updated_directory.clone(),
                 display_text: display_text.clone(),
+                progress: None,
             };
         }
     
@@ -122,13 +137,10 @@ impl MyApp {
                     }
                 } else {
                     let mut state = self.state.borrow_mut();
-                    state.current_page = PageState::Files {
-                        display_text: String::new(),
-                        input_text: String::new(),
-                        response_text: String::new(),
-                        ai_input: String::new(),
-                        ai_output: String::new()
-                    };
+                    if let PageState::Load { display_text, progress, .. } = &mut state.current_page {
+                        display_text.clear();
+                        *progress = Some(ScanSnapshot::default());
+                    }
                 }
             }
         }
@@ -142,6 +154,26 @@ impl MyApp {
             state.current_page = PageState::Home;
         }
     }
+
+
+    fn show_load_progress(&mut self, ui: &mut egui::Ui, directory: &str, progress: &ScanSnapshot) {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label(format!("Scanning {}", directory.trim()));
+        });
+        ui.label(format!("Directories visited: {}", progress.directories));
+        ui.label(format!("Entries found: {}", progress.entries));
+        ui.label(format!("Bytes tallied: {}", humansize::format_size(progress.bytes, humansize::BINARY)));
+        ui.label(format!("Current path: {}", progress.current_path));
+
+        if ui.button("Cancel").clicked() {
+            if let Err(err) = self.to_backend.try_send(Command::CancelLoad) {
+                if let PageState::Load { display_text, .. } = &mut self.state.borrow_mut().current_page {
+                    *display_text = format!("Failed to send command: {}", err);
+                }
+            }
+        }
+    }
         
     fn show_files_page(&mut self, ui: &mut egui::Ui) {
         ui.heading("Files Page");
@@ -152,9 +184,44 @@ impl MyApp {
             response_text,
             ai_input,
             ai_output,
-            ..
+            display_options,
+            saved_queries,
         } = &mut self.state.borrow_mut().current_page
         {
+            // Clicking a column lists the current directory sorted by it, clicking it again flips the order
+            ui.horizontal(|ui| {
+                for key in SortKey::ALL {
+                    let label = match display_options.sort {
+                        Some(sort) if sort == key => format!("{} {}", key.label(), if display_options.descending { "⬇" } else { "⬆" }),
+                        _ => key.label().to_string(),
+                    };
+                    if ui.button(label).clicked() {
+                        if display_options.sort == Some(key) {
+                            display_options.descending = !display_options.descending;
+                        } else {
+                            display_options.sort = Some(key);
+                            display_options.descending = key.descending_by_default();
+                        }
+                        if let Err(err) = self.to_backend.try_send(Command::Display(display_options.clone())) {
+                            *response_text = format!("Error sending command: {}", err);
+                        }
+                    }
+                }
+            });
+
+            if !saved_queries.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.label("Saved queries:");
+                    for name in saved_queries.iter() {
+                        if ui.button(name).clicked() {
+                            if let Err(err) = self.to_backend.try_send(Command::RunQuery(name.clone())) {
+                                *response_text = format!("Error sending command: {}", err);
+                            }
+                        }
+                    }
+                });
+            }
+
             // Display file content
             egui::ScrollArea::vertical()
                 .max_height(ui.available_height() * 0.9)
@@ -176,6 +243,12 @@ impl MyApp {
                 let trimmed_input = input_text.trim();
                 if !trimmed_input.is_empty() {
                     let command = parse_command(trimmed_input);
+                    match &command {
+                        Command::Display(options) => *display_options = options.clone(),
+                        Command::SaveQuery(name, _) if !saved_queries.contains(name) => saved_queries.push(name.clone()),
+                        Command::RemoveQuery(name) => saved_queries.retain(|saved| saved != name),
+                        _ => {}
+                    }
                     if let Err(err) = self.to_backend.try_send(command) {
                         *response_text = format!("Error sending command: {}", err);
                     }
@@ -259,11 +332,40 @@ impl MyApp {
                 }
                 BackendResponse::Error(error) => {
                     // Handle backend error, update state or UI
-                    if let PageState::Load { display_text, .. } = &mut self.state.borrow_mut().current_page {
+                    if let PageState::Load { display_text, progress, .. } = &mut self.state.borrow_mut().current_page {
                         *display_text = format!("Error: {}", error);
+                        *progress = None;
+                    }
+                }
+                BackendResponse::LoadProgress(snapshot) => {
+                    if let PageState::Load { progress: Some(progress), .. } = &mut self.state.borrow_mut().current_page {
+                        *progress = snapshot;
+                    }
+                }
+                BackendResponse::Loaded(message) => {
+                    self.state.borrow_mut().current_page = PageState::Files {
+                        display_text: message,
+                        input_text: String::new(),
+                        response_text: String::new(),
+                        ai_input: String::new(),
+                        ai_output: String::new(),
+                        display_options: DisplayOptions::default(),
+                        saved_queries: saved_query_names(),
+                    };
+                }
+                BackendResponse::LoadCancelled(message) => {
+                    if let PageState::Load { display_text, progress, .. } = &mut self.state.borrow_mut().current_page {
+                        *display_text = message;
+                        *progress = None;
                     }
                 }
             }
         }
     }
 }
+
+fn saved_query_names() -> Vec<String> {
+    SavedQueries::load(SavedQueries::default_path())
+        .map(|saved| saved.names().map(str::to_string).collect())
+        .unwrap_or_default()
+}
@@ -1,19 +1,35 @@
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::ffi::CStr;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::collections::{HashMap, VecDeque};
 
-use crate::system::{disown, prune, FileSystemNode};
+use crate::system::{build_fs_model_blocking, build_node, reconcile, ExtensionTotals, FileMetadata, FileSystemNode, FileSystemTree, MountInfo, NodeId, NodeKind, PendingPaths, Reconciliation};
+use crate::watch::WatchEvent;
+use crate::scanner::{ScanError, ScanOptions, ScanProgress, Scanner};
+use crate::listing::{self, DisplayOptions, SortKey};
+use crate::search::SearchQuery;
+use crate::query::Query;
+use crate::transaction::{self, Operation, Outcome};
+use crate::trash::{Trash, TrashedItem};
 use crate::ai;
 
+// Changed items listed per action before the rest are only counted
+const DRIFT_SHOWN: usize = 10;
+
+#[allow(clippy::upper_case_acronyms)]
 enum KernelAction {
     CREATE {
         path: PathBuf,
-        is_file: bool
+        is_file: bool,
+        // The topmost entry the creation added to the model, if it had to add any
+        created: Option<NodeId>
     },
     DELETE {
-        target:Arc<Mutex<FileSystemNode>>
+        target: NodeId
     },
     MOVE {
         original_path: String,
@@ -21,25 +37,94 @@ enum KernelAction {
     },
 }
 
+/// Which of a node's two sizes `display` and `status` report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SizeMode {
+    /// Logical file length, as `ls -l` shows it
+    #[default]
+    Apparent,
+    /// Space reserved on disk, as `du` shows it
+    Allocated,
+}
+
+impl std::fmt::Display for SizeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeMode::Apparent => write!(f, "apparent"),
+            SizeMode::Allocated => write!(f, "allocated"),
+        }
+    }
+}
+
 pub struct Kernel {
-    root: Arc<Mutex<FileSystemNode>>,
+    tree: FileSystemTree,
     // marked_for_deletion: Vec<Rc<Mutex<FileSystemNode>>>,
     actions: VecDeque<KernelAction>,
     ai_suggestion: String,
     action_file: bool,
-    dry:bool
+    dry:bool,
+    size_mode: SizeMode,
+    display_options: DisplayOptions,
+    // Where deleted items go, when they are not removed for good
+    trash: Option<Trash>,
+    // What the last `search` found, which `#1`, `#2` and so on refer to
+    search_results: Vec<NodeId>,
 }
 
 impl Kernel {
 
-    pub fn new(root: Arc<Mutex<FileSystemNode>>, action_file: bool, dry: bool) -> Self {
+    pub fn new(tree: FileSystemTree, action_file: bool, dry: bool, trash: Option<Trash>) -> Self {
         Kernel {
-            root: root.clone(),
+            tree,
             // marked_for_deletion: Vec::new(),
             actions: VecDeque::new(),
             ai_suggestion: String::new(),
             action_file,
-            dry
+            dry,
+            size_mode: SizeMode::default(),
+            display_options: DisplayOptions::default(),
+            trash,
+            search_results: Vec::new(),
+        }
+    }
+
+    pub fn tree(&self) -> &FileSystemTree {
+        &self.tree
+    }
+
+    pub fn tree_mut(&mut self) -> &mut FileSystemTree {
+        &mut self.tree
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.tree.root()
+    }
+
+    pub fn set_size_mode(&mut self, size_mode: SizeMode) {
+        self.size_mode = size_mode;
+    }
+
+    pub fn get_size_mode(&self) -> SizeMode {
+        self.size_mode
+    }
+
+    /// Remembers how the listing was last asked for, so it can be shown the same way again.
+    pub fn set_display_options(&mut self, display_options: DisplayOptions) {
+        self.display_options = display_options;
+    }
+
+    pub fn get_display_options(&self) -> &DisplayOptions {
+        &self.display_options
+    }
+
+    pub fn has_pending_actions(&self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn node_size(&self, node: &FileSystemNode) -> u64 {
+        match self.size_mode {
+            SizeMode::Apparent => node.size(),
+            SizeMode::Allocated => node.allocated_size(),
         }
     }
 
@@ -63,69 +148,394 @@ impl Kernel {
     }
 
 
-    pub fn display(&self, node: Arc<Mutex<FileSystemNode>>) -> String {
-        let borrowed = node.lock().unwrap();
-        let mut display = format!("\nCurrent Directory: {}\n", borrowed.get_path().to_string_lossy());
-    
-        borrowed.for_each_child(|i, child| {
-            let child_node = child.lock().unwrap();
-            if !child_node.is_marked() {
-                let node_type = if child_node.is_file() { "[File]" } else { "[Directory]" };
-                display.push_str(&format!(
-                    "[{}] {} ({} {})\n",
-                    i,
-                    child_node.get_name(),
-                    self.format_size(child_node.size()),
-                    node_type
-                ));
+    fn format_time(&self, timestamp: i64) -> String {
+        if timestamp == 0 {
+            return "-".to_string();
+        }
+
+        // Render in the local timezone, as `ls -l` would
+        let time = timestamp as libc::time_t;
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return timestamp.to_string();
+        }
+
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min
+        )
+    }
+
+    fn format_kind(&self, node: &FileSystemNode) -> &'static str {
+        match (node.kind(), node.symlink()) {
+            (NodeKind::Symlink, Some(link)) if link.broken => "[Broken Symlink]",
+            (NodeKind::Symlink, _) => "[Symlink]",
+            (NodeKind::File, _) => "[File]",
+            (NodeKind::Directory, _) => "[Directory]",
+        }
+    }
+
+    fn format_mount(&self, mount: &MountInfo) -> String {
+        format!(
+            "{}, {} free of {}",
+            mount.file_system,
+            self.format_size(mount.available_space),
+            self.format_size(mount.total_space)
+        )
+    }
+
+    fn format_mode(&self, mode: u32) -> String {
+        let mut permissions = String::with_capacity(9);
+        for shift in [6, 3, 0] {
+            let bits = (mode >> shift) & 0o7;
+            permissions.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            permissions.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            permissions.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        permissions
+    }
+
+    fn format_owner(&self, uid: u32, gid: u32) -> String {
+        let mut buffer = vec![0 as libc::c_char; 4096];
+
+        let user = unsafe {
+            let mut passwd: libc::passwd = mem::zeroed();
+            let mut result = std::ptr::null_mut();
+            libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result);
+            if result.is_null() {
+                uid.to_string()
+            } else {
+                CStr::from_ptr(passwd.pw_name).to_string_lossy().into_owned()
             }
-        });
+        };
+
+        let group = unsafe {
+            let mut group: libc::group = mem::zeroed();
+            let mut result = std::ptr::null_mut();
+            libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result);
+            if result.is_null() {
+                gid.to_string()
+            } else {
+                CStr::from_ptr(group.gr_name).to_string_lossy().into_owned()
+            }
+        };
+
+        format!("{}:{}", user, group)
+    }
+
+    pub fn display(&self, node: NodeId, options: &DisplayOptions) -> String {
+        let borrowed = self.tree.node(node);
+        let mut display = format!("\nCurrent Directory: {}\n", self.tree.get_path(node).to_string_lossy());
+        display.push_str(&format!(
+            "Owner: {}  Mode: {}  Inode: {} (device {})  Links: {}\n",
+            self.format_owner(borrowed.uid(), borrowed.gid()),
+            self.format_mode(borrowed.mode()),
+            borrowed.inode(),
+            borrowed.device(),
+            borrowed.nlink()
+        ));
+        display.push_str(&format!(
+            "Modified: {}  Accessed: {}  Changed: {}\n",
+            self.format_time(borrowed.modified()),
+            self.format_time(borrowed.accessed()),
+            self.format_time(borrowed.changed())
+        ));
+        if let Some(mount) = borrowed.mount() {
+            display.push_str(&format!("Mount point: {}\n", self.format_mount(mount)));
+        }
     
+        let (children, total) = listing::select(&self.tree, node, options, |child| self.node_size(child));
+        for &child in &children {
+            let child_node = self.tree.node(child);
+            let node_type = self.format_kind(child_node);
+            let name = match child_node.symlink() {
+                Some(link) => format!("{} -> {}", self.tree.get_name(child), link.target.display()),
+                None => self.tree.get_name(child).to_string(),
+            };
+            let links = if child_node.is_hard_linked() {
+                format!(" [{} links]", child_node.nlink())
+            } else {
+                String::new()
+            };
+            let mount = match child_node.mount() {
+                Some(mount) => format!(" [Mount: {}]", self.format_mount(mount)),
+                None => String::new(),
+            };
+            let incomplete = if child_node.is_incomplete() { " [Incomplete]" } else { "" };
+            let items = match options.sort {
+                Some(SortKey::Items) if child_node.kind() == NodeKind::Directory => format!(" [{} items]", child_node.children_len()),
+                _ => String::new(),
+            };
+            display.push_str(&format!(
+                "[{}] {} ({} {}){}{}{}{} {} {} modified {}\n",
+                child,
+                name,
+                self.format_size(self.node_size(child_node)),
+                node_type,
+                items,
+                links,
+                mount,
+                incomplete,
+                self.format_mode(child_node.mode()),
+                self.format_owner(child_node.uid(), child_node.gid()),
+                self.format_time(child_node.modified())
+            ));
+        }
+    
+        if options.is_filtered() {
+            display.push_str(&format!("Showing {} of {} entries\n", children.len(), total));
+        }
+
         display.push_str(&format!(
-            "Total storage used: {}\n",
-            self.format_size(borrowed.size())
+            "Total storage used: {} ({} size)\n",
+            self.format_size(self.node_size(borrowed)),
+            self.size_mode
         ));
+
+        if borrowed.excluded_count() > 0 {
+            display.push_str(&format!(
+                "Skipped by exclude patterns: {} entries\n",
+                borrowed.excluded_count()
+            ));
+        }
+
+        if borrowed.is_incomplete() {
+            display.push_str("Incomplete: some paths below could not be read, run `errors` to list them\n");
+        }
     
         display
     }
+
+    /// Counts, depth, modification times and where the bytes go by extension, for everything below `node`.
+    pub fn stats(&mut self, node: NodeId) -> String {
+        const EXTENSIONS_SHOWN: usize = 10;
+
+        let stats = self.tree.stats(node).clone();
+        let mut display = format!("\nStatistics for {}\n", self.tree.get_path(node).to_string_lossy());
+        display.push_str(&format!(
+            "Files: {}  Directories: {}  Symlinks: {}\n",
+            stats.files, stats.directories, stats.symlinks
+        ));
+        display.push_str(&format!("Deepest level below: {}\n", stats.max_depth));
+        if let (Some((newest, newest_id)), Some((oldest, oldest_id))) = (stats.newest, stats.oldest) {
+            display.push_str(&format!("Newest: {} {}\n", self.format_time(newest), self.tree.get_path(newest_id).to_string_lossy()));
+            display.push_str(&format!("Oldest: {} {}\n", self.format_time(oldest), self.tree.get_path(oldest_id).to_string_lossy()));
+        }
+
+        let size = |totals: &ExtensionTotals| match self.size_mode {
+            SizeMode::Apparent => totals.size,
+            SizeMode::Allocated => totals.allocated_size,
+        };
+        let mut extensions: Vec<(&String, &ExtensionTotals)> = stats.extensions.iter().collect();
+        extensions.sort_by(|a, b| size(b.1).cmp(&size(a.1)).then_with(|| a.0.cmp(b.0)));
+        let total: u64 = extensions.iter().map(|(_, totals)| size(totals)).sum();
+        let share = |bytes: u64| if total == 0 { 0.0 } else { bytes as f64 * 100.0 / total as f64 };
+
+        display.push_str(&format!("Files by extension ({} size, {} in total):\n", self.size_mode, self.format_size(total)));
+        for (extension, totals) in extensions.iter().take(EXTENSIONS_SHOWN) {
+            let name = if extension.is_empty() { "(none)".to_string() } else { format!(".{}", extension) };
+            display.push_str(&format!(
+                "  {:<12} {:>12} {:>6.1}%  {} files\n",
+                name,
+                self.format_size(size(totals)),
+                share(size(totals)),
+                totals.files
+            ));
+        }
+        if extensions.len() > EXTENSIONS_SHOWN {
+            let rest = &extensions[EXTENSIONS_SHOWN..];
+            let bytes: u64 = rest.iter().map(|(_, totals)| size(totals)).sum();
+            let files: u64 = rest.iter().map(|(_, totals)| totals.files).sum();
+            display.push_str(&format!(
+                "  {:<12} {:>12} {:>6.1}%  {} files\n",
+                format!("{} more", rest.len()),
+                self.format_size(bytes),
+                share(bytes),
+                files
+            ));
+        }
+        display
+    }
+
+    /// Looks through everything below `node` and lists what `query` matches, numbered so that
+    /// `#1`, `#2` and so on can name a result in later commands.
+    pub fn search(&mut self, node: NodeId, query: &SearchQuery) -> String {
+        let found = self.tree.find_named(node, |name| query.matches_name(name));
+        self.search_results = listing::arrange(&self.tree, found, &query.options, |found| self.node_size(found));
+        self.list_results(node)
+    }
+
+    /// Runs a `select` below `node`, or below the directory it names after `under`, and lists what
+    /// it picks the way `search` does.
+    pub fn select(&mut self, node: NodeId, query: &Query) -> Result<String, String> {
+        let node = match query.under() {
+            Some(under) => {
+                let path = match under.strip_prefix('~') {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", std::env::var("HOME").unwrap_or_default(), rest),
+                    _ => under.to_string(),
+                };
+                match self.resolve_path(node, &path) {
+                    Some(under) if !self.tree.node(under).is_file() => under,
+                    Some(_) => return Err(format!("{} is not a directory.", under)),
+                    None => return Err(format!("{} is not in the loaded tree.", under)),
+                }
+            }
+            None => node,
+        };
+        self.search_results = query.run(&self.tree, node, |found| self.node_size(found));
+        Ok(self.list_results(node))
+    }
+
+    fn list_results(&self, node: NodeId) -> String {
+        const RESULTS_SHOWN: usize = 200;
+
+        let path = self.tree.get_path(node).to_string_lossy().into_owned();
+        if self.search_results.is_empty() {
+            return format!("Nothing below {} matches.", path);
+        }
+
+        let count = self.search_results.len();
+        let mut display = format!("\n{} {} below {}:\n", count, if count == 1 { "match" } else { "matches" }, path);
+        for (number, &id) in self.search_results.iter().enumerate().take(RESULTS_SHOWN) {
+            let found = self.tree.node(id);
+            display.push_str(&format!(
+                "#{} [{}] {} ({} {}) modified {}\n",
+                number + 1,
+                id,
+                self.tree.get_path(id).to_string_lossy(),
+                self.format_size(self.node_size(found)),
+                self.format_kind(found),
+                self.format_time(found.modified())
+            ));
+        }
+        if self.search_results.len() > RESULTS_SHOWN {
+            display.push_str(&format!(
+                "{} more not shown; add filters or a limit to narrow it down\n",
+                self.search_results.len() - RESULTS_SHOWN
+            ));
+        }
+        display.push_str("Pass #N to del, move, open or go to to act on a result, or #all, #2-5 or #1,4 to del or move several.\n");
+        display
+    }
+
+    /// Lists every path below `node` that the scan could not read.
+    pub fn scan_errors(&self, node: NodeId) -> String {
+        let errors: Vec<&ScanError> = self.tree.walk(node).flat_map(|id| self.tree.node(id).scan_errors()).collect();
+
+        let path = self.tree.get_path(node).to_string_lossy().into_owned();
+        if errors.is_empty() {
+            return format!("Everything below {} was read.", path);
+        }
+
+        let mut display = format!("{} unreadable paths below {}:\n", errors.len(), path);
+        for error in &errors {
+            display.push_str(&format!("{}\n", error));
+        }
+        if errors.iter().any(|error| error.is_permission_denied()) {
+            display.push_str("Some paths were denied access; loading again with elevated privileges may read them.\n");
+        }
+        display
+    }
     
 
-    pub fn get_parent(&self, node: Arc<Mutex<FileSystemNode>>) -> Option<Weak<Mutex<FileSystemNode>>> {
-        node.lock().unwrap().get_parent()
+    pub fn get_parent(&self, node: NodeId) -> Option<NodeId> {
+        self.tree.get_parent(node)
     }
 
 
-    pub fn get_child(&self, node: Arc<Mutex<FileSystemNode>>, index: usize) -> Option<Arc<Mutex<FileSystemNode>>> {
-        let borrowed = node.lock().unwrap();
-        let child = borrowed.get_child(index);
+    /// Finds what a command refers to: a node ID as `display` lists it, `#N` for the Nth result
+    /// of the last search, or a path that is either absolute or relative to `node`. A bare name
+    /// is a relative path of one component, and `./` reaches an entry whose name is a number or
+    /// starts with `#`.
+    pub fn resolve(&self, node: NodeId, target: &str) -> Option<NodeId> {
+        // IDs are never reused, so one that is still in the tree is still the entry it was shown as
+        if let Ok(id) = target.parse::<NodeId>() {
+            return self.tree.contains(id).then_some(id);
+        }
+        if let Some(number) = target.strip_prefix('#') {
+            let id = *self.search_results.get(number.parse::<usize>().ok()?.checked_sub(1)?)?;
+            return self.tree.contains(id).then_some(id);
+        }
+        self.resolve_path(node, target)
+    }
 
-        match &child {
-            Some(ref node) => {
-                if node.lock().unwrap().is_file() {
-                    println!("Cannot navigate into a file.");
-                    return None;
+    /// Finds the entry at `target`, a path that is either absolute or relative to `node`, without
+    /// taking numbers as IDs. For paths that come from elsewhere than the user, like the AI's
+    /// suggestions, where `2024` is a directory and not node 2024.
+    pub fn resolve_path(&self, node: NodeId, target: &str) -> Option<NodeId> {
+        let path = Path::new(target);
+        let (mut current, relative) = match path.strip_prefix(self.tree.root_path()) {
+            Ok(relative) => (self.tree.root(), relative),
+            Err(_) if path.is_absolute() => return None,
+            Err(_) => (node, path),
+        };
+        for component in relative.components() {
+            current = match component {
+                Component::CurDir => current,
+                Component::ParentDir => self.tree.get_parent(current).unwrap_or(current),
+                Component::Normal(name) => self.tree.child_named(current, &name.to_string_lossy())?,
+                Component::RootDir | Component::Prefix(_) => return None,
+            };
+        }
+        Some(current)
+    }
+
+    /// Like `resolve`, but also takes several results of the last search at once: `#all`, a range
+    /// such as `#2-5`, or a list such as `#1,4,7`, which may mix the two.
+    pub fn resolve_selection(&self, node: NodeId, target: &str) -> Result<Vec<NodeId>, String> {
+        let invalid = || format!("Invalid selection: {}. Use #all, #N, #N-M or #N,M.", target);
+        let numbers = match target.strip_prefix('#') {
+            Some("all") => (1..=self.search_results.len()).collect(),
+            Some(selection) if selection.contains([',', '-']) => {
+                let mut numbers = Vec::new();
+                for part in selection.split(',').map(str::trim) {
+                    match part.split_once('-') {
+                        Some((first, last)) => {
+                            let (first, last): (usize, usize) = (first.trim().parse().map_err(|_| invalid())?, last.trim().parse().map_err(|_| invalid())?);
+                            numbers.extend(first..=last);
+                        }
+                        None => numbers.push(part.parse().map_err(|_| invalid())?),
+                    }
                 }
-            },
-            None => println!("Invalid index."),
+                numbers
+            }
+            _ => return self.resolve(node, target).map(|id| vec![id]).ok_or_else(|| format!("No such item: {}.", target)),
+        };
+
+        let mut selected = Vec::new();
+        for number in numbers {
+            let id = number
+                .checked_sub(1)
+                .and_then(|index| self.search_results.get(index))
+                .filter(|&&id| self.tree.contains(id))
+                .ok_or_else(|| format!("No such item: #{}.", number))?;
+            if !selected.contains(id) {
+                selected.push(*id);
+            }
+        }
+        if selected.is_empty() {
+            return Err("Nothing selected; run `search` or `select` first.".to_string());
         }
-        child
+        Ok(selected)
     }
 
-    pub fn get_index(&self, node: Arc<Mutex<FileSystemNode>>, child: String) -> Option<usize> {
-        let borrowed = node.lock().unwrap();
-        let mut i = 0;
-        let l = borrowed.children_len();
-        let path = PathBuf::from(child);
+    pub fn get_directory(&self, node: NodeId, target: &str) -> Option<NodeId> {
+        let directory = self.resolve(node, target);
 
-        while i < l {
-            if let Some(child) = borrowed.get_child(i) {
-                if *child.lock().unwrap().get_path() == path {
-                    return Some(i);
+        match directory {
+            Some(directory) => {
+                if self.tree.node(directory).is_file() {
+                    println!("Cannot navigate into a file.");
+                    return None;
                 }
-            }
-            i +=1 ;
+            },
+            None => println!("No such item: {}.", target),
         }
-        None
+        directory
     }
 
     pub fn set_suggestion(&mut self, suggestion: String) {
@@ -136,56 +546,234 @@ impl Kernel {
         self.ai_suggestion.clone()
     }
 
-    pub fn convert_suggestions(&mut self, node: Arc<Mutex<FileSystemNode>>) {
+    pub fn convert_suggestions(&mut self, node: NodeId) {
         let commands = ai::parse_ai(&self.ai_suggestion);
         for command in commands {
             match command {
                 ai::AICommand::DeleteFile { delete_file } => {
-                    let index = self.get_index(node.clone(), delete_file.path);
-                    if let Some(i) = index {
-                        self.mark_for_deletion(node.clone(), i);
+                    match self.resolve_path(node, &delete_file.path) {
+                        Some(target) => {
+                            if let Err(err) = self.mark_for_deletion(target) {
+                                println!("{}", err);
+                            }
+                        }
+                        None => println!("No such item: {}.", delete_file.path),
                     }
                 }
                 ai::AICommand::MoveItem { move_item } => {
-                    self.move_item(move_item.original_location, move_item.new_location);
+                    if let Err(err) = self.move_item(move_item.original_location, move_item.new_location) {
+                        println!("{}", err);
+                    }
                 }
                 ai::AICommand::CreateDirectory { create_directory } => {
-                    self.create(node.clone(), create_directory.path, false);
+                    if let Err(err) = self.create(node, create_directory.path, false) {
+                        println!("{}", err);
+                    }
                 }
             }
         }
     }
 
-    pub fn go_to(&self, mut path: String) -> Option<Arc<Mutex<FileSystemNode>>> {
-        let mut current_node = Some(self.root.clone());
+    pub fn go_to(&self, mut path: String) -> Option<NodeId> {
+        let mut current_node = self.tree.root();
     
         // Adjust the path to be relative if it starts with the root's path
-        let relative_path = if let Some(root_node) = &current_node {
-            let root_str = root_node.lock().unwrap().get_path().to_string_lossy().to_string();
-            if path.starts_with(&root_str) {
-                path.split_off(root_str.len())
-            } else {
-                path
-            }
+        let root_str = self.tree.root_path().to_string_lossy().to_string();
+        let relative_path = if path.starts_with(&root_str) {
+            path.split_off(root_str.len())
         } else {
-            path // Default to the original path if no root node is found
+            path
         };
     
         // Iterate through the components of the path
         for address in relative_path.split('/').filter(|part| !part.is_empty()) {
-            if let Some(node) = current_node {
-                current_node = node.lock().unwrap().go_to(address);
-            } else {
-                return None;
-            }
+            current_node = self.tree.child_named(current_node, address)?;
         }
     
-        current_node
+        Some(current_node)
     }
     
 
+    /// Scans the subtree below `node` again and folds the result into the existing nodes.
+    /// Queued actions keep pointing at the same nodes and what they did to the model is kept.
+    pub fn rescan(&mut self, node: NodeId, scanner: &dyn Scanner, options: &ScanOptions) -> Result<Reconciliation, String> {
+        let (path, is_file) = (self.tree.get_path(node).to_string_lossy().into_owned(), self.tree.node(node).is_file());
+        if is_file {
+            return Err(format!("{} is not a directory.", path));
+        }
+        if fs::symlink_metadata(&path).is_err() {
+            return Err(format!("{} no longer exists, rescan its parent instead.", path));
+        }
+
+        let fresh = build_fs_model_blocking(path.clone(), scanner, options.clone(), &ScanProgress::new())
+            .ok_or_else(|| format!("Failed to scan {}.", path))?;
+        let pending = self.pending_paths();
+        reconcile(&mut self.tree, node, &fresh, &pending)
+    }
+
+    /// Brings the node at the event's path in line with the disk and returns the directory
+    /// whose listing changed, if any. Paths that queued actions are about to create or move
+    /// away are left alone, since the model already shows them as they will be.
+    pub fn apply_watch_event(&mut self, event: &WatchEvent, scanner: &dyn Scanner, options: &ScanOptions) -> Option<PathBuf> {
+        let root_path = self.tree.root_path().to_path_buf();
+        let path = event.path();
+        let pending = self.pending_paths();
+        if !path.starts_with(&root_path) || pending.keeps(path) || pending.skips(path) {
+            return None;
+        }
+
+        if let WatchEvent::Rescan(_) = event {
+            let node = self.go_to(path.to_string_lossy().into_owned())?;
+            return self.rescan(node, scanner, options).ok().map(|_| path.to_path_buf());
+        }
+        if path == root_path {
+            return None;
+        }
+
+        // Both kinds of event are settled by looking at the disk, since several may have been
+        // coalesced and the entry can be gone again by the time this runs
+        let metadata = fs::symlink_metadata(path).ok();
+        if options.exclude.is_excluded_below(&root_path, path, metadata.as_ref().is_some_and(|metadata| metadata.is_dir())) {
+            return None;
+        }
+        let parent_path = path.parent()?.to_path_buf();
+        let name = path.file_name()?.to_string_lossy().into_owned();
+        let node = self.go_to(path.to_string_lossy().into_owned());
+
+        match (node, metadata) {
+            (Some(node), None) => self.tree.remove(node),
+            (Some(node), Some(metadata)) => {
+                let (is_file, size, old_metadata) = {
+                    let borrowed = self.tree.node(node);
+                    (borrowed.is_file(), borrowed.size(), *borrowed.metadata())
+                };
+                let new_metadata = FileMetadata::from(&metadata);
+                if is_file == metadata.is_dir() {
+                    // Replaced by something of the other kind
+                    self.tree.remove(node);
+                    let parent = self.go_to(parent_path.to_string_lossy().into_owned())?;
+                    self.tree.graft(parent, &name, &build_node(path, &metadata, scanner, options)?).ok()?;
+                } else if is_file {
+                    if new_metadata == old_metadata && metadata.len() == size {
+                        return None;
+                    }
+                    self.tree.resize(node, metadata.len(), metadata.blocks() * 512, new_metadata);
+                } else {
+                    // A directory's own contents arrive as separate events
+                    self.tree.node_mut(node).set_metadata(new_metadata);
+                }
+            }
+            (None, Some(metadata)) => {
+                let parent = self.go_to(parent_path.to_string_lossy().into_owned())?;
+                self.tree.graft(parent, &name, &build_node(path, &metadata, scanner, options)?).ok()?;
+            }
+            (None, None) => return None,
+        }
+
+        Some(parent_path)
+    }
+
+    /// Paths where queued actions make the model and the disk disagree.
+    pub fn pending_paths(&self) -> PendingPaths {
+        let mut pending = PendingPaths::default();
+        for action in &self.actions {
+            match action {
+                KernelAction::CREATE { path, .. } => pending.model_only.push(path.clone()),
+                KernelAction::MOVE { original_path, new_path } => {
+                    pending.model_only.push(PathBuf::from(new_path));
+                    pending.disk_only.push(PathBuf::from(original_path));
+                }
+                KernelAction::DELETE { .. } => {}
+            }
+        }
+        pending
+    }
+
+    fn collect_links(&self, node: NodeId, links: &mut HashMap<(u64, u64), (u64, u64, u64)>) {
+        for id in self.tree.walk(node) {
+            let borrowed = self.tree.node(id);
+            if borrowed.is_hard_linked() {
+                let entry = links
+                    .entry(borrowed.inode_key())
+                    .or_insert((0, borrowed.nlink(), self.node_size(borrowed)));
+                entry.0 += 1;
+            }
+        }
+    }
+
+    // Bytes the queued deletions would give back, and how many of those stay on disk because
+    // other links to them lie outside the loaded directory. Deletions nested inside another
+    // queued deletion are only counted once, and a hard-linked file only frees its data once
+    // every link to it in the tree is queued.
+    fn space_freed(&self) -> (u64, u64) {
+        let targets: Vec<(PathBuf, NodeId)> = self
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                KernelAction::DELETE { target } if self.tree.contains(*target) => Some((self.tree.get_path(*target), *target)),
+                _ => None,
+            })
+            .collect();
+
+        let mut freed: u64 = 0;
+        // (links queued, link count, size, queued subtrees that charged it)
+        let mut links: HashMap<(u64, u64), (u64, u64, u64, u64)> = HashMap::new();
+
+        for (i, (path, target)) in targets.iter().enumerate() {
+            let covered = targets.iter().enumerate().any(|(j, (other, _))| {
+                (other != path && path.starts_with(other)) || (other == path && j < i)
+            });
+            if covered {
+                continue;
+            }
+
+            freed += self.node_size(self.tree.node(*target));
+
+            let mut target_links = HashMap::new();
+            self.collect_links(*target, &mut target_links);
+            for (key, (count, nlink, size)) in target_links {
+                let entry = links.entry(key).or_insert((0, nlink, size, 0));
+                entry.0 += count;
+                entry.3 += 1;
+            }
+        }
+        if links.is_empty() {
+            return (freed, 0);
+        }
+
+        let mut in_tree = HashMap::new();
+        self.collect_links(self.tree.root(), &mut in_tree);
+        let mut held: u64 = 0;
+        for (key, (queued, nlink, size, charged)) in links {
+            freed = freed.saturating_sub(size * charged);
+            let present = in_tree.get(&key).map_or(queued, |&(count, _, _)| count);
+            if queued >= present {
+                freed += size;
+                if nlink > present {
+                    held += size;
+                }
+            }
+        }
+
+        (freed, held)
+    }
+
+    // Links to a file that lie outside the loaded directory, which keep its data on disk
+    fn links_outside(&self, node: NodeId) -> u64 {
+        let borrowed = self.tree.node(node);
+        if !borrowed.is_hard_linked() {
+            return 0;
+        }
+        let key = borrowed.inode_key();
+        let present = self.tree.walk(self.tree.root()).filter(|&id| {
+            let other = self.tree.node(id);
+            other.is_hard_linked() && other.inode_key() == key
+        });
+        borrowed.nlink().saturating_sub(present.count() as u64)
+    }
+
     pub fn get_status(&self) -> String {
-        let mut total_space_saved = 0;
+        let (total_space_saved, held) = self.space_freed();
         let mut index = 0;
         let status: Vec<String> = self
             .actions
@@ -193,14 +781,21 @@ impl Kernel {
             .map(|item| {
                 
                 let action = match item {
-                    KernelAction::CREATE { path, is_file: _ } => {
-                        format!("[{}] CREATE: {}", index, path.to_string_lossy().to_string())
+                    KernelAction::CREATE { path, .. } => {
+                        format!("[{}] CREATE: {}", index, path.to_string_lossy())
                     }
-                    KernelAction::DELETE { target  } => {
-                        let borrowed_item = target.lock().unwrap();
-                        let path = borrowed_item.get_path().to_string_lossy();
-                        total_space_saved += borrowed_item.size();
-                        format!("[{}] DELETE: {} ({})", index, path.to_string(), self.format_size(borrowed_item.size()))
+                    KernelAction::DELETE { target } if !self.tree.contains(*target) => {
+                        format!("[{}] DELETE: {} (already gone from disk)", index, self.tree.get_path(*target).to_string_lossy())
+                    }
+                    KernelAction::DELETE { target } => {
+                        let path = self.tree.get_path(*target);
+                        let size = self.node_size(self.tree.node(*target));
+                        let outside = match self.links_outside(*target) {
+                            0 => String::new(),
+                            1 => ", not freed: 1 more link outside the loaded directory".to_string(),
+                            count => format!(", not freed: {} more links outside the loaded directory", count),
+                        };
+                        format!("[{}] DELETE: {} ({}{})", index, path.to_string_lossy(), self.format_size(size), outside)
                     }
                     KernelAction::MOVE { original_path, new_path } => {
                         format!("[{}] MOVE: {} -> {}", index, original_path.clone(), new_path.clone())
@@ -212,231 +807,1016 @@ impl Kernel {
             .collect();
 
         format!(
-            "The following are marked for action: \n {} \nTotal space saved: {}",
+            "The following are marked for action: \n {} \nTotal space saved: {}{}{}",
             status.join("\n"),
-            self.format_size(total_space_saved)
+            self.format_size(total_space_saved),
+            if self.trash.is_some() { " (once purged from the trash)" } else { "" },
+            if held > 0 {
+                format!("\n{} of that is not freed, still linked from outside the loaded directory", self.format_size(held))
+            } else {
+                String::new()
+            }
         )
     }
 
-    pub fn mark_for_deletion(&mut self, node: Arc<Mutex<FileSystemNode>>, index: usize) {
-        if let Some(to_delete) = node.lock().unwrap().get_child(index) {
-            // self.marked_for_deletion.push(to_delete.clone());
-            self.actions.push_back(KernelAction::DELETE { target: to_delete.clone() });
-            to_delete.lock().unwrap().delete();
+    pub fn mark_for_deletion(&mut self, to_delete: NodeId) -> Result<(), String> {
+        if to_delete == self.tree.root() {
+            return Err("Cannot delete the loaded directory itself.".to_string());
         }
+        if self.tree.node(to_delete).is_marked() {
+            return Err(format!("{} is already marked for deletion.", self.tree.get_path(to_delete).display()));
+        }
+        // self.marked_for_deletion.push(to_delete.clone());
+        self.actions.push_back(KernelAction::DELETE { target: to_delete });
+        self.tree.node_mut(to_delete).delete();
+        Ok(())
     }
 
 
-    pub fn undo_deletion(&mut self, index: usize) {
+    /// Takes the action at `index` out of the queue and puts the model back as it was before it.
+    /// An action that a later one builds on, like a creation that something is moved into, can
+    /// only be undone once the later one is.
+    pub fn undo_deletion(&mut self, index: usize) -> Result<String, String> {
+        let action = self.actions.get(index).ok_or_else(|| format!("There is no action [{}]; `status` lists the queue.", index))?;
+        let touched = self.touched_paths(action);
+        for (later, other) in self.actions.iter().enumerate().skip(index + 1) {
+            let overlaps = self
+                .touched_paths(other)
+                .iter()
+                .any(|path| touched.iter().any(|own| path.starts_with(own) || own.starts_with(path)));
+            if overlaps {
+                return Err(format!("[{}] builds on [{}]; undo it first.", later, index));
+            }
+        }
 
-        match &self.actions[index] {
-            KernelAction::DELETE {target} => {
-                target.lock().unwrap().undelete();
+        let description = self.describe(action);
+        match action {
+            KernelAction::DELETE { target } if self.tree.contains(*target) => self.tree.node_mut(*target).undelete(),
+            KernelAction::DELETE { .. } | KernelAction::CREATE { created: None, .. } => {}
+            &KernelAction::CREATE { created: Some(created), .. } => {
+                if self.tree.contains(created) {
+                    self.tree.remove(created);
+                }
+            }
+            KernelAction::MOVE { original_path, new_path } => {
+                let original = Path::new(original_path);
+                let name = original.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                let parent = original.parent().and_then(|parent| self.go_to(parent.to_string_lossy().into_owned()));
+                let (Some(node), Some(parent)) = (self.go_to(new_path.clone()), parent) else {
+                    return Err(format!("Cannot move {} back to {}, it is no longer in the model.", new_path, original_path));
+                };
+                self.tree.detach(node);
+                self.tree.attach(parent, node);
+                self.tree.set_name(node, &name);
             }
-            _=>{}
         }
         self.actions.remove(index);
+        Ok(format!("Undid [{}] {}.", index, description))
     }
 
-    pub fn commit_actions(&mut self) {
-        while let Some(action) = self.actions.pop_front() {
-            match action {
-                KernelAction::CREATE { path, is_file } => {
-                    if !self.dry {
-                        self.commit_creation(path.clone(), is_file)
-                    }
-                    if self.action_file {
-                        let _ = fs::write("changes.txt", format!("CREATE {}", path.clone().display()));
-                    }
+    // Where in the model an action makes changes, for telling which actions build on which
+    fn touched_paths(&self, action: &KernelAction) -> Vec<PathBuf> {
+        match action {
+            KernelAction::CREATE { path, created, .. } => {
+                vec![created.filter(|&id| self.tree.contains(id)).map_or_else(|| path.clone(), |id| self.tree.get_path(id))]
+            }
+            KernelAction::DELETE { target } => vec![self.tree.get_path(*target)],
+            KernelAction::MOVE { original_path, new_path } => vec![PathBuf::from(original_path), PathBuf::from(new_path)],
+        }
+    }
+
+    // Where a queued path is on disk, relative paths being taken from the loaded directory
+    // as `go_to` takes them
+    fn disk_path(&self, path: &Path) -> PathBuf {
+        let root = self.tree.root_path();
+        normalize(if path.starts_with(root) { path.to_path_buf() } else { root.join(path.strip_prefix("/").unwrap_or(path)) })
+    }
+
+    fn operation(&self, action: &KernelAction) -> Operation {
+        match action {
+            KernelAction::CREATE { path, is_file, .. } => Operation::Create { path: self.disk_path(path), is_file: *is_file },
+            KernelAction::DELETE { target } => Operation::Delete { path: self.tree.get_path(*target) },
+            KernelAction::MOVE { original_path, new_path } => Operation::Move {
+                from: self.disk_path(Path::new(original_path)),
+                to: self.disk_path(Path::new(new_path)),
+            },
+        }
+    }
+
+    fn describe(&self, action: &KernelAction) -> String {
+        match action {
+            KernelAction::CREATE { path, .. } => format!("CREATE: {}", path.display()),
+            KernelAction::DELETE { target } => format!("DELETE: {}", self.tree.get_path(*target).display()),
+            KernelAction::MOVE { original_path, new_path } => format!("MOVE: {} -> {}", original_path, new_path),
+        }
+    }
+
+    fn log_action(&self, action: &KernelAction) {
+        let line = match action {
+            KernelAction::CREATE { path, .. } => format!("CREATE {}", path.display()),
+            KernelAction::DELETE { target } => format!("DELETE {}", self.tree.get_path(*target).display()),
+            KernelAction::MOVE { original_path, new_path } => format!("MOVE {} > {}", original_path, new_path),
+        };
+        let _ = fs::write("changes.txt", line);
+    }
+
+    fn conflicts(&self, operations: &[Operation]) -> Vec<String> {
+        transaction::validate(operations)
+            .into_iter()
+            .map(|(index, problem)| format!("[{}] {}: {}", index, self.describe(&self.actions[index]), problem))
+            .collect()
+    }
+
+    // How the item at `path` differs from what the scan saw of `node`, if it does
+    fn drift_of(&self, node: &FileSystemNode, path: &Path) -> Option<String> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Some(format!("{} is no longer on disk", path.display()));
+        };
+        let mut changes = Vec::new();
+        if (metadata.dev(), metadata.ino()) != node.inode_key() {
+            changes.push("was replaced by another item".to_string());
+        } else {
+            if node.kind() != NodeKind::Directory && metadata.len() != node.size() {
+                changes.push(format!("changed size from {} to {}", self.format_size(node.size()), self.format_size(metadata.len())));
+            }
+            if metadata.mtime() != node.modified() {
+                changes.push(match node.kind() {
+                    NodeKind::Directory => format!("had entries added, removed or renamed at {}", self.format_time(metadata.mtime())),
+                    _ => format!("was modified at {}", self.format_time(metadata.mtime())),
+                });
+            }
+        }
+        (!changes.is_empty()).then(|| format!("{} {}", path.display(), changes.join(" and ")))
+    }
+
+    /// Stats every item a queued deletion or move would touch again and lists, by action index,
+    /// those that changed on disk since they were scanned. A deletion covers everything below
+    /// its target that the scan saw, so content that turned up later is never removed unseen.
+    fn drift(&self) -> Vec<String> {
+        // Items a queued action puts in place are not on disk yet
+        let placed: Vec<PathBuf> = self
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                KernelAction::CREATE { path, .. } => Some(self.disk_path(path)),
+                KernelAction::MOVE { new_path, .. } => Some(self.disk_path(Path::new(new_path))),
+                KernelAction::DELETE { .. } => None,
+            })
+            .collect();
+
+        let mut report = Vec::new();
+        for (index, action) in self.actions.iter().enumerate() {
+            let changed: Vec<String> = match action {
+                KernelAction::DELETE { target } if self.tree.contains(*target) => self
+                    .tree
+                    .walk(*target)
+                    .filter(|&id| self.tree.node(id).inode_key().1 != 0)
+                    .filter_map(|id| {
+                        let path = self.tree.get_path(id);
+                        if placed.iter().any(|placed| path.starts_with(placed)) {
+                            return None;
+                        }
+                        self.drift_of(self.tree.node(id), &path)
+                    })
+                    .collect(),
+                KernelAction::MOVE { original_path, new_path } => self
+                    .go_to(new_path.clone())
+                    .filter(|&id| self.tree.node(id).inode_key().1 != 0)
+                    .and_then(|id| self.drift_of(self.tree.node(id), &self.disk_path(Path::new(original_path))))
+                    .into_iter()
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if changed.is_empty() {
+                continue;
+            }
+            report.push(format!("[{}] {}:", index, self.describe(action)));
+            report.extend(changed.iter().take(DRIFT_SHOWN).map(|change| format!("    {}", change)));
+            if changed.len() > DRIFT_SHOWN {
+                report.push(format!("    and {} more", changed.len() - DRIFT_SHOWN));
+            }
+        }
+        report
+    }
+
+    /// Checks the queue against the disk as `commit` would before touching anything: lists
+    /// every conflict by action index, and every item that changed since it was scanned.
+    pub fn check(&self) -> Result<String, String> {
+        let operations: Vec<Operation> = self.actions.iter().map(|action| self.operation(action)).collect();
+        let conflicts = self.conflicts(&operations);
+        let drift = self.drift();
+        let mut report = Vec::new();
+        if !conflicts.is_empty() {
+            report.push(format!("{} {} found:\n{}", conflicts.len(), if conflicts.len() == 1 { "conflict" } else { "conflicts" }, conflicts.join("\n")));
+        }
+        if !drift.is_empty() {
+            report.push(format!("Changed on disk since the scan:\n{}", drift.join("\n")));
+        }
+        match (report.is_empty(), self.actions.len()) {
+            (true, 0) => Ok("Nothing is queued.".to_string()),
+            (true, 1) => Ok("The queued action can be committed.".to_string()),
+            (true, count) => Ok(format!("All {} queued actions can be committed.", count)),
+            (false, _) => Err(report.join("\n")),
+        }
+    }
+
+    // Takes the metadata of the item at `path` from disk again, so that what a commit did to it
+    // does not count as a change made behind the model's back
+    fn refresh(&mut self, path: &Path) {
+        if let (Some(node), Ok(metadata)) = (self.go_to(path.to_string_lossy().into_owned()), fs::symlink_metadata(path)) {
+            if self.tree.get_path(node) == path {
+                self.tree.node_mut(node).set_metadata(FileMetadata::from(&metadata));
+            }
+        }
+    }
+
+    /// Carries out the queue as one unit. Every action is checked against the disk first, and
+    /// nothing is touched if any of them cannot be done, or, unless `force` is set, if anything
+    /// they touch changed since it was scanned. The actions then run in queue order; if one
+    /// fails, what already went through is undone where it can be and the queue is kept.
+    /// Returns the outcome of every action, as an error if not all of them went through.
+    pub fn commit_actions(&mut self, force: bool) -> Result<String, String> {
+        if self.actions.is_empty() {
+            return Ok("Nothing to commit.".to_string());
+        }
+
+        let operations: Vec<Operation> = self.actions.iter().map(|action| self.operation(action)).collect();
+        let conflicts = self.conflicts(&operations);
+        if !conflicts.is_empty() {
+            return Err(format!("Nothing was committed. Undo or change these actions first:\n{}", conflicts.join("\n")));
+        }
+        if !force {
+            let drift = self.drift();
+            if !drift.is_empty() {
+                return Err(format!(
+                    "Nothing was committed. These items changed on disk since they were scanned:\n{}\n`rescan` shows them as they are now; `commit --force` commits anyway.",
+                    drift.join("\n")
+                ));
+            }
+        }
+
+        if self.dry {
+            let report: Vec<String> = self
+                .actions
+                .iter()
+                .enumerate()
+                .map(|(index, action)| format!("[{}] {}: skipped (dry run)", index, self.describe(action)))
+                .collect();
+            if self.action_file {
+                self.actions.iter().for_each(|action| self.log_action(action));
+            }
+            self.actions.clear();
+            return Ok(format!("Dry run, nothing was changed on disk:\n{}", report.join("\n")));
+        }
+
+        let outcomes = transaction::apply(&operations, self.trash.as_ref());
+        let report: Vec<String> = self
+            .actions
+            .iter()
+            .zip(&outcomes)
+            .enumerate()
+            .map(|(index, (action, outcome))| format!("[{}] {}: {}", index, self.describe(action), outcome))
+            .collect();
+        if self.action_file {
+            for (action, outcome) in self.actions.iter().zip(&outcomes) {
+                if outcome.is_applied() {
+                    self.log_action(action);
                 }
-                KernelAction::DELETE { target } => {
-                    if !self.dry {
-                        self.commit_deletion(target.clone());
-                    }
-                    if self.action_file {
-                        let _ = fs::write("changes.txt", format!("DELETE {}", target.lock().unwrap().get_path().display()));
-                    }
+            }
+        }
+
+        for (operation, outcome) in operations.iter().zip(&outcomes) {
+            if *outcome == Outcome::NotRun {
+                continue;
+            }
+            match operation {
+                Operation::Create { path, .. } => {
+                    self.refresh(path);
+                    self.refresh(path.parent().unwrap_or(path));
                 }
-                KernelAction::MOVE { original_path, new_path } => {
-                    if !self.dry {
-                        self.commit_move(original_path.clone(), new_path.clone());
-                    }
-                    if self.action_file {
-                        let _ = fs::write("changes.txt", format!("MOVE {} > {}", original_path.clone(), new_path.clone()));
+                Operation::Move { from, to } => {
+                    self.refresh(to);
+                    self.refresh(from.parent().unwrap_or(from));
+                    self.refresh(to.parent().unwrap_or(to));
+                }
+                Operation::Delete { path } => self.refresh(path.parent().unwrap_or(path)),
+            }
+        }
+
+        // Deletions that went through and stayed through leave the queue and the model either
+        // way; the rest stays queued when the commit failed
+        let committed = outcomes.iter().all(Outcome::is_applied);
+        let mut trashed: Vec<PathBuf> = Vec::new();
+        if let Some(trash) = &self.trash {
+            for (operation, outcome) in operations.iter().zip(&outcomes) {
+                if let (Operation::Delete { path }, Outcome::Applied) = (operation, outcome) {
+                    match trash.location_for(path) {
+                        Ok(location) if !trashed.contains(&location) => trashed.push(location),
+                        _ => {}
                     }
                 }
             }
         }
+        let actions = mem::take(&mut self.actions);
+        for (action, outcome) in actions.into_iter().zip(outcomes) {
+            match action {
+                KernelAction::DELETE { target } if outcome.is_applied() && self.tree.contains(target) => self.tree.remove(target),
+                KernelAction::DELETE { .. } if outcome.is_applied() => {}
+                action if !committed => self.actions.push_back(action),
+                _ => {}
+            }
+        }
+
+        let mut report = report.join("\n");
+        if !trashed.is_empty() {
+            let locations: Vec<String> = trashed.iter().map(|location| location.display().to_string()).collect();
+            report.push_str(&format!("\nDeleted items were moved to {}; `restore` lists them.", locations.join(" and ")));
+        }
+        if committed {
+            Ok(format!("Committed all actions:\n{}", report))
+        } else {
+            Err(format!("The commit failed; actions that did not go through are still queued:\n{}", report))
+        }
+    }
+
+    fn trash(&self) -> Result<&Trash, String> {
+        self.trash.as_ref().ok_or_else(|| "Deleted items are removed for good; start with --trash or --quarantine <dir> to keep them.".to_string())
     }
 
-    pub fn commit_deletion(&mut self, target: Arc<Mutex<FileSystemNode>>) {
-        println!("\nCommitting deletions...");
-        // if Rc::strong_count(&node) == 1 {
-        //     continue;
-        // }
-        let (path, is_file) = {
-            let borrowed_node = target.lock().unwrap();
-            (borrowed_node.get_path().clone(), borrowed_node.is_file())
+    fn describe_trashed(&self, number: usize, item: &TrashedItem) -> String {
+        let mut line = format!("[{}] {} (deleted {})", number, item.origin.display(), self.format_time(item.deleted));
+        if let Ok(trash) = self.trash() {
+            if item.location() != trash.location() {
+                line.push_str(&format!(", in {}", item.location().display()));
+            }
+        }
+        line
+    }
+
+    /// Lists what is in the trash, numbered for `restore`.
+    pub fn trashed(&self) -> Result<String, String> {
+        let trash = self.trash()?;
+        let items = trash.items()?;
+        if items.is_empty() {
+            return Ok(format!("The trash in {} is empty.", trash.location().display()));
+        }
+        let lines: Vec<String> = items.iter().enumerate().map(|(i, item)| self.describe_trashed(i + 1, item)).collect();
+        Ok(format!("In the trash in {}:\n{}", trash.location().display(), lines.join("\n")))
+    }
+
+    /// Puts items back from the trash. `target` is a number `restore` listed, or the path an
+    /// item was deleted from, absolute or relative to `node`; a directory path brings back
+    /// everything deleted from below it. Where several items came from the same place, the
+    /// latest one is restored.
+    pub fn restore(&mut self, node: NodeId, target: &str, scanner: &dyn Scanner, options: &ScanOptions) -> Result<String, String> {
+        let trash = self.trash()?.clone();
+        let items = trash.items()?;
+        let chosen: Vec<&TrashedItem> = match target.parse::<usize>() {
+            Ok(number) => vec![number
+                .checked_sub(1)
+                .and_then(|index| items.get(index))
+                .ok_or_else(|| format!("No item {} in the trash; `restore` lists them.", number))?],
+            Err(_) => {
+                let path = normalize(if target.starts_with('/') { PathBuf::from(target) } else { self.tree.get_path(node).join(target) });
+                let exact: Vec<&TrashedItem> = items.iter().filter(|item| item.origin == path).collect();
+                let below = if exact.is_empty() { items.iter().filter(|item| item.origin.starts_with(&path)).collect() } else { exact };
+                // Newest first, so only the latest of each origin is kept
+                let mut chosen: Vec<&TrashedItem> = Vec::new();
+                for item in below.into_iter().rev() {
+                    if !chosen.iter().any(|other| other.origin == item.origin) {
+                        chosen.push(item);
+                    }
+                }
+                if chosen.is_empty() {
+                    return Err(format!("Nothing in the trash was deleted from {}.", path.display()));
+                }
+                chosen
+            }
         };
 
-        // Perform file or directory deletion based on the extracted data
-        if is_file {
-            match fs::remove_file(&path) {
-                Ok(_) => { 
-                    println!("Deleted file: {}", path.display());
-                    disown(target);
-                },
-                Err(e) => eprintln!("Failed to delete file {}: {}", path.display(), e),
+        let root = self.tree.root_path().to_path_buf();
+        let mut report = Vec::new();
+        for item in chosen {
+            match trash.restore(item) {
+                Ok(()) => {
+                    report.push(format!("Restored {}.", item.origin.display()));
+                    if item.origin.starts_with(&root) {
+                        // Bring in the highest directory the model is missing, which has the item below it
+                        let top = item
+                            .origin
+                            .ancestors()
+                            .take_while(|ancestor| ancestor.starts_with(&root) && self.go_to(ancestor.to_string_lossy().into_owned()).is_none())
+                            .last()
+                            .unwrap_or(&item.origin)
+                            .to_path_buf();
+                        self.apply_watch_event(&WatchEvent::Changed(top), scanner, options);
+                    }
+                }
+                Err(err) => report.push(err),
             }
-        } else {
-            match fs::remove_dir_all(&path) {
-                Ok(_) => {
-                    println!("Deleted directory: {}", path.display());
-                    prune(target.clone());
-                    disown(target);
-                },
-                Err(e) => eprintln!("Failed to delete directory {}: {}", path.display(), e),
+        }
+        Ok(report.join("\n"))
+    }
+
+    /// Removes for good what went into the trash at least `older_than` seconds ago, or everything
+    /// in it without an age.
+    pub fn purge(&self, older_than: Option<i64>) -> Result<String, String> {
+        let trash = self.trash()?;
+        let now = listing::now();
+        let mut purged = 0;
+        let mut report = Vec::new();
+        for item in trash.items()? {
+            if older_than.is_some_and(|age| now - item.deleted < age) {
+                continue;
+            }
+            match trash.purge(&item) {
+                Ok(()) => purged += 1,
+                Err(err) => report.push(err),
             }
         }
+        report.push(match purged {
+            0 => "Nothing was purged from the trash.".to_string(),
+            1 => format!("Purged 1 item from the trash in {}.", trash.location().display()),
+            _ => format!("Purged {} items from the trash in {}.", purged, trash.location().display()),
+        });
+        Ok(report.join("\n"))
     }
 
-    pub fn commit_creation(&mut self, path: PathBuf, is_file: bool) {
-        if is_file {
-            let _ = fs::write(&path, "");
-        } else {
-            let _ = fs::create_dir_all(&path);
-        }
-    }
-
-    pub fn commit_move(&mut self, original_path: String, new_path: String) {
-        let _ = fs::rename(original_path, new_path);
-    }
-
-    pub fn open_file(&self, node: Arc<Mutex<FileSystemNode>>, index: usize) {
-        let child = node.lock().unwrap().get_child(index);
-        if let Some(child_node) = child {
-            let borrowed_node = child_node.lock().unwrap();
-            let file_path = borrowed_node.get_path();
-            if cfg!(target_os = "macos") {
-                Command::new("open")
-                    .arg(file_path)
-                    .spawn()
-                    .expect("Failed to open file");
-            // } else if cfg!(target_os = "windows") {
-            //     Command::new("cmd")
-            //         .args(&["/C", "start", "", file_path])
-            //         .spawn()
-            //         .expect("Failed to open file");
-            // } else if cfg!(target_os = "linux") {
-            //     Command::new("xdg-open")
-            //         .arg(file_path)
-            //         .spawn()
-            //         .expect("Failed to open file");
-            } else {
-                eprintln!("Unsupported operating system");
+    pub fn open_file(&self, target: NodeId) {
+        let file_path = self.tree.get_path(target);
+        if cfg!(target_os = "macos") {
+            match Command::new("open").arg(file_path).spawn() {
+                // Reaped on a thread of its own so the backend does not wait for the opener
+                Ok(mut child) => {
+                    thread::spawn(move || child.wait());
+                }
+                Err(err) => eprintln!("Failed to open file: {}", err),
             }
+        // } else if cfg!(target_os = "windows") {
+        //     Command::new("cmd")
+        //         .args(&["/C", "start", "", file_path])
+        //         .spawn()
+        //         .expect("Failed to open file");
+        // } else if cfg!(target_os = "linux") {
+        //     Command::new("xdg-open")
+        //         .arg(file_path)
+        //         .spawn()
+        //         .expect("Failed to open file");
+        } else {
+            eprintln!("Unsupported operating system");
         }
     }
 
-    pub fn create(&mut self, node: Arc<Mutex<FileSystemNode>>, path: String, is_file: bool) {
-        let mut current = node.clone();
-        let mut path_so_far = current.lock().unwrap().get_path().clone();
+    pub fn create(&mut self, node: NodeId, path: String, is_file: bool) -> Result<(), String> {
+        let mut current = node;
+        let mut path_so_far = self.tree.get_path(current);
 
         if path.starts_with("/") {
-            current = self.root.clone();
+            current = self.tree.root();
             path_so_far = PathBuf::new();
         }
 
         let addresses: Vec<&str> = path.split("/").collect();
         let n = addresses.len();
+        let mut created = None;
 
         let mut i = 0;
         while i < n {
             let address = addresses[i];
             path_so_far.push(address);
             if address == ".." {
-                let mut parent = None;
-                if let Some(weak_parent) = current.lock().unwrap().get_parent() {
-                    if let Some(strong_parent) = weak_parent.upgrade() {
-                        parent = Some(strong_parent);
-                    }
-                }
-                if let Some(p) = parent {
-                    current = p;
-                }
-            } else if address != "." {
-                let mut child;
-                let has_child;
-                {
-                    child = current.lock().unwrap().go_to(address);
-                    has_child = child.is_some();
-                }
-
-                if !has_child {
-                    let new_node = FileSystemNode::new(addresses[n-1].to_string(), path_so_far.to_path_buf(), i == n - 1 && is_file, 0, Some(Arc::downgrade(&current)), Vec::new(), false);
-                    let next = Arc::new(Mutex::new(new_node));
-                    current.lock().unwrap().add_child(next.clone());
-                    child = Some(next)
+                if let Some(parent) = self.tree.get_parent(current) {
+                    current = parent;
                 }
-                
-                if let Some(kid) = child {
-                    current = kid.clone();
-                }
-
+            } else if address != "." && !address.is_empty() {
+                // An entry queued for deletion is replaced by a new one, as the commit will do on disk
+                current = match self.tree.child_named(current, address) {
+                    Some(child) if !self.tree.node(child).is_marked() => child,
+                    _ => {
+                        let child = self.tree.insert(Some(current), address, FileSystemNode::new(i == n - 1 && is_file, 0))?;
+                        *created.get_or_insert(child)
+                    }
+                };
             }
             i+=1;
         }
 
-        self.actions.push_back(KernelAction::CREATE { path: path_so_far.to_path_buf(), is_file });
+        self.actions.push_back(KernelAction::CREATE { path: path_so_far.to_path_buf(), is_file, created });
+        Ok(())
     }
 
-    pub fn move_item(&mut self, original_path: String, new_path: String) {
-    
-        if let Some(node) = self.go_to(original_path.clone()) {
-    
-            let parent = {
-                let node_ref = node.lock().unwrap();
-                node_ref.get_parent().and_then(|weak_parent| weak_parent.upgrade())
-            };
-    
-            if let Some(parent_arc) = parent {
-                let mut parent_ref = parent_arc.lock().unwrap();
-                parent_ref.remove_child(original_path.clone());
-    
-                let size = parent_ref.size();
-                parent_ref.set_size(size - node.lock().unwrap().size());
-            } else {
-                println!("Parent not found for node with path: {}", original_path);
+    pub fn move_item(&mut self, original_path: String, new_path: String) -> Result<(), String> {
+        let node = self.go_to(original_path.clone()).ok_or_else(|| format!("No such item: {}.", original_path))?;
+
+        // Only a directory can take the item, so check before taking it out of its parent
+        let new_node = match self.go_to(new_path.clone()) {
+            Some(new_node) if !self.tree.node(new_node).is_file() => new_node,
+            _ => return Err(format!("New parent not found for path: {}", new_path)),
+        };
+
+        let mut ancestor = Some(new_node);
+        while let Some(id) = ancestor {
+            if id == node {
+                return Err(format!("Cannot move {} into itself.", original_path));
             }
-    
-            // Append the item's name to the new path
-            let item_name = Path::new(&original_path)
-                .file_name()
-                .map(|name| name.to_string_lossy().into_owned())
-                .unwrap_or_else(|| "".to_string());
-            let final_new_path = Path::new(&new_path)
-                .join(item_name.clone())
-                .to_string_lossy()
-                .into_owned();
-    
-            if let Some(new_node) = self.go_to(new_path.clone()) {
-                new_node.lock().unwrap().add_child(node.clone());
-                let size = new_node.lock().unwrap().size();
-                new_node.lock().unwrap().set_size(size + node.lock().unwrap().size());
-                node.lock().unwrap().set_parent(Some(Arc::downgrade(&new_node)));
-            } else {
-                println!("New parent not found for path: {}", new_path);
+            ancestor = self.tree.get_parent(id);
+        }
+
+        // Append the item's name to the new path
+        let item_name = Path::new(&original_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if self.tree.child_named(new_node, &item_name).is_some() {
+            return Err(format!("{} already contains an item named {}.", new_path, item_name));
+        }
+        let final_new_path = Path::new(&new_path)
+            .join(item_name.clone())
+            .to_string_lossy()
+            .into_owned();
+
+        if self.tree.get_parent(node).is_some() {
+            self.tree.detach(node);
+        } else {
+            println!("Parent not found for node with path: {}", original_path);
+        }
+
+        self.tree.attach(new_node, node);
+        self.tree.set_name(node, &item_name);
+
+        // Push the final new path to actions
+        self.actions.push_back(KernelAction::MOVE {
+            original_path,
+            new_path: final_new_path,
+        });
+        Ok(())
+    }
+
+}
+
+// Takes `.` and `..` out of a path without looking at the disk
+fn normalize(path: PathBuf) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normal.pop();
             }
-    
-            node.lock().unwrap().set_path(final_new_path.clone());
-            node.lock().unwrap().set_name(item_name.clone());
-    
-            // Push the final new path to actions
-            self.actions.push_back(KernelAction::MOVE {
-                original_path,
-                new_path: final_new_path,
-            });
+            Component::CurDir => {}
+            other => normal.push(other),
         }
     }
-    
-}
\ No newline at end of file
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{MemoryScanner, NativeScanner, ScanEntry, ScanErrorKind};
+    use crate::system::SymlinkInfo;
+
+    fn kernel(entries: Vec<ScanEntry>) -> Kernel {
+        let scanner = MemoryScanner::new(entries);
+        let tree = build_fs_model_blocking("/memory".to_string(), &scanner, ScanOptions::default(), &ScanProgress::new()).unwrap();
+        Kernel::new(tree, false, true, None)
+    }
+
+    // A kernel that commits for real, over what is in `dir`
+    fn scanned(dir: &Path) -> Kernel {
+        let tree = build_fs_model_blocking(dir.to_string_lossy().into_owned(), &NativeScanner, ScanOptions::default(), &ScanProgress::new()).unwrap();
+        Kernel::new(tree, false, false, None)
+    }
+
+    #[test]
+    fn suggested_paths_are_not_taken_for_ids() {
+        let mut kernel = kernel(vec![
+            ScanEntry::new("/memory/notes".to_string(), 10, true),
+            ScanEntry::new("/memory/1".to_string(), 0, false),
+            ScanEntry::new("/memory/2024".to_string(), 0, false),
+        ]);
+        let root = kernel.tree().root();
+        let notes = kernel.tree().child_named(root, "notes").unwrap();
+        let one = kernel.tree().child_named(root, "1").unwrap();
+        let year = kernel.tree().child_named(root, "2024").unwrap();
+        // A command typed at the prompt still takes a bare number as an ID
+        assert_eq!(notes.to_string(), "1");
+        assert_eq!(kernel.resolve(root, "1"), Some(notes));
+        assert_eq!(kernel.resolve_path(root, "1"), Some(one));
+
+        kernel.set_suggestion(r#"[{"delete_file": {"path": "1"}}, {"delete_file": {"path": "/memory/2024"}}]"#.to_string());
+        kernel.convert_suggestions(root);
+
+        assert!(kernel.tree().node(one).is_marked());
+        assert!(kernel.tree().node(year).is_marked());
+        assert!(!kernel.tree().node(notes).is_marked());
+    }
+
+    #[test]
+    fn commit_reports_every_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("tree/below")).unwrap();
+        fs::write(root.join("keep"), b"x").unwrap();
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+
+        kernel.create(top, "made".to_string(), false).unwrap();
+        kernel.move_item(root.join("keep").to_string_lossy().into_owned(), root.join("made").to_string_lossy().into_owned()).unwrap();
+        kernel.mark_for_deletion(kernel.resolve(top, "tree/below").unwrap()).unwrap();
+        kernel.mark_for_deletion(kernel.resolve(top, "tree").unwrap()).unwrap();
+
+        let report = kernel.commit_actions(false).unwrap();
+        let root = root.display();
+        assert_eq!(
+            report,
+            format!(
+                "Committed all actions:\n\
+                 [0] CREATE: {root}/made: done\n\
+                 [1] MOVE: {root}/keep -> {root}/made/keep: done\n\
+                 [2] DELETE: {root}/tree/below: removed along with [3]\n\
+                 [3] DELETE: {root}/tree: done"
+            )
+        );
+        assert_eq!(kernel.commit_actions(false), Ok("Nothing to commit.".to_string()));
+        assert!(kernel.resolve(top, "tree").is_none());
+        assert!(kernel.resolve(top, "made/keep").is_some());
+    }
+
+    #[test]
+    fn ids_of_removed_entries_are_refused_after_new_entries_are_made() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("old"), b"x").unwrap();
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let old = kernel.resolve(top, "old").unwrap();
+        let shown = old.to_string();
+
+        kernel.mark_for_deletion(old).unwrap();
+        kernel.commit_actions(false).unwrap();
+        kernel.create(top, "old".to_string(), true).unwrap();
+        kernel.create(top, "new".to_string(), false).unwrap();
+        kernel.commit_actions(false).unwrap();
+
+        assert_eq!(kernel.resolve(top, &shown), None);
+        let (recreated, new) = (kernel.resolve(top, "old").unwrap(), kernel.resolve(top, "new").unwrap());
+        assert!(old != recreated && old != new);
+        assert_eq!(kernel.resolve(top, &recreated.to_string()), Some(recreated));
+        assert!(root.join("old").exists());
+    }
+
+    #[test]
+    fn search_results_are_numbered_for_later_commands() {
+        let mut kernel = kernel(vec![
+            ScanEntry::new("/memory/docs".to_string(), 0, false),
+            ScanEntry::new("/memory/docs/report.pdf".to_string(), 2000, true),
+            ScanEntry::new("/memory/docs/report.txt".to_string(), 10, true),
+            ScanEntry::new("/memory/old".to_string(), 0, false),
+            ScanEntry::new("/memory/old/Report-2020.PDF".to_string(), 5000, true),
+            ScanEntry::new("/memory/other.pdf".to_string(), 50, true),
+        ]);
+        let root = kernel.tree().root();
+        let (recent, old) = (kernel.resolve(root, "docs/report.pdf").unwrap(), kernel.resolve(root, "old/Report-2020.PDF").unwrap());
+
+        let listing = kernel.search(root, &SearchQuery::parse("report --ext pdf --sort size").unwrap());
+        assert!(listing.starts_with("\n2 matches below /memory:\n#1 "), "{}", listing);
+        assert_eq!(kernel.resolve(root, "#1"), Some(old));
+        assert_eq!(kernel.resolve(root, "#2"), Some(recent));
+        assert_eq!(kernel.resolve(root, "#3"), None);
+        assert_eq!(kernel.resolve(root, "#0"), None);
+        assert_eq!(kernel.resolve_selection(root, "#all"), Ok(vec![old, recent]));
+        assert_eq!(kernel.resolve_selection(root, "#2,1"), Ok(vec![recent, old]));
+
+        // The next search numbers its own results, and only below where it ran
+        let docs = kernel.resolve(root, "docs").unwrap();
+        let listing = kernel.search(docs, &SearchQuery::parse("*.PDF").unwrap());
+        assert!(listing.starts_with("\n1 match below /memory/docs:\n"), "{}", listing);
+        assert_eq!(kernel.resolve(root, "#1"), Some(recent));
+        assert_eq!(kernel.resolve(root, "#2"), None);
+        assert_eq!(kernel.search(docs, &SearchQuery::parse("nothing").unwrap()), "Nothing below /memory/docs matches.");
+        assert_eq!(kernel.resolve(root, "#1"), None);
+    }
+
+    #[test]
+    fn deleting_and_creating_the_same_path_replaces_the_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("notes"), b"old notes").unwrap();
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let old = kernel.resolve(top, "notes").unwrap();
+
+        kernel.mark_for_deletion(old).unwrap();
+        kernel.create(top, "notes".to_string(), false).unwrap();
+        let new = kernel.resolve(top, "notes").unwrap();
+        assert_ne!(new, old);
+        assert!(!kernel.tree().node(new).is_file());
+
+        let report = kernel.commit_actions(false).unwrap();
+        assert!(report.starts_with("Committed all actions:"), "{}", report);
+        assert!(root.join("notes").is_dir());
+        assert!(!kernel.tree().contains(old));
+        assert_eq!(kernel.resolve(top, "notes"), Some(new));
+        assert_eq!(kernel.tree().node(top).children_len(), 1);
+    }
+
+    #[test]
+    fn undo_puts_the_model_back() {
+        let mut kernel = kernel(vec![
+            ScanEntry::new("/memory/docs".to_string(), 0, false),
+            ScanEntry::new("/memory/docs/a.txt".to_string(), 10, true),
+            ScanEntry::new("/memory/archive".to_string(), 0, false),
+        ]);
+        let root = kernel.tree().root();
+        let (docs, file, archive) = (kernel.resolve(root, "docs").unwrap(), kernel.resolve(root, "docs/a.txt").unwrap(), kernel.resolve(root, "archive").unwrap());
+
+        kernel.create(root, "new/deep/file".to_string(), true).unwrap();
+        kernel.create(root, "new/other".to_string(), false).unwrap();
+        kernel.move_item("/memory/docs/a.txt".to_string(), "/memory/archive".to_string()).unwrap();
+        kernel.mark_for_deletion(docs).unwrap();
+        kernel.create(root, "docs".to_string(), false).unwrap();
+        assert_eq!(kernel.tree().node(archive).size(), 10);
+
+        assert_eq!(kernel.undo_deletion(5), Err("There is no action [5]; `status` lists the queue.".to_string()));
+        assert_eq!(kernel.undo_deletion(0), Err("[1] builds on [0]; undo it first.".to_string()));
+        assert_eq!(kernel.undo_deletion(3), Err("[4] builds on [3]; undo it first.".to_string()));
+
+        assert_eq!(kernel.undo_deletion(4), Ok("Undid [4] CREATE: /memory/docs.".to_string()));
+        assert_eq!(kernel.undo_deletion(3), Ok("Undid [3] DELETE: /memory/docs.".to_string()));
+        assert!(!kernel.tree().node(docs).is_marked());
+        assert_eq!(kernel.resolve(root, "docs"), Some(docs));
+
+        assert_eq!(kernel.undo_deletion(2), Ok("Undid [2] MOVE: /memory/docs/a.txt -> /memory/archive/a.txt.".to_string()));
+        assert_eq!(kernel.resolve(root, "docs/a.txt"), Some(file));
+        assert_eq!((kernel.tree().node(docs).size(), kernel.tree().node(archive).size()), (10, 0));
+
+        kernel.undo_deletion(1).unwrap();
+        assert!(kernel.resolve(root, "new/other").is_none());
+        let new = kernel.resolve(root, "new").unwrap();
+        kernel.undo_deletion(0).unwrap();
+        assert!(!kernel.tree().contains(new) && kernel.resolve(root, "new").is_none());
+        assert_eq!(kernel.tree().node(root).children_len(), 2);
+        assert_eq!(kernel.commit_actions(false), Ok("Nothing to commit.".to_string()));
+    }
+
+    // Gives `path` a modification time `seconds` after the epoch
+    fn set_mtime(path: &Path, seconds: u64) {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+        fs::File::open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn commit_refuses_items_changed_since_the_scan_unless_forced() {
+        const SCANNED: u64 = 1_000_000_000;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("grown"), [0; 10]).unwrap();
+        fs::create_dir(root.join("folder")).unwrap();
+        fs::write(root.join("folder/old"), b"x").unwrap();
+        fs::write(root.join("swapped"), b"before").unwrap();
+        fs::create_dir(root.join("dest")).unwrap();
+        for name in ["grown", "folder", "folder/old", "swapped"] {
+            set_mtime(&root.join(name), SCANNED);
+        }
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+        kernel.mark_for_deletion(kernel.resolve(top, "grown").unwrap()).unwrap();
+        kernel.mark_for_deletion(kernel.resolve(top, "folder").unwrap()).unwrap();
+        kernel.move_item(path("swapped"), path("dest")).unwrap();
+
+        // A file grows, a directory gains an entry, and an item is replaced by a new inode
+        fs::write(root.join("grown"), [0; 30]).unwrap();
+        set_mtime(&root.join("grown"), SCANNED);
+        fs::write(root.join("folder/new"), b"y").unwrap();
+        set_mtime(&root.join("folder"), SCANNED + 60);
+        fs::write(root.join("replacement"), b"after").unwrap();
+        fs::rename(root.join("replacement"), root.join("swapped")).unwrap();
+
+        let expected = format!(
+            "Nothing was committed. These items changed on disk since they were scanned:\n\
+             [0] DELETE: {grown}:\n    {grown} changed size from {before} to {after}\n\
+             [1] DELETE: {folder}:\n    {folder} had entries added, removed or renamed at {at}\n\
+             [2] MOVE: {swapped} -> {moved}:\n    {swapped} was replaced by another item\n\
+             `rescan` shows them as they are now; `commit --force` commits anyway.",
+            grown = path("grown"),
+            before = kernel.format_size(10),
+            after = kernel.format_size(30),
+            folder = path("folder"),
+            at = kernel.format_time((SCANNED + 60) as i64),
+            swapped = path("swapped"),
+            moved = path("dest/swapped"),
+        );
+        assert_eq!(kernel.commit_actions(false), Err(expected));
+        assert!(root.join("grown").exists() && root.join("folder/new").exists() && root.join("swapped").exists());
+
+        let report = kernel.commit_actions(true).unwrap();
+        assert!(report.starts_with("Committed all actions:"), "{}", report);
+        assert!(!root.join("grown").exists() && !root.join("folder").exists());
+        assert_eq!(fs::read(root.join("dest/swapped")).unwrap(), b"after");
+    }
+
+    #[test]
+    fn check_reports_conflicts_by_action_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("parent")).unwrap();
+        fs::create_dir(root.join("doomed")).unwrap();
+        fs::write(root.join("item"), b"x").unwrap();
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+        kernel.move_item(path("item"), path("parent")).unwrap();
+        kernel.mark_for_deletion(kernel.resolve(top, "parent").unwrap()).unwrap();
+        kernel.mark_for_deletion(kernel.resolve(top, "doomed").unwrap()).unwrap();
+        kernel.create(top, "doomed/new".to_string(), true).unwrap();
+        // Something takes the name a queued creation wants once the scan is done
+        kernel.create(top, "late".to_string(), true).unwrap();
+        fs::write(root.join("late"), b"y").unwrap();
+
+        let expected = format!(
+            "3 conflicts found:\n\
+             [1] DELETE: {parent}: it also deletes {parent}/item, which [0] moves there\n\
+             [3] CREATE: {doomed}/new: {doomed} is not a directory\n\
+             [4] CREATE: {late}: {late} already exists",
+            parent = path("parent"),
+            doomed = path("doomed"),
+            late = path("late"),
+        );
+        assert_eq!(kernel.check(), Err(expected.clone()));
+        assert_eq!(kernel.commit_actions(false), Err(format!("Nothing was committed. Undo or change these actions first:\n{}", expected.split_once('\n').unwrap().1)));
+        assert!(root.join("item").exists() && root.join("parent").exists() && root.join("doomed").exists());
+
+        for index in [4, 3, 1] {
+            kernel.undo_deletion(index).unwrap();
+        }
+        assert_eq!(kernel.check(), Ok("All 2 queued actions can be committed.".to_string()));
+    }
+
+    #[test]
+    fn purge_older_than_keeps_recent_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for name in ["old", "new"] {
+            fs::write(root.join(name), b"x").unwrap();
+        }
+        let trash = Trash::quarantine(root.join("trash"));
+        trash.put(&root.join("old")).unwrap();
+        trash.put(&root.join("new")).unwrap();
+        let record = root.join("trash/info/old.trashinfo");
+        let contents = fs::read_to_string(&record).unwrap();
+        let dated = contents.lines().map(|line| if line.starts_with("DeletionDate=") { "DeletionDate=2020-01-01T00:00:00" } else { line }).collect::<Vec<_>>();
+        fs::write(&record, dated.join("\n")).unwrap();
+
+        let tree = build_fs_model_blocking(root.to_string_lossy().into_owned(), &NativeScanner, ScanOptions::default(), &ScanProgress::new()).unwrap();
+        let kernel = Kernel::new(tree, false, false, Some(trash.clone()));
+        let report = kernel.purge(Some(listing::parse_age("30d").unwrap())).unwrap();
+
+        assert!(report.starts_with("Purged 1 item from the trash"), "{}", report);
+        let left: Vec<String> = trash.items().unwrap().into_iter().map(|item| item.name).collect();
+        assert_eq!(left, ["new"]);
+        assert!(!root.join("trash/files/old").exists());
+        assert_eq!(kernel.purge(None).unwrap(), format!("Purged 1 item from the trash in {}.", trash.location().display()));
+        assert!(trash.items().unwrap().is_empty());
+    }
+
+    #[test]
+    fn display_shows_the_metadata_of_each_entry() {
+        let modified = 1_600_000_000;
+        let metadata = FileMetadata { modified, uid: 4242, gid: 4343, mode: 0o100640, inode: 7, device: 3, nlink: 1, ..FileMetadata::default() };
+        let kernel = kernel(vec![ScanEntry::new("/memory/report".to_string(), 10, true).with_metadata(metadata)]);
+        let display = kernel.display(kernel.tree().root(), &DisplayOptions::default());
+
+        let line = format!("[1] report (10 bytes [File]) rw-r----- 4242:4343 modified {}\n", kernel.format_time(modified));
+        assert!(display.contains(&line), "{}", display);
+        assert_eq!(kernel.format_time(0), "-");
+    }
+
+    #[test]
+    fn size_mode_switches_between_apparent_and_allocated() {
+        let mut kernel = kernel(vec![
+            ScanEntry::new("/memory/sparse".to_string(), 10 * 1024, true).with_allocated_size(512),
+            ScanEntry::new("/memory/small".to_string(), 100, true).with_allocated_size(4096),
+        ]);
+        let root = kernel.tree().root();
+        kernel.mark_for_deletion(kernel.resolve(root, "sparse").unwrap()).unwrap();
+
+        assert!(kernel.display(root, &DisplayOptions::default()).contains("Total storage used: 10.10 KB (apparent size)"));
+        assert!(kernel.get_status().contains("Total space saved: 10.00 KB"));
+
+        kernel.set_size_mode(SizeMode::Allocated);
+        assert_eq!(kernel.get_size_mode(), SizeMode::Allocated);
+        assert!(kernel.display(root, &DisplayOptions::default()).contains("Total storage used: 4.50 KB (allocated size)"));
+        assert!(kernel.get_status().contains("Total space saved: 512 bytes"));
+    }
+
+    #[test]
+    fn display_shows_where_symlinks_point() {
+        let link = |target: &str, broken: bool| SymlinkInfo { target: PathBuf::from(target), broken };
+        let kernel = kernel(vec![
+            ScanEntry::new("/memory/docs".to_string(), 4, true).with_symlink(link("/srv/docs", false)),
+            ScanEntry::new("/memory/stale".to_string(), 7, true).with_symlink(link("gone", true)),
+        ]);
+        let display = kernel.display(kernel.tree().root(), &DisplayOptions::default());
+        assert!(display.contains("[1] docs -> /srv/docs (4 bytes [Symlink])"), "{}", display);
+        assert!(display.contains("[2] stale -> gone (7 bytes [Broken Symlink])"), "{}", display);
+    }
+
+    #[test]
+    fn errors_lists_unreadable_paths_below_a_node() {
+        let mut kernel = kernel(vec![
+            ScanEntry::new("/memory/locked".to_string(), 0, false),
+            ScanEntry::new("/memory/open".to_string(), 0, false),
+        ]);
+        let root = kernel.tree().root();
+        let (locked, open) = (kernel.resolve(root, "locked").unwrap(), kernel.resolve(root, "open").unwrap());
+        assert_eq!(kernel.scan_errors(root), "Everything below /memory was read.");
+
+        let error = ScanError::new("/memory/locked".to_string(), ScanErrorKind::Open, Some(libc::EACCES));
+        kernel.tree_mut().node_mut(locked).set_scan_errors(vec![error]);
+        assert_eq!(
+            kernel.scan_errors(root),
+            format!(
+                "1 unreadable paths below /memory:\n/memory/locked: cannot open directory ({})\n\
+                 Some paths were denied access; loading again with elevated privileges may read them.\n",
+                std::io::Error::from_raw_os_error(libc::EACCES)
+            )
+        );
+        assert_eq!(kernel.scan_errors(open), "Everything below /memory/open was read.");
+    }
+
+    #[test]
+    fn rescan_reconciles_a_subtree_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        for (name, size) in [("sub/keep", 10), ("sub/grow", 5), ("sub/gone", 7), ("sub/queued", 3), ("other", 100)] {
+            fs::write(root.join(name), vec![0; size]).unwrap();
+        }
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let [sub, keep, grow, queued] = ["sub", "sub/keep", "sub/grow", "sub/queued"].map(|path| kernel.resolve(top, path).unwrap());
+        kernel.mark_for_deletion(queued).unwrap();
+        kernel.create(top, "sub/planned".to_string(), true).unwrap();
+
+        fs::write(root.join("sub/grow"), [0; 50]).unwrap();
+        fs::remove_file(root.join("sub/gone")).unwrap();
+        fs::write(root.join("sub/new"), [0; 2]).unwrap();
+        let report = kernel.rescan(sub, &NativeScanner, &ScanOptions::default()).unwrap();
+
+        assert_eq!(report.to_string(), "1 added, 1 removed, 1 updated");
+        assert_eq!(kernel.resolve(top, "sub/keep"), Some(keep));
+        assert_eq!(kernel.resolve(top, "sub/grow"), Some(grow));
+        assert_eq!(kernel.tree().node(grow).size(), 50);
+        assert!(kernel.resolve(top, "sub/gone").is_none());
+        assert!(kernel.resolve(top, "sub/new").is_some());
+        assert_eq!(kernel.tree().node(sub).size(), 65);
+        assert_eq!(kernel.tree().node(top).size(), 165);
+
+        // What the queue refers to is left as the actions made it
+        assert_eq!(kernel.resolve(top, "sub/queued"), Some(queued));
+        assert!(kernel.tree().node(queued).is_marked());
+        assert!(kernel.resolve(top, "sub/planned").is_some());
+        assert_eq!(kernel.pending_paths().model_only, [root.join("sub/planned")]);
+
+        assert!(kernel.rescan(keep, &NativeScanner, &ScanOptions::default()).unwrap_err().ends_with("is not a directory."));
+    }
+
+    #[test]
+    fn watch_events_keep_the_model_and_its_totals_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), [0; 10]).unwrap();
+        fs::write(root.join("doomed"), [0; 5]).unwrap();
+        let mut kernel = scanned(root);
+        let top = kernel.tree().root();
+        let file = kernel.resolve(top, "sub/file").unwrap();
+        let options = ScanOptions::default();
+        let apply = |kernel: &mut Kernel, event: WatchEvent| kernel.apply_watch_event(&event, &NativeScanner, &options);
+
+        fs::write(root.join("sub/file"), [0; 30]).unwrap();
+        assert_eq!(apply(&mut kernel, WatchEvent::Changed(root.join("sub/file"))), Some(root.join("sub")));
+        assert_eq!(kernel.tree().node(file).size(), 30);
+
+        fs::create_dir(root.join("sub/made")).unwrap();
+        fs::write(root.join("sub/made/inner"), [0; 7]).unwrap();
+        assert_eq!(apply(&mut kernel, WatchEvent::Changed(root.join("sub/made"))), Some(root.join("sub")));
+        assert_eq!(kernel.tree().node(kernel.resolve(top, "sub/made").unwrap()).size(), 7);
+
+        fs::remove_file(root.join("doomed")).unwrap();
+        assert_eq!(apply(&mut kernel, WatchEvent::Removed(root.join("doomed"))), Some(root.to_path_buf()));
+        assert!(kernel.resolve(top, "doomed").is_none());
+        assert_eq!(kernel.tree().node(kernel.resolve(top, "sub").unwrap()).size(), 37);
+        assert_eq!(kernel.tree().node(top).size(), 37);
+
+        // Nothing changed, and a path a queued creation is about to make, are both left alone
+        assert_eq!(apply(&mut kernel, WatchEvent::Changed(root.join("sub/file"))), None);
+        kernel.create(top, "planned".to_string(), true).unwrap();
+        fs::write(root.join("planned"), [0; 99]).unwrap();
+        assert_eq!(apply(&mut kernel, WatchEvent::Changed(root.join("planned"))), None);
+        assert_eq!(kernel.tree().node(top).size(), 37);
+    }
+}
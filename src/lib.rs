@@ -1,6 +1,18 @@
 pub mod system;
 pub mod kernel;
+pub mod listing;
+pub mod search;
+pub mod query;
+pub mod transaction;
+pub mod trash;
 pub mod gui;
 pub mod threads;
+pub mod scanner;
+pub mod exclude;
+pub mod snapshot;
+pub mod watch;
+#[cfg(target_os = "macos")]
 pub mod mac;
-pub mod ai;
\ No newline at end of file
+#[cfg(target_os = "linux")]
+pub mod linux;
+pub mod ai;
@@ -0,0 +1,471 @@
+use std::collections::HashSet;
+use rayon::prelude::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use libc::*;
+use std::mem;
+use std::fs;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::exclude::relative_to;
+use crate::scanner::{DirectoryListing, ScanEntry, ScanError, ScanErrorKind, ScanOptions, ScanProgress, ScanSink};
+use crate::system::{FileMetadata, SymlinkInfo};
+use crate::watch::WatchEvent;
+
+// Layout of the fixed part of a record returned by getdents64(2).
+// The NUL-terminated name immediately follows `d_type`.
+#[repr(C)]
+struct linux_dirent64 {
+    d_ino: u64,
+    d_off: i64,
+    d_reclen: u16,
+    d_type: u8,
+}
+
+pub fn fetch_file_system_with_getdents_parallel(path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+    let root_key = fs::metadata(path).map(|metadata| (metadata.dev(), metadata.ino())).unwrap_or_default();
+    let mut directories_to_process = vec![(path.to_string(), root_key)];
+    // Directories are tracked by (device, inode) so a followed link can never lead back into one
+    let mut visited_directories = HashSet::new();
+
+    while !directories_to_process.is_empty() && !progress.is_cancelled() {
+        // A directory reached a second time is left as it is, empty
+        directories_to_process.retain(|(dir_path, key)| {
+            let first_visit = visited_directories.insert(*key);
+            if !first_visit {
+                sink.finish(dir_path);
+            }
+            first_visit
+        });
+
+        let new_directories: Vec<(String, (u64, u64))> = directories_to_process
+            .into_par_iter()
+            .flat_map(|(dir_path, _)| {
+                if progress.is_cancelled() {
+                    return Vec::new();
+                }
+
+                let mut local_errors = Vec::new();
+                let dir_results = fetch_file_system_with_getdents(&dir_path, options, &mut local_errors);
+
+                let mut subdirectories = Vec::new();
+                let mut local_results = Vec::new();
+                let mut local_excluded = Vec::new();
+                let mut unentered = Vec::new();
+
+                for (mut entry, directory_key) in dir_results {
+                    entry.path = if dir_path == "/" {
+                        format!("/{}", entry.path)
+                    } else {
+                        format!("{}/{}", dir_path, entry.path)
+                    };
+
+                    // Excluded subtrees are dropped here, before anything gets to open them
+                    if options.exclude.is_excluded(relative_to(path, &entry.path), directory_key.is_some()) {
+                        local_excluded.push(entry.path);
+                        continue;
+                    }
+
+                    // With --one-file-system a directory on another device is listed but not entered
+                    if let Some(key) = directory_key {
+                        if !options.one_file_system || key.0 == root_key.0 {
+                            subdirectories.push((entry.path.clone(), key));
+                        } else {
+                            unentered.push(entry.path.clone());
+                        }
+                    }
+
+                    local_results.push(entry);
+                }
+
+                progress.record_directory(&dir_path, &local_results);
+                sink.directory(&dir_path, DirectoryListing { entries: local_results, excluded: local_excluded, errors: local_errors });
+                for directory in unentered {
+                    sink.finish(&directory);
+                }
+
+                subdirectories
+            })
+            .collect();
+
+        directories_to_process = new_directories;
+    }
+}
+
+unsafe fn read_link_at(dirfd: c_int, name: *const c_char) -> Option<PathBuf> {
+    let mut target = vec![0u8; PATH_MAX as usize];
+    let length = readlinkat(dirfd, name, target.as_mut_ptr() as *mut c_char, target.len());
+    if length < 0 {
+        return None;
+    }
+    target.truncate(length as usize);
+    Some(PathBuf::from(OsString::from_vec(target)))
+}
+
+
+// Entries come back with their bare name in `path`; the caller joins it onto the directory.
+// Anything to descend into is paired with its (device, inode).
+// Whatever could not be read is pushed onto `errors` instead of being skipped silently.
+fn fetch_file_system_with_getdents(path: &str, options: &ScanOptions, errors: &mut Vec<ScanError>) -> Vec<(ScanEntry, Option<(u64, u64)>)> {
+    let mut results = Vec::new();
+    let c_path = match CString::new(path) {
+        Ok(c_path) => c_path,
+        Err(_) => {
+            errors.push(ScanError::new(path.to_string(), ScanErrorKind::Open, Some(EINVAL)));
+            return results;
+        }
+    };
+
+    unsafe {
+        // O_DIRECTORY makes open fail on fifos, sockets and devices instead of blocking on them.
+        // Only the root or a followed link can be a symlink here, and both should be opened through.
+        let dirfd = open(c_path.as_ptr(), O_RDONLY | O_DIRECTORY | O_CLOEXEC);
+
+        if dirfd < 0 {
+            errors.push(ScanError::last_os_error(path.to_string(), ScanErrorKind::Open));
+            return results;
+        }
+
+        let buffer_size = 262144;
+        let mut buffer: Vec<u8> = vec![0; buffer_size];
+        let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
+
+        loop {
+            let read = syscall(SYS_getdents64, dirfd, buffer_ptr, buffer_size);
+
+            if read < 0 {
+                errors.push(ScanError::last_os_error(path.to_string(), ScanErrorKind::Read));
+                break;
+            }
+            if read == 0 {
+                break;
+            }
+
+            let read = read as usize;
+            let mut offset = 0;
+            while offset < read {
+                let entry = buffer_ptr.add(offset) as *const u8;
+                let dirent = &*(entry as *const linux_dirent64);
+                let length = dirent.d_reclen as usize;
+                if length == 0 || offset + length > read {
+                    errors.push(ScanError::new(path.to_string(), ScanErrorKind::Malformed, None));
+                    break;
+                }
+
+                let name_ptr = entry.add(mem::offset_of!(linux_dirent64, d_type) + 1) as *const c_char;
+                let name = CStr::from_ptr(name_ptr);
+                offset += length;
+
+                let name_bytes = name.to_bytes();
+                if name_bytes == b"." || name_bytes == b".." {
+                    continue;
+                }
+
+                let mut stx: statx = mem::zeroed();
+                let stat_result = statx(
+                    dirfd,
+                    name_ptr,
+                    AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT,
+                    STATX_BASIC_STATS,
+                    &mut stx,
+                );
+
+                let file_name = name.to_string_lossy().into_owned();
+                if stat_result == 0 {
+                    let file_type = stx.stx_mode as u32 & S_IFMT;
+                    let mut is_dir = file_type == S_IFDIR;
+                    let mut directory_key = is_dir.then(|| (makedev(stx.stx_dev_major, stx.stx_dev_minor), stx.stx_ino));
+
+                    let mut symlink = None;
+                    if file_type == S_IFLNK {
+                        let mut target_stat: stat = mem::zeroed();
+                        let broken = fstatat(dirfd, name_ptr, &mut target_stat, 0) != 0;
+                        if options.follow_symlinks && !broken && (target_stat.st_mode & S_IFMT) == S_IFDIR {
+                            is_dir = true;
+                            directory_key = Some((target_stat.st_dev, target_stat.st_ino));
+                        }
+                        symlink = read_link_at(dirfd, name_ptr).map(|target| SymlinkInfo { target, broken });
+                    }
+
+                    let file_size = if is_dir { 0 } else { stx.stx_size };
+                    // stx_blocks is always counted in 512 byte units, whatever the filesystem block size
+                    let allocated_size = stx.stx_blocks * 512;
+                    let metadata = FileMetadata {
+                        modified: stx.stx_mtime.tv_sec,
+                        accessed: stx.stx_atime.tv_sec,
+                        changed: stx.stx_ctime.tv_sec,
+                        uid: stx.stx_uid,
+                        gid: stx.stx_gid,
+                        mode: stx.stx_mode as u32,
+                        inode: stx.stx_ino,
+                        device: makedev(stx.stx_dev_major, stx.stx_dev_minor),
+                        nlink: stx.stx_nlink as u64,
+                    };
+                    let mut entry = ScanEntry::new(file_name, file_size, !is_dir)
+                        .with_allocated_size(allocated_size)
+                        .with_metadata(metadata);
+                    if let Some(symlink) = symlink {
+                        entry = entry.with_symlink(symlink);
+                    }
+                    results.push((entry, directory_key));
+                } else {
+                    // Fall back on the type recorded in the directory entry; without an inode the
+                    // directory cannot be tracked, so it is not descended into
+                    let entry_path = format!("{}/{}", path.trim_end_matches('/'), file_name);
+                    errors.push(ScanError::last_os_error(entry_path, ScanErrorKind::Stat));
+                    results.push((ScanEntry::new(file_name, 0, dirent.d_type != DT_DIR), None));
+                }
+            }
+        }
+
+        close(dirfd);
+    }
+
+    results
+}
+
+const WATCH_MASK: u32 = IN_CREATE
+    | IN_DELETE
+    | IN_MODIFY
+    | IN_ATTRIB
+    | IN_CLOSE_WRITE
+    | IN_MOVED_FROM
+    | IN_MOVED_TO
+    | IN_DELETE_SELF
+    | IN_ONLYDIR;
+
+// One inotify instance with a watch on every directory below `root`
+struct InotifyWatches {
+    fd: c_int,
+    root: PathBuf,
+    options: ScanOptions,
+    paths: HashMap<c_int, PathBuf>,
+    limit_reported: bool,
+}
+
+/// Watches `directories` with inotify and forwards what happens below them from a background
+/// thread. Directories that appear later are watched as they are reported.
+pub fn watch(root: PathBuf, directories: Vec<PathBuf>, options: ScanOptions, events: UnboundedSender<WatchEvent>) -> io::Result<()> {
+    let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut watches = InotifyWatches {
+        fd,
+        root,
+        options,
+        paths: HashMap::new(),
+        limit_reported: false,
+    };
+    for directory in &directories {
+        watches.add(directory);
+    }
+
+    thread::Builder::new()
+        .name("inotify".to_string())
+        .spawn(move || watches.run(events))?;
+    Ok(())
+}
+
+impl InotifyWatches {
+    fn add(&mut self, path: &Path) {
+        let Ok(c_path) = CString::new(path.as_os_str().as_encoded_bytes()) else {
+            return;
+        };
+        let wd = unsafe { inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd >= 0 {
+            self.paths.insert(wd, path.to_path_buf());
+        } else if io::Error::last_os_error().raw_os_error() == Some(ENOSPC) && !self.limit_reported {
+            self.limit_reported = true;
+            eprintln!("inotify watch limit reached, raise fs.inotify.max_user_watches to watch everything below {}", self.root.display());
+        }
+    }
+
+    // A directory that shows up may already have contents, so everything below it is watched too
+    fn add_recursive(&mut self, path: &Path) {
+        self.add(path);
+        let Ok(entries) = fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+            let entry_path = entry.path();
+            if is_dir && !self.options.exclude.is_excluded_below(&self.root, &entry_path, true) {
+                self.add_recursive(&entry_path);
+            }
+        }
+    }
+
+    // Watches below a directory that moved are dropped; if it moved somewhere still watched,
+    // the IN_MOVED_TO that follows adds them again under the new path
+    fn remove_below(&mut self, path: &Path) {
+        let stale: Vec<c_int> = self
+            .paths
+            .iter()
+            .filter(|(_, watched)| watched.starts_with(path))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in stale {
+            self.paths.remove(&wd);
+            unsafe { inotify_rm_watch(self.fd, wd) };
+        }
+    }
+
+    fn run(mut self, events: UnboundedSender<WatchEvent>) {
+        // inotify_event has to be read into a buffer aligned for it
+        let mut buffer = vec![0u64; 8192];
+        let buffer_size = buffer.len() * mem::size_of::<u64>();
+        let buffer_ptr = buffer.as_mut_ptr() as *mut u8;
+
+        'reading: loop {
+            let read = unsafe { read(self.fd, buffer_ptr as *mut c_void, buffer_size) };
+            if read < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("Failed to read inotify events: {}", io::Error::last_os_error());
+                break;
+            }
+
+            let read = read as usize;
+            let mut offset = 0;
+            while offset + mem::size_of::<inotify_event>() <= read {
+                let (event, name) = unsafe {
+                    let event = &*(buffer_ptr.add(offset) as *const inotify_event);
+                    let name = if event.len > 0 {
+                        let name_ptr = buffer_ptr.add(offset + mem::size_of::<inotify_event>()) as *const c_char;
+                        CStr::from_ptr(name_ptr).to_string_lossy().into_owned()
+                    } else {
+                        String::new()
+                    };
+                    (*event, name)
+                };
+                offset += mem::size_of::<inotify_event>() + event.len as usize;
+
+                if event.mask & IN_Q_OVERFLOW != 0 {
+                    if events.send(WatchEvent::Rescan(self.root.clone())).is_err() {
+                        break 'reading;
+                    }
+                    continue;
+                }
+                if event.mask & IN_IGNORED != 0 {
+                    self.paths.remove(&event.wd);
+                    continue;
+                }
+                // The parent reports the deletion as well, and IN_IGNORED follows
+                if event.mask & IN_DELETE_SELF != 0 {
+                    continue;
+                }
+                let Some(directory) = self.paths.get(&event.wd) else {
+                    continue;
+                };
+
+                let path = if name.is_empty() { directory.clone() } else { directory.join(&name) };
+                if self.options.exclude.is_excluded_below(&self.root, &path, event.mask & IN_ISDIR != 0) {
+                    continue;
+                }
+                if event.mask & IN_ISDIR != 0 {
+                    if event.mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                        self.add_recursive(&path);
+                    } else if event.mask & IN_MOVED_FROM != 0 {
+                        self.remove_below(&path);
+                    }
+                }
+
+                let watch_event = if event.mask & (IN_DELETE | IN_MOVED_FROM) != 0 {
+                    WatchEvent::Removed(path)
+                } else {
+                    WatchEvent::Changed(path)
+                };
+                if events.send(watch_event).is_err() {
+                    break 'reading;
+                }
+            }
+        }
+
+        unsafe { close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use crate::scanner::NativeScanner;
+    use crate::system::build_fs_model_blocking;
+
+    type Listed = (ScanEntry, Option<(u64, u64)>);
+
+    fn list(path: &Path) -> (Vec<Listed>, Vec<ScanError>) {
+        let mut errors = Vec::new();
+        let mut entries = fetch_file_system_with_getdents(&path.to_string_lossy(), &ScanOptions::default(), &mut errors);
+        entries.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+        (entries, errors)
+    }
+
+    #[test]
+    fn entries_carry_statx_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("file"), b"hello").unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        symlink("file", root.join("link")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+
+        let (entries, errors) = list(root);
+        assert!(errors.is_empty());
+        let names: Vec<&str> = entries.iter().map(|(entry, _)| entry.path.as_str()).collect();
+        assert_eq!(names, ["dangling", "file", "link", "sub"]);
+
+        let (file, key) = &entries[1];
+        let expected = fs::symlink_metadata(root.join("file")).unwrap();
+        assert!(file.is_file && key.is_none());
+        assert_eq!(file.size, 5);
+        assert_eq!(file.allocated_size, expected.blocks() * 512);
+        assert_eq!(file.metadata, FileMetadata::from(&expected));
+
+        let (sub, key) = &entries[3];
+        let expected = fs::metadata(root.join("sub")).unwrap();
+        assert!(!sub.is_file);
+        assert_eq!(*key, Some((expected.dev(), expected.ino())));
+
+        let link = entries[2].0.symlink.as_ref().unwrap();
+        assert_eq!((link.target.as_path(), link.broken), (Path::new("file"), false));
+        assert!(entries[0].0.symlink.as_ref().unwrap().broken);
+    }
+
+    #[test]
+    fn unreadable_directories_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (entries, errors) = list(&dir.path().join("missing"));
+        assert!(entries.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].kind, errors[0].errno), (ScanErrorKind::Open, Some(ENOENT)));
+    }
+
+    #[test]
+    fn large_directories_take_several_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("big")).unwrap();
+        // Long names so the records fill more than one getdents64 buffer
+        for i in 0..3000 {
+            fs::write(root.join("big").join(format!("{:0>200}", i)), b"x").unwrap();
+        }
+        fs::write(root.join("top"), b"abc").unwrap();
+
+        let tree = build_fs_model_blocking(root.to_string_lossy().into_owned(), &NativeScanner, ScanOptions::default(), &ScanProgress::new()).unwrap();
+        let big = tree.child_named(tree.root(), "big").unwrap();
+        assert_eq!(tree.node(big).children_len(), 3000);
+        assert_eq!(tree.node(big).size(), 3000);
+        assert_eq!(tree.node(tree.root()).size(), 3003);
+    }
+}
@@ -0,0 +1,346 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::system::{FileSystemNode, FileSystemTree, NodeId, NodeKind};
+
+/// What `display` can order a directory's entries by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+    Type,
+    /// Number of entries directly inside a directory
+    Items,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 5] = [SortKey::Name, SortKey::Size, SortKey::Modified, SortKey::Type, SortKey::Items];
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "name" => Some(SortKey::Name),
+            "size" => Some(SortKey::Size),
+            "mtime" | "modified" => Some(SortKey::Modified),
+            "type" => Some(SortKey::Type),
+            "items" | "count" => Some(SortKey::Items),
+            _ => None,
+        }
+    }
+
+    /// Whether the biggest, newest or fullest entries are the interesting ones to see first.
+    pub fn descending_by_default(self) -> bool {
+        matches!(self, SortKey::Size | SortKey::Modified | SortKey::Items)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortKey::Name => "Name",
+            SortKey::Size => "Size",
+            SortKey::Modified => "Modified",
+            SortKey::Type => "Type",
+            SortKey::Items => "Items",
+        }
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortKey::Name => write!(f, "name"),
+            SortKey::Size => write!(f, "size"),
+            SortKey::Modified => write!(f, "mtime"),
+            SortKey::Type => write!(f, "type"),
+            SortKey::Items => write!(f, "items"),
+        }
+    }
+}
+
+/// Which entries `display` lists and in what order. The default lists everything in the order
+/// it was scanned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisplayOptions {
+    pub sort: Option<SortKey>,
+    pub descending: bool,
+    pub kind: Option<NodeKind>,
+    /// Lowercase extensions without the dot; empty lets every name through
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Only entries last modified at least this many seconds ago
+    pub older_than: Option<i64>,
+    /// Only entries modified within this many seconds
+    pub newer_than: Option<i64>,
+    pub top: Option<usize>,
+}
+
+impl DisplayOptions {
+    /// Reads the flags of `display`, for example `--sort size --desc --top 20 --min 100M`.
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut options = DisplayOptions::default();
+        let mut direction = None;
+        let mut args = args.split_whitespace();
+
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match flag {
+                "--sort" => {
+                    let key = value()?;
+                    options.sort = Some(SortKey::parse(key).ok_or_else(|| format!("Cannot sort by '{}', use name, size, mtime, type or items", key))?);
+                }
+                "--desc" => direction = Some(true),
+                "--asc" => direction = Some(false),
+                "--top" => {
+                    let count = value()?;
+                    options.top = Some(count.parse().map_err(|_| format!("Invalid count '{}'", count))?);
+                }
+                "--min" => options.min_size = Some(parse_size(value()?)?),
+                "--max" => options.max_size = Some(parse_size(value()?)?),
+                "--type" => options.kind = Some(parse_kind(value()?)?),
+                "--ext" => {
+                    options.extensions = value()?
+                        .split(',')
+                        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                        .filter(|extension| !extension.is_empty())
+                        .collect();
+                }
+                "--older" => options.older_than = Some(parse_age(value()?)?),
+                "--newer" => options.newer_than = Some(parse_age(value()?)?),
+                _ => return Err(format!("Unknown display option '{}'", flag)),
+            }
+        }
+
+        options.descending = direction.unwrap_or_else(|| options.sort.is_some_and(SortKey::descending_by_default));
+        Ok(options)
+    }
+
+    /// Whether anything but the order was asked for, so the listing may leave entries out.
+    pub fn is_filtered(&self) -> bool {
+        self.kind.is_some()
+            || !self.extensions.is_empty()
+            || self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.older_than.is_some()
+            || self.newer_than.is_some()
+            || self.top.is_some()
+    }
+
+    fn matches(&self, name: &str, node: &FileSystemNode, size: u64, now: i64) -> bool {
+        if self.kind.is_some_and(|kind| kind != node.kind()) {
+            return false;
+        }
+        if !self.extensions.is_empty() && !extension(name).is_some_and(|extension| self.extensions.contains(&extension)) {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        let age = now - node.modified();
+        !(self.older_than.is_some_and(|older| age < older) || self.newer_than.is_some_and(|newer| age > newer))
+    }
+}
+
+/// The children of `node` that `options` lets through, in its order, along with how many
+/// children there were to choose from. Entries queued for deletion are never listed.
+pub fn select(tree: &FileSystemTree, node: NodeId, options: &DisplayOptions, size: impl Fn(&FileSystemNode) -> u64) -> (Vec<NodeId>, usize) {
+    let children: Vec<NodeId> = tree.node(node).children().iter().copied().filter(|&child| !tree.node(child).is_marked()).collect();
+    let total = children.len();
+    (arrange(tree, children, options, size), total)
+}
+
+/// Keeps the entries of `ids` that `options` lets through, sorts them as it asks and cuts them
+/// down to its count.
+pub fn arrange(tree: &FileSystemTree, ids: Vec<NodeId>, options: &DisplayOptions, size: impl Fn(&FileSystemNode) -> u64) -> Vec<NodeId> {
+    let now = now();
+    let mut selected: Vec<NodeId> = ids
+        .into_iter()
+        .filter(|&id| options.matches(tree.get_name(id), tree.node(id), size(tree.node(id)), now))
+        .collect();
+
+    if let Some(key) = options.sort {
+        selected.sort_by(|&a, &b| {
+            let (a_node, b_node) = (tree.node(a), tree.node(b));
+            let (a_name, b_name) = (tree.get_name(a), tree.get_name(b));
+            let ordering = match key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => size(a_node).cmp(&size(b_node)),
+                SortKey::Modified => a_node.modified().cmp(&b_node.modified()),
+                SortKey::Type => kind_rank(a_node.kind()).cmp(&kind_rank(b_node.kind())).then_with(|| extension(a_name).cmp(&extension(b_name))),
+                SortKey::Items => a_node.children_len().cmp(&b_node.children_len()),
+            };
+            let ordering = ordering.then_with(|| a_name.to_lowercase().cmp(&b_name.to_lowercase()));
+            if options.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+
+    if let Some(top) = options.top {
+        selected.truncate(top);
+    }
+    selected
+}
+
+/// Seconds since the Unix epoch, which is how node times are kept.
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as i64).unwrap_or(0)
+}
+
+fn kind_rank(kind: NodeKind) -> u8 {
+    match kind {
+        NodeKind::Directory => 0,
+        NodeKind::File => 1,
+        NodeKind::Symlink => 2,
+    }
+}
+
+/// The lowercase extension of a name, if it has one. A leading dot starts a hidden name rather
+/// than an extension.
+pub fn extension(name: &str) -> Option<String> {
+    Path::new(name).extension().map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+pub fn parse_kind(text: &str) -> Result<NodeKind, String> {
+    match text {
+        "file" | "f" => Ok(NodeKind::File),
+        "dir" | "directory" | "d" => Ok(NodeKind::Directory),
+        "symlink" | "link" | "l" => Ok(NodeKind::Symlink),
+        _ => Err(format!("Unknown type '{}', use file, dir or symlink", text)),
+    }
+}
+
+/// Reads a size like `4096`, `100M` or `1.5GB`. Units are powers of 1024, as everywhere sizes
+/// are shown.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let lower = text.trim().to_lowercase();
+    let number_end = lower.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(number_end);
+    let multiplier: u64 = match unit.trim_end_matches("ib").trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return Err(format!("Invalid size '{}'", text)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size '{}'", text))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Reads an age like `90s`, `15m`, `12h`, `30d`, `2w` or `1y` into seconds.
+pub fn parse_age(text: &str) -> Result<i64, String> {
+    let text = text.trim();
+    let number_end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(number_end);
+    let seconds: i64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid age '{}', use a number followed by s, m, h, d, w or y", text)),
+    };
+    let number: i64 = number.parse().map_err(|_| format!("Invalid age '{}'", text))?;
+    number.checked_mul(seconds).ok_or_else(|| format!("Age '{}' is too large", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{MemoryScanner, ScanEntry, ScanOptions, ScanProgress};
+    use crate::system::{build_fs_model_blocking, FileMetadata};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn file(path: &str, size: u64, days_old: i64) -> ScanEntry {
+        let metadata = FileMetadata { modified: now() - days_old * DAY, ..FileMetadata::default() };
+        ScanEntry::new(format!("/memory/{}", path), size, true).with_metadata(metadata)
+    }
+
+    fn tree() -> FileSystemTree {
+        let entries = vec![
+            file("b.txt", 300, 10),
+            ScanEntry::new("/memory/Photos".to_string(), 0, false),
+            file("Photos/one.jpg", 5, 2),
+            file("Photos/two.jpg", 5, 2),
+            file("A.mp4", 100, 400),
+            file("c.MOV", 2000, 1),
+        ];
+        build_fs_model_blocking("/memory".to_string(), &MemoryScanner::new(entries), ScanOptions::default(), &ScanProgress::new()).unwrap()
+    }
+
+    // Names `display` would list below the root with `args`
+    fn list(tree: &FileSystemTree, args: &str) -> Vec<String> {
+        let options = DisplayOptions::parse(args).unwrap();
+        let (ids, _) = select(tree, tree.root(), &options, |node| node.size());
+        ids.into_iter().map(|id| tree.get_name(id).to_string()).collect()
+    }
+
+    #[test]
+    fn every_sort_key_orders_the_listing() {
+        let tree = tree();
+        assert_eq!(list(&tree, ""), ["b.txt", "Photos", "A.mp4", "c.MOV"]);
+        assert_eq!(list(&tree, "--sort name"), ["A.mp4", "b.txt", "c.MOV", "Photos"]);
+        assert_eq!(list(&tree, "--sort name --desc"), ["Photos", "c.MOV", "b.txt", "A.mp4"]);
+        assert_eq!(list(&tree, "--sort size"), ["c.MOV", "b.txt", "A.mp4", "Photos"]);
+        assert_eq!(list(&tree, "--sort size --asc"), ["Photos", "A.mp4", "b.txt", "c.MOV"]);
+        // Directories keep the time they were scanned with, which is none here
+        assert_eq!(list(&tree, "--sort mtime"), ["c.MOV", "b.txt", "A.mp4", "Photos"]);
+        assert_eq!(list(&tree, "--sort type"), ["Photos", "c.MOV", "A.mp4", "b.txt"]);
+        assert_eq!(list(&tree, "--sort items"), ["Photos", "c.MOV", "b.txt", "A.mp4"]);
+        assert!(DisplayOptions::parse("--sort colour").is_err());
+        assert!(DisplayOptions::parse("--sort").is_err());
+    }
+
+    #[test]
+    fn filters_leave_entries_out() {
+        let tree = tree();
+        assert_eq!(list(&tree, "--type dir"), ["Photos"]);
+        assert_eq!(list(&tree, "--type file --sort name"), ["A.mp4", "b.txt", "c.MOV"]);
+        assert_eq!(list(&tree, "--ext .MP4,mov --sort name"), ["A.mp4", "c.MOV"]);
+        assert_eq!(list(&tree, "--min 200 --max 1K"), ["b.txt"]);
+        assert_eq!(list(&tree, "--type file --older 30d"), ["A.mp4"]);
+        assert_eq!(list(&tree, "--newer 5d"), ["c.MOV"]);
+        assert_eq!(list(&tree, "--sort size --top 2"), ["c.MOV", "b.txt"]);
+        assert_eq!(list(&tree, "--type symlink"), Vec::<String>::new());
+
+        let (shown, total) = select(&tree, tree.root(), &DisplayOptions::parse("--ext jpg").unwrap(), |node| node.size());
+        assert_eq!((shown.len(), total), (0, 4));
+        assert!(!DisplayOptions::parse("--sort size --desc").unwrap().is_filtered());
+        assert!(DisplayOptions::parse("--top 1").unwrap().is_filtered());
+        assert!(DisplayOptions::parse("--min").is_err());
+        assert!(DisplayOptions::parse("--colour red").is_err());
+    }
+
+    #[test]
+    fn sizes_are_read_in_powers_of_1024() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("1.5GB"), Ok(3 << 29));
+        assert_eq!(parse_size(" 2KiB "), Ok(2048));
+        assert_eq!(parse_size("1t"), Ok(1 << 40));
+        for invalid in ["", "M", "10x", "1.2.3K", "-5"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ages_are_read_in_seconds() {
+        assert_eq!(parse_age("90s"), Ok(90));
+        assert_eq!(parse_age("15m"), Ok(15 * 60));
+        assert_eq!(parse_age("12h"), Ok(12 * 60 * 60));
+        assert_eq!(parse_age("30d"), Ok(30 * DAY));
+        assert_eq!(parse_age("2w"), Ok(14 * DAY));
+        assert_eq!(parse_age("1y"), Ok(365 * DAY));
+        for invalid in ["", "30", "d", "3 d", "-1d", "1.5d"] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_age("9999999999999y"), Err("Age '9999999999999y' is too large".to_string()));
+        assert!(parse_age("99999999999999999999s").is_err());
+    }
+}
@@ -1,57 +1,85 @@
 use std::os::unix::fs::FileTypeExt;
 use std::fs;
-use std::sync::Arc;
-use parking_lot::Mutex;
 use std::collections::HashSet;
 use rayon::prelude::*;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_void;
 use libc::*;
 use std::mem;
-
-pub fn fetch_file_system_with_getattrlistbulk_parallel(path: &str) -> Vec<(String, u64, bool)> {
-    println!("Processing directory: {}", path);
-
-    let results = Arc::new(Mutex::new(Vec::new()));
-    let mut directories_to_process = vec![path.to_string()];
+use std::ptr;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::exclude::relative_to;
+use crate::scanner::{DirectoryListing, ScanEntry, ScanError, ScanErrorKind, ScanOptions, ScanProgress, ScanSink};
+use crate::system::{FileMetadata, SymlinkInfo};
+use crate::watch::WatchEvent;
+
+pub fn fetch_file_system_with_getattrlistbulk_parallel(path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+    let root_key = fs::metadata(path).map(|metadata| (metadata.dev(), metadata.ino())).unwrap_or_default();
+    let mut directories_to_process = vec![(path.to_string(), root_key)];
+    // Directories are tracked by (device, inode) so a followed link can never lead back into one
     let mut visited_directories = HashSet::new();
 
-    while !directories_to_process.is_empty() {
-        directories_to_process.retain(|dir| visited_directories.insert(dir.clone()));
-
-        let results_clone = Arc::clone(&results);
+    while !directories_to_process.is_empty() && !progress.is_cancelled() {
+        // A directory reached a second time is left as it is, empty
+        directories_to_process.retain(|(dir_path, key)| {
+            let first_visit = visited_directories.insert(*key);
+            if !first_visit {
+                sink.finish(dir_path);
+            }
+            first_visit
+        });
 
-        let new_directories: Vec<String> = directories_to_process
+        let new_directories: Vec<(String, (u64, u64))> = directories_to_process
             .into_par_iter()
-            .flat_map(|dir_path| {
-                if dir_path.ends_with(".framework") {
-                    // println!("Skipping .framework directory: {}", dir_path);
+            .flat_map(|(dir_path, _)| {
+                if progress.is_cancelled() {
                     return Vec::new();
                 }
 
-                let dir_results = fetch_file_system_with_getattrlistbulk(&dir_path);
+                let mut local_errors = Vec::new();
+                let dir_results = fetch_file_system_with_getattrlistbulk(&dir_path, options, &mut local_errors);
 
                 let mut subdirectories = Vec::new();
                 let mut local_results = Vec::new();
+                let mut local_excluded = Vec::new();
+                let mut unentered = Vec::new();
 
-                for (file_name, size, is_file) in dir_results {
-                    let entry_path = if dir_path == "/" {
-                        format!("/{}", file_name)
+                for (mut entry, directory_key) in dir_results {
+                    entry.path = if dir_path == "/" {
+                        format!("/{}", entry.path)
                     } else {
-                        format!("{}/{}", dir_path, file_name)
+                        format!("{}/{}", dir_path, entry.path)
                     };
 
-                    if !is_file {
-                        subdirectories.push(entry_path.clone());
+                    // Excluded subtrees are dropped here, before anything gets to open them
+                    if options.exclude.is_excluded(relative_to(path, &entry.path), directory_key.is_some()) {
+                        local_excluded.push(entry.path);
+                        continue;
                     }
 
-                    local_results.push((entry_path, size, is_file));
+                    // With --one-file-system a directory on another device is listed but not entered
+                    if let Some(key) = directory_key {
+                        if !options.one_file_system || key.0 == root_key.0 {
+                            subdirectories.push((entry.path.clone(), key));
+                        } else {
+                            unentered.push(entry.path.clone());
+                        }
+                    }
+
+                    local_results.push(entry);
                 }
 
-                // Safely add results using Mutex
-                {
-                    let mut results_lock = results_clone.lock();
-                    results_lock.extend(local_results);
+                progress.record_directory(&dir_path, &local_results);
+                sink.directory(&dir_path, DirectoryListing { entries: local_results, excluded: local_excluded, errors: local_errors });
+                for directory in unentered {
+                    sink.finish(&directory);
                 }
 
                 subdirectories
@@ -63,14 +91,30 @@ pub fn fetch_file_system_with_getattrlistbulk_parallel(path: &str) -> Vec<(Strin
         // Optional: Sleep to reduce system load
         // sleep(Duration::from_millis(1000));
     }
+}
+
 
-    Arc::try_unwrap(results)
-        .expect("Failed to unwrap Arc")
-        .into_inner()
+// Attributes are packed back to back with 4 byte alignment, so wider values may sit unaligned.
+unsafe fn read_attr<T: Copy>(field: &mut *const u8) -> T {
+    let value = ptr::read_unaligned(*field as *const T);
+    *field = field.add(mem::size_of::<T>());
+    value
 }
 
+unsafe fn read_link_at(dirfd: c_int, name: *const c_char) -> Option<PathBuf> {
+    let mut target = vec![0u8; PATH_MAX as usize];
+    let length = readlinkat(dirfd, name, target.as_mut_ptr() as *mut c_char, target.len());
+    if length < 0 {
+        return None;
+    }
+    target.truncate(length as usize);
+    Some(PathBuf::from(OsString::from_vec(target)))
+}
 
-fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)> {
+// Entries come back with their bare name in `path`; the caller joins it onto the directory.
+// Anything to descend into is paired with its (device, inode).
+// Whatever could not be read is pushed onto `errors` instead of being skipped silently.
+fn fetch_file_system_with_getattrlistbulk(path: &str, options: &ScanOptions, errors: &mut Vec<ScanError>) -> Vec<(ScanEntry, Option<(u64, u64)>)> {
     // println!("PATH: {}", path);
     if let Ok(metadata) = fs::symlink_metadata(path) {
         if metadata.file_type().is_fifo() {
@@ -91,7 +135,7 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
         let dirfd = open(c_path.as_ptr() as *const i8, O_RDONLY);
 
         if dirfd < 0 {
-            // eprintln!("Failed to open directory: {}", path);
+            errors.push(ScanError::last_os_error(path.to_string(), ScanErrorKind::Open));
             return results;
         }
 
@@ -99,10 +143,20 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
         let mut attrlist = attrlist {
             bitmapcount: ATTR_BIT_MAP_COUNT,
             reserved: 0,
-            commonattr: ATTR_CMN_NAME | ATTR_CMN_OBJTYPE | ATTR_CMN_RETURNED_ATTRS,
+            commonattr: ATTR_CMN_NAME
+                | ATTR_CMN_DEVID
+                | ATTR_CMN_OBJTYPE
+                | ATTR_CMN_MODTIME
+                | ATTR_CMN_CHGTIME
+                | ATTR_CMN_ACCTIME
+                | ATTR_CMN_OWNERID
+                | ATTR_CMN_GRPID
+                | ATTR_CMN_ACCESSMASK
+                | ATTR_CMN_FILEID
+                | ATTR_CMN_RETURNED_ATTRS,
             volattr: 0,
-            dirattr: 0,
-            fileattr: ATTR_FILE_TOTALSIZE,
+            dirattr: ATTR_DIR_LINKCOUNT | ATTR_DIR_ALLOCSIZE,
+            fileattr: ATTR_FILE_LINKCOUNT | ATTR_FILE_TOTALSIZE | ATTR_FILE_ALLOCSIZE,
             forkattr: 0,
         };
 
@@ -112,26 +166,30 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
         let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
 
         let attrlist_ptr = &mut attrlist as *mut attrlist as *mut c_void;
-        let result = getattrlistbulk(dirfd, attrlist_ptr, buffer_ptr, buffer_size, 0);
+        // Each call fills the buffer with as many entries as fit, so keep asking until it returns none
+        'batches: loop {
+            let result = getattrlistbulk(dirfd, attrlist_ptr, buffer_ptr, buffer_size, 0);
+            if result < 0 {
+                errors.push(ScanError::last_os_error(path.to_string(), ScanErrorKind::Read));
+                break;
+            }
+            if result == 0 {
+                break;
+            }
 
-        if result < 0 {
-            let errno_value = *libc::__error();
-            let err_msg = CStr::from_ptr(strerror(errno_value)).to_string_lossy();
-            eprintln!("getattrlistbulk failed: {}, {}", err_msg, path);
-        } else {
             let mut offset = 0;
             for _ in 0..result {
                 if offset >= buffer_size {
-                    eprintln!("Buffer overrun detected");
-                    break;
+                    errors.push(ScanError::new(path.to_string(), ScanErrorKind::Malformed, None));
+                    break 'batches;
                 }
 
                 let entry = buffer_ptr.add(offset) as *const u8;
 
                 let length = *(entry as *const u32) as usize;
                 if length == 0 || offset + length > buffer_size {
-                    eprintln!("Invalid entry length");
-                    break;
+                    errors.push(ScanError::new(path.to_string(), ScanErrorKind::Malformed, None));
+                    break 'batches;
                 }
 
                 let mut field = entry.add(mem::size_of::<u32>());
@@ -141,7 +199,10 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
 
                 let mut file_name = String::new();
                 let mut file_size = 0;
+                let mut allocated_size = 0;
                 let mut is_file = false;
+                let mut obj_type = 0;
+                let mut metadata = FileMetadata::default();
 
                 if attribute_set.commonattr & ATTR_CMN_NAME != 0 {
                     let name_info = *(field as *const attrreference_t);
@@ -149,32 +210,96 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
                     let name_ptr = field.add(name_info.attr_dataoffset as usize) as *const i8;
 
                     if name_ptr >= (buffer_ptr.add(buffer_size) as *const i8) || name_ptr < entry as *const i8 {
-                        eprintln!("Invalid name pointer detected");
-                        break;
+                        errors.push(ScanError::new(path.to_string(), ScanErrorKind::Malformed, None));
+                        break 'batches;
                     }
 
                     file_name = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
                     field = field.add(mem::size_of::<attrreference_t>());
                 }
 
+                if attribute_set.commonattr & ATTR_CMN_DEVID != 0 {
+                    metadata.device = read_attr::<dev_t>(&mut field) as u64;
+                }
+
                 if attribute_set.commonattr & ATTR_CMN_OBJTYPE != 0 {
-                    let obj_type = *(field as *const u32);
+                    obj_type = *(field as *const u32);
                     // println!("{}, {}", file_name, obj_type);
                     is_file = obj_type == 1 || obj_type == 5;
                     field = field.add(mem::size_of::<u32>());
                 }
 
+                if attribute_set.commonattr & ATTR_CMN_MODTIME != 0 {
+                    metadata.modified = read_attr::<timespec>(&mut field).tv_sec;
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_CHGTIME != 0 {
+                    metadata.changed = read_attr::<timespec>(&mut field).tv_sec;
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_ACCTIME != 0 {
+                    metadata.accessed = read_attr::<timespec>(&mut field).tv_sec;
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_OWNERID != 0 {
+                    metadata.uid = read_attr::<uid_t>(&mut field);
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_GRPID != 0 {
+                    metadata.gid = read_attr::<gid_t>(&mut field);
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_ACCESSMASK != 0 {
+                    metadata.mode = read_attr::<u32>(&mut field);
+                }
+
+                if attribute_set.commonattr & ATTR_CMN_FILEID != 0 {
+                    metadata.inode = read_attr::<u64>(&mut field);
+                }
+
+                if attribute_set.dirattr & ATTR_DIR_LINKCOUNT != 0 {
+                    metadata.nlink = read_attr::<u32>(&mut field) as u64;
+                }
+
+                if attribute_set.dirattr & ATTR_DIR_ALLOCSIZE != 0 {
+                    allocated_size = read_attr::<off_t>(&mut field).max(0) as u64;
+                }
+
+                if attribute_set.fileattr & ATTR_FILE_LINKCOUNT != 0 {
+                    metadata.nlink = read_attr::<u32>(&mut field) as u64;
+                }
+
+                // Both sizes are 64 bit off_t values; reading them as u32 truncated anything over 4 GiB
                 if attribute_set.fileattr & ATTR_FILE_TOTALSIZE != 0 {
-                    let file_size_ptr = field as *const u32;
-                    if file_size_ptr.is_null() || file_size_ptr.align_offset(mem::align_of::<u32>()) != 0 {
-                        eprintln!("Unaligned or null pointer for file size");
-                        file_size = 0; // Default to 0 or skip this entry
-                    } else {
-                        file_size = *file_size_ptr;
+                    file_size = read_attr::<off_t>(&mut field).max(0) as u64;
+                }
+
+                if attribute_set.fileattr & ATTR_FILE_ALLOCSIZE != 0 {
+                    allocated_size = read_attr::<off_t>(&mut field).max(0) as u64;
+                }
+
+                let mut directory_key = (obj_type == 2).then_some((metadata.device, metadata.inode));
+
+                let mut symlink = None;
+                if obj_type == 5 {
+                    if let Ok(c_name) = CString::new(file_name.as_str()) {
+                        let mut target_stat: stat = mem::zeroed();
+                        let broken = fstatat(dirfd, c_name.as_ptr(), &mut target_stat, 0) != 0;
+                        if options.follow_symlinks && !broken && (target_stat.st_mode & S_IFMT) == S_IFDIR {
+                            is_file = false;
+                            directory_key = Some((target_stat.st_dev as u64, target_stat.st_ino));
+                        }
+                        symlink = read_link_at(dirfd, c_name.as_ptr()).map(|target| SymlinkInfo { target, broken });
                     }
                 }
 
-                results.push((file_name, file_size as u64, is_file));
+                let mut scan_entry = ScanEntry::new(file_name, file_size, is_file)
+                    .with_allocated_size(allocated_size)
+                    .with_metadata(metadata);
+                if let Some(symlink) = symlink {
+                    scan_entry = scan_entry.with_symlink(symlink);
+                }
+                results.push((scan_entry, directory_key));
                 offset += length;
             }
         }
@@ -183,4 +308,132 @@ fn fetch_file_system_with_getattrlistbulk(path: &str) -> Vec<(String, u64, bool)
     }
 
     results
-}
\ No newline at end of file
+}
+// The parts of CoreServices' FSEvents and CoreFoundation the watcher needs
+type FSEventStreamCallback = extern "C" fn(*const c_void, *mut c_void, usize, *mut c_void, *const u32, *const u64);
+
+#[repr(C)]
+struct FSEventStreamContext {
+    version: isize,
+    info: *mut c_void,
+    retain: *const c_void,
+    release: *const c_void,
+    copy_description: *const c_void,
+}
+
+#[repr(C)]
+struct CFArrayCallBacks {
+    version: isize,
+    retain: *const c_void,
+    release: *const c_void,
+    copy_description: *const c_void,
+    equal: *const c_void,
+}
+
+#[link(name = "CoreServices", kind = "framework")]
+extern "C" {
+    fn FSEventStreamCreate(
+        allocator: *const c_void,
+        callback: FSEventStreamCallback,
+        context: *const FSEventStreamContext,
+        paths_to_watch: *const c_void,
+        since_when: u64,
+        latency: f64,
+        flags: u32,
+    ) -> *mut c_void;
+    fn FSEventStreamScheduleWithRunLoop(stream: *mut c_void, run_loop: *mut c_void, run_loop_mode: *const c_void);
+    fn FSEventStreamStart(stream: *mut c_void) -> u8;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFRunLoopDefaultMode: *const c_void;
+    static kCFTypeArrayCallBacks: CFArrayCallBacks;
+    fn CFRunLoopGetCurrent() -> *mut c_void;
+    fn CFRunLoopRun();
+    fn CFStringCreateWithCString(allocator: *const c_void, c_string: *const c_char, encoding: u32) -> *const c_void;
+    fn CFArrayCreate(allocator: *const c_void, values: *const *const c_void, count: isize, callbacks: *const CFArrayCallBacks) -> *const c_void;
+}
+
+const CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+const EVENT_ID_SINCE_NOW: u64 = u64::MAX;
+const CREATE_FLAG_NO_DEFER: u32 = 0x02;
+const CREATE_FLAG_FILE_EVENTS: u32 = 0x10;
+const EVENT_FLAG_MUST_SCAN_SUBDIRS: u32 = 0x01;
+const EVENT_FLAG_USER_DROPPED: u32 = 0x02;
+const EVENT_FLAG_KERNEL_DROPPED: u32 = 0x04;
+// Seconds FSEvents waits to coalesce changes before calling back
+const LATENCY: f64 = 0.1;
+
+// FSEvents merges flags for the same path, so whether the entry is still there decides the event
+extern "C" fn fs_events_callback(
+    _stream: *const c_void,
+    info: *mut c_void,
+    count: usize,
+    paths: *mut c_void,
+    flags: *const u32,
+    _ids: *const u64,
+) {
+    let events = unsafe { &*(info as *const UnboundedSender<WatchEvent>) };
+    let paths = paths as *const *const c_char;
+
+    for i in 0..count {
+        let (path, flags) = unsafe {
+            let path = CStr::from_ptr(*paths.add(i)).to_string_lossy().into_owned();
+            (PathBuf::from(path), *flags.add(i))
+        };
+        let event = if flags & (EVENT_FLAG_MUST_SCAN_SUBDIRS | EVENT_FLAG_USER_DROPPED | EVENT_FLAG_KERNEL_DROPPED) != 0 {
+            WatchEvent::Rescan(path)
+        } else if fs::symlink_metadata(&path).is_ok() {
+            WatchEvent::Changed(path)
+        } else {
+            WatchEvent::Removed(path)
+        };
+        let _ = events.send(event);
+    }
+}
+
+/// Watches everything below `root` with an FSEvents stream, run from its own thread.
+/// FSEvents is recursive by itself, so unlike inotify no per-directory setup is needed.
+pub fn watch(root: PathBuf, events: UnboundedSender<WatchEvent>) -> io::Result<()> {
+    let c_root = CString::new(root.as_os_str().as_encoded_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    thread::Builder::new()
+        .name("fsevents".to_string())
+        .spawn(move || unsafe {
+            // Lives as long as the stream, which is as long as the thread
+            let info = Box::into_raw(Box::new(events)) as *mut c_void;
+            let context = FSEventStreamContext {
+                version: 0,
+                info,
+                retain: ptr::null(),
+                release: ptr::null(),
+                copy_description: ptr::null(),
+            };
+
+            let path = CFStringCreateWithCString(ptr::null(), c_root.as_ptr(), CF_STRING_ENCODING_UTF8);
+            let paths = CFArrayCreate(ptr::null(), &path, 1, &kCFTypeArrayCallBacks);
+            let stream = FSEventStreamCreate(
+                ptr::null(),
+                fs_events_callback,
+                &context,
+                paths,
+                EVENT_ID_SINCE_NOW,
+                LATENCY,
+                CREATE_FLAG_NO_DEFER | CREATE_FLAG_FILE_EVENTS,
+            );
+            if stream.is_null() {
+                eprintln!("Failed to create an FSEvents stream for {}", root.display());
+                return;
+            }
+
+            FSEventStreamScheduleWithRunLoop(stream, CFRunLoopGetCurrent(), kCFRunLoopDefaultMode);
+            if FSEventStreamStart(stream) == 0 {
+                eprintln!("Failed to start watching {}", root.display());
+                return;
+            }
+            CFRunLoopRun();
+        })?;
+    Ok(())
+}
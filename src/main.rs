@@ -1,4 +1,7 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use clap::Parser;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::BufReader;
@@ -6,9 +9,16 @@ use tokio::sync::mpsc;
 
 use rust::system::*;
 use rust::kernel::Kernel;
+use rust::listing::DisplayOptions;
+use rust::query::{Query, SavedQueries};
 use rust::gui;
 use rust::threads::*;
 use rust::ai;
+use rust::snapshot;
+use rust::watch::{self, WatchEvent};
+use rust::scanner::{scanner_for, ScanOptions, ScanProgress, Scanner, ScannerKind};
+use rust::exclude::ExcludeSet;
+use rust::trash::Trash;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -23,7 +33,54 @@ struct Cli {
 
     // actions
     #[arg(long)]
-    action_file: bool
+    action_file: bool,
+
+    /// Move deleted items to the trash instead of removing them
+    #[arg(long)]
+    trash: bool,
+
+    /// Move deleted items into this directory instead of removing them
+    #[arg(long, value_name = "DIR")]
+    quarantine: Option<PathBuf>,
+
+    /// Scanner backend used to load a directory
+    #[arg(long, value_enum, default_value_t = ScannerKind::Native)]
+    scanner: ScannerKind,
+
+    /// Descend into directories reached through symbolic links
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Do not descend into directories on other filesystems
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Gitignore-style pattern to leave out of the scan (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// File of gitignore-style patterns to leave out of the scan
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Option<PathBuf>,
+
+    /// Start from this snapshot when it exists, and write it after every full scan
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+
+    /// Keep the loaded tree up to date with changes made on disk
+    #[arg(long)]
+    watch: bool,
+}
+
+// Everything the backends need to know from the command line
+struct BackendConfig {
+    dry: bool,
+    action_file: bool,
+    trash: Option<Trash>,
+    scanner_kind: ScannerKind,
+    scan_options: ScanOptions,
+    snapshot: Option<PathBuf>,
+    watch: bool,
 }
 
 #[tokio::main]
@@ -33,13 +90,49 @@ async fn main() {
     println!("Parsed CLI arguments: {:?}", cli);
     println!("Dry: {}", cli.dry);
     println!("GUI mode: {}", cli.gui_mode);
+
+    let mut exclude = ExcludeSet::new();
+    // Bundles are huge and rarely interesting; `--exclude '!*.framework/'` brings them back
+    if cfg!(target_os = "macos") {
+        let _ = exclude.add("*.framework/");
+    }
+    if let Some(exclude_file) = &cli.exclude_from {
+        if let Err(err) = exclude.add_file(exclude_file) {
+            eprintln!("Failed to read {}: {}", exclude_file.display(), err);
+        }
+    }
+    for pattern in &cli.exclude {
+        if let Err(err) = exclude.add(pattern) {
+            eprintln!("{}", err);
+        }
+    }
+
+    let scan_options = ScanOptions {
+        follow_symlinks: cli.follow_symlinks,
+        exclude,
+        one_file_system: cli.one_file_system,
+    };
+    let trash = match (cli.quarantine, cli.trash) {
+        (Some(dir), _) => Some(Trash::quarantine(dir)),
+        (None, true) => Trash::platform().map_err(|err| eprintln!("{}", err)).ok(),
+        (None, false) => None,
+    };
+    let config = BackendConfig {
+        dry: cli.dry,
+        action_file: cli.action_file,
+        trash,
+        scanner_kind: cli.scanner,
+        scan_options,
+        snapshot: cli.snapshot,
+        watch: cli.watch,
+    };
     
     if cli.gui_mode {
         let (to_backend, mut from_gui) = mpsc::channel(32);
         let (to_gui, from_backend) = mpsc::channel(32);
 
         let backend_handle = tokio::spawn(async move {
-            run_backend(&mut from_gui, &to_gui, cli.dry, cli.action_file).await;
+            run_backend(&mut from_gui, &to_gui, config).await;
         });
 
         // Start the GUI application in the main thread
@@ -48,141 +141,454 @@ async fn main() {
         // Wait for the backend task to finish
         backend_handle.await.unwrap();
     } else {
-        lone_run_backend(cli.dry, cli.action_file).await;
+        lone_run_backend(config).await;
     }
 }
 
 
+// How often a running scan reports its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// How long to wait for more watcher events before applying a batch
+const WATCH_COALESCE: Duration = Duration::from_millis(100);
+
 enum BackendState {
     Uninitialized,
     Initialized {
         kernel: Arc<Mutex<Kernel>>,
-        current_node: Arc<Mutex<FileSystemNode>>,
+        current_node: NodeId,
     },
 }
 
-async fn run_backend(from_gui: &mut mpsc::Receiver<Command>, to_gui: &mpsc::Sender<BackendResponse>, dry: bool, action_file: bool) {
+async fn run_backend(from_gui: &mut mpsc::Receiver<Command>, to_gui: &mpsc::Sender<BackendResponse>, config: BackendConfig) {
+    let BackendConfig { dry, action_file, trash, scanner_kind, scan_options, snapshot, watch } = config;
     let mut state = BackendState::Uninitialized;
+    let scanner: Arc<dyn Scanner> = scanner_for(scanner_kind).into();
+    let mut watch_events = None;
+
+    if let Some(snapshot_path) = snapshot.as_ref().filter(|path| path.exists()) {
+        match snapshot::load(snapshot_path) {
+            Ok(tree) => {
+                let path = tree.root_path().to_string_lossy().into_owned();
+                let current_node = tree.root();
+                if watch {
+                    watch_events = start_watching(&tree, &scan_options);
+                }
+                let kernel = Arc::new(Mutex::new(Kernel::new(tree, action_file, dry, trash.clone())));
+                spawn_revalidation(kernel.clone(), scanner.clone(), scan_options.clone(), snapshot_path.clone(), Some(to_gui.clone()));
+                state = BackendState::Initialized {
+                    kernel,
+                    current_node,
+                };
+                if let Err(err) = to_gui.send(BackendResponse::Loaded(format!("Snapshot loaded: {} (revalidating in the background)", path))).await {
+                    eprintln!("Failed to send response to GUI: {}", err);
+                }
+            }
+            Err(err) => {
+                send_error(to_gui, format!("Failed to load snapshot {}: {}", snapshot_path.display(), err)).await;
+            }
+        }
+    }
 
     loop {
-        match from_gui.recv().await {
-            Some(command) => match &mut state {
-                BackendState::Uninitialized => match command {
-                    Command::LoadDirectory(path) => {
-                        match build_fs_model(path.clone()).await {
-                            Some(fs_root) => {
-                                let kernel = Arc::new(Mutex::new(Kernel::new(fs_root.clone(), action_file, dry)));
-                                state = BackendState::Initialized {
-                                    kernel: kernel.clone(),
-                                    current_node: fs_root,
-                                };
-                                send_response(&to_gui, format!("Directory loaded: {}", path)).await;
+        let command = tokio::select! {
+            command = from_gui.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            event = next_watch_event(&mut watch_events) => {
+                match (event, &mut state, watch_events.as_mut()) {
+                    (Some(event), BackendState::Initialized { kernel, current_node }, Some(events)) => {
+                        let changed = apply_watch_events(event, events, kernel.clone(), scanner.clone(), &scan_options).await;
+                        let (current_path, display) = {
+                            let kernel = kernel.lock().unwrap();
+                            *current_node = kernel.tree().nearest_existing(*current_node);
+                            (kernel.tree().get_path(*current_node), kernel.display(*current_node, kernel.get_display_options()))
+                        };
+                        if changed.contains(&current_path) {
+                            send_response(to_gui, display).await;
+                        }
+                    }
+                    (None, _, _) => watch_events = None,
+                    _ => {}
+                }
+                continue;
+            }
+        };
+
+        match &mut state {
+            BackendState::Uninitialized => match command {
+                Command::LoadDirectory(path) => {
+                    let progress = Arc::new(ScanProgress::new());
+                    let (tree, exit) = load_directory(path.clone(), scanner.clone(), &scan_options, progress.clone(), from_gui, to_gui).await;
+                    match tree {
+                        Some(tree) => {
+                            let mut message = format!("Directory loaded: {}", path);
+                            if let Some(snapshot_path) = &snapshot {
+                                message.push_str(&format!("\n{}", save_snapshot(&tree, snapshot_path)));
                             }
-                            None => {
-                                send_error(&to_gui, "Failed to load directory.".to_string()).await;
+                            if watch {
+                                watch_events = start_watching(&tree, &scan_options);
+                            }
+                            let current_node = tree.root();
+                            state = BackendState::Initialized {
+                                kernel: Arc::new(Mutex::new(Kernel::new(tree, action_file, dry, trash.clone()))),
+                                current_node,
+                            };
+                            if let Err(err) = to_gui.send(BackendResponse::Loaded(message)).await {
+                                eprintln!("Failed to send response to GUI: {}", err);
                             }
                         }
+                        None if progress.is_cancelled() => {
+                            if let Err(err) = to_gui.send(BackendResponse::LoadCancelled(format!("Load of {} cancelled.", path))).await {
+                                eprintln!("Failed to send response to GUI: {}", err);
+                            }
+                        }
+                        None => {
+                            send_error(to_gui, "Failed to load directory.".to_string()).await;
+                        }
                     }
-                    Command::Exit => break,
-                    _ => {
-                        send_error(&to_gui, "Load a directory before issuing commands.".to_string()).await;
+                    if exit {
+                        break;
                     }
-                },
-                BackendState::Initialized {kernel, current_node } => {
-                    let updated_node = handle_command(
-                        command,
-                        kernel.clone(),
-                        current_node.clone(),
-                        &to_gui,
-                    )
-                    .await;
-                    *current_node = updated_node;
+                }
+                Command::CancelLoad => {
+                    send_error(to_gui, "No load in progress.".to_string()).await;
+                }
+                Command::Exit => break,
+                _ => {
+                    send_error(to_gui, "Load a directory before issuing commands.".to_string()).await;
                 }
             },
-            None => break, // Handle sender disconnect
+            BackendState::Initialized {kernel, current_node } => {
+                let updated_node = handle_command(
+                    command,
+                    kernel.clone(),
+                    *current_node,
+                    to_gui,
+                    scanner.clone(),
+                    &scan_options,
+                )
+                .await;
+                let kernel = kernel.lock().unwrap();
+                *current_node = kernel.tree().nearest_existing(updated_node);
+            }
         }
     }
 }
 
+fn start_watching(tree: &FileSystemTree, scan_options: &ScanOptions) -> Option<mpsc::UnboundedReceiver<WatchEvent>> {
+    match watch::start(tree, scan_options) {
+        Ok(events) => Some(events),
+        Err(err) => {
+            eprintln!("Failed to watch {}: {}", tree.root_path().display(), err);
+            None
+        }
+    }
+}
+
+// Waits for the next watcher event, or forever when nothing is being watched
+async fn next_watch_event(events: &mut Option<mpsc::UnboundedReceiver<WatchEvent>>) -> Option<WatchEvent> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Applies a burst of watcher events at once and returns the directories whose listing changed
+async fn apply_watch_events(
+    first: WatchEvent,
+    events: &mut mpsc::UnboundedReceiver<WatchEvent>,
+    kernel: Arc<Mutex<Kernel>>,
+    scanner: Arc<dyn Scanner>,
+    scan_options: &ScanOptions,
+) -> HashSet<PathBuf> {
+    // A build touches many files in a row; give the rest of them a moment to arrive
+    tokio::time::sleep(WATCH_COALESCE).await;
+    let batch = watch::coalesce(first, events);
+
+    let scan_options = scan_options.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut kernel = kernel.lock().unwrap();
+        batch
+            .iter()
+            .filter_map(|event| kernel.apply_watch_event(event, scanner.as_ref(), &scan_options))
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn save_snapshot(tree: &FileSystemTree, snapshot_path: &Path) -> String {
+    match snapshot::save(tree, snapshot_path) {
+        Ok(()) => format!("Snapshot written to {}.", snapshot_path.display()),
+        Err(err) => format!("Failed to write snapshot {}: {}", snapshot_path.display(), err),
+    }
+}
+
+// Re-stats a tree loaded from a snapshot in the background and reports what it patched.
+// The snapshot is rewritten when something changed, unless actions are queued: those are
+// already applied to the model and would otherwise be saved as if they had happened.
+fn spawn_revalidation(
+    kernel: Arc<Mutex<Kernel>>,
+    scanner: Arc<dyn Scanner>,
+    scan_options: ScanOptions,
+    snapshot_path: PathBuf,
+    to_gui: Option<mpsc::Sender<BackendResponse>>,
+) {
+    tokio::spawn(async move {
+        let revalidated_kernel = kernel.clone();
+        let report = match tokio::task::spawn_blocking(move || snapshot::revalidate(&revalidated_kernel, scanner.as_ref(), &scan_options)).await {
+            Ok(report) => report,
+            Err(err) => {
+                eprintln!("Revalidation failed: {}", err);
+                return;
+            }
+        };
+
+        let mut message = format!("Revalidated snapshot: {}.", report);
+        {
+            let kernel = kernel.lock().unwrap();
+            if !report.is_unchanged() && !kernel.has_pending_actions() {
+                message.push_str(&format!(" {}", save_snapshot(kernel.tree(), &snapshot_path)));
+            }
+        }
+        match to_gui {
+            Some(to_gui) => send_response(&to_gui, message).await,
+            None => println!("{}", message),
+        }
+    });
+}
+
+// Resolves a command argument against the current directory unless it is absolute
+// Anything in the tree, named by ID or path, gives that node's path; other paths are taken as
+// relative to the current directory
+fn absolute_path(kernel: &Kernel, current_node: NodeId, target: &str) -> String {
+    match kernel.resolve(current_node, target) {
+        Some(node) => kernel.tree().get_path(node).to_string_lossy().into_owned(),
+        None if target.starts_with('/') => target.to_string(),
+        None => kernel.tree().get_path(current_node).join(target).to_string_lossy().into_owned(),
+    }
+}
+
+// Paths of what a `del` or `move` source names: every result a `#` selection picks, or the one
+// item a plain target resolves to
+fn selected_paths(kernel: &Kernel, current_node: NodeId, target: &str) -> Result<Vec<String>, String> {
+    if !target.starts_with('#') {
+        return Ok(vec![absolute_path(kernel, current_node, target)]);
+    }
+    let selected = kernel.resolve_selection(current_node, target)?;
+    Ok(selected.into_iter().map(|node| kernel.tree().get_path(node).to_string_lossy().into_owned()).collect())
+}
+
+// Sums up the moves of a `move`, listing the ones that were refused; fails only when nothing moved
+fn move_report(original_path: &str, new_path: &str, results: Vec<Result<(), String>>) -> Result<String, String> {
+    let (total, moved) = (results.len(), results.iter().filter(|result| result.is_ok()).count());
+    let mut report: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+    match moved {
+        0 => return Err(report.join("\n")),
+        1 if total == 1 => report.push(format!("Moved {} to {}.", original_path, new_path)),
+        _ if moved == total => report.push(format!("Moved {} items to {}.", moved, new_path)),
+        _ => report.push(format!("Moved {} of {} items to {}.", moved, total, new_path)),
+    }
+    Ok(report.join("\n"))
+}
+
+// Marks everything `target` selects and reports on each; fails only when nothing was marked
+fn mark_selection(kernel: &mut Kernel, current_node: NodeId, target: &str) -> Result<String, String> {
+    let mut marked = 0;
+    let report: Vec<String> = kernel
+        .resolve_selection(current_node, target)?
+        .into_iter()
+        .map(|node| match kernel.mark_for_deletion(node) {
+            Ok(()) => {
+                marked += 1;
+                format!("Marked {} for deletion.", kernel.tree().get_path(node).display())
+            }
+            Err(err) => err,
+        })
+        .collect();
+    if marked == 0 {
+        Err(report.join("\n"))
+    } else {
+        Ok(report.join("\n"))
+    }
+}
+
+// Runs the query saved as `name`
+fn run_saved_query(kernel: &mut Kernel, current_node: NodeId, name: &str) -> Result<String, String> {
+    let saved = SavedQueries::load(SavedQueries::default_path()).map_err(|err| format!("Failed to read saved queries: {}", err))?;
+    let text = saved.get(name).ok_or_else(|| format!("No saved query named {}; `query` lists them.", name))?;
+    let query = Query::parse(text).map_err(|err| format!("Saved query {} is invalid: {}", name, err))?;
+    kernel.select(current_node, &query)
+}
+
+// Lists, saves or removes the queries in the saved queries file
+fn edit_saved_queries(change: Option<(&str, Option<&str>)>) -> Result<String, String> {
+    let mut saved = SavedQueries::load(SavedQueries::default_path()).map_err(|err| format!("Failed to read saved queries: {}", err))?;
+    let message = match change {
+        None if saved.names().next().is_none() => return Ok(format!("No saved queries in {}.", saved.path().display())),
+        None => {
+            let mut list = format!("Saved queries in {}:\n", saved.path().display());
+            for (name, query) in saved.iter() {
+                list.push_str(&format!("{} = {}\n", name, query));
+            }
+            return Ok(list);
+        }
+        Some((name, Some(query))) => {
+            saved.set(name, query);
+            format!("Saved query {}.", name)
+        }
+        Some((name, None)) => {
+            if !saved.remove(name) {
+                return Err(format!("No saved query named {}.", name));
+            }
+            format!("Removed query {}.", name)
+        }
+    };
+    saved.save().map_err(|err| format!("Failed to write {}: {}", saved.path().display(), err))?;
+    Ok(message)
+}
+
+// Rescans a subtree on a blocking thread; the kernel stays locked meanwhile so no action can
+// be queued against nodes that are being reconciled
+async fn rescan(
+    kernel: Arc<Mutex<Kernel>>,
+    current_node: NodeId,
+    path: Option<String>,
+    scanner: Arc<dyn Scanner>,
+    scan_options: &ScanOptions,
+) -> Result<String, String> {
+    let (target, target_path) = {
+        let kernel = kernel.lock().unwrap();
+        let target = match &path {
+            Some(path) => kernel
+                .resolve(current_node, path)
+                .ok_or_else(|| format!("Invalid path: {}.", path))?,
+            None => current_node,
+        };
+        (target, kernel.tree().get_path(target).to_string_lossy().into_owned())
+    };
+
+    let scan_options = scan_options.clone();
+    let report = tokio::task::spawn_blocking(move || kernel.lock().unwrap().rescan(target, scanner.as_ref(), &scan_options))
+        .await
+        .map_err(|err| format!("Rescan failed: {}", err))??;
+    Ok(format!("Rescanned {}: {}.", target_path, report))
+}
+
+// Runs a scan while still listening to the GUI, forwarding progress until it finishes.
+// CancelLoad stops the scan; the returned flag is set when the GUI asked to exit meanwhile.
+async fn load_directory(
+    path: String,
+    scanner: Arc<dyn Scanner>,
+    scan_options: &ScanOptions,
+    progress: Arc<ScanProgress>,
+    from_gui: &mut mpsc::Receiver<Command>,
+    to_gui: &mpsc::Sender<BackendResponse>,
+) -> (Option<FileSystemTree>, bool) {
+    let load = build_fs_model(path, scanner, scan_options, progress.clone());
+    tokio::pin!(load);
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    let mut exit = false;
+
+    loop {
+        tokio::select! {
+            tree = &mut load => return (tree, exit),
+            _ = ticker.tick() => {
+                if let Err(err) = to_gui.send(BackendResponse::LoadProgress(progress.snapshot())).await {
+                    eprintln!("Failed to send progress to GUI: {}", err);
+                }
+            }
+            command = from_gui.recv(), if !exit => match command {
+                Some(Command::CancelLoad) => progress.cancel(),
+                Some(Command::Exit) | None => {
+                    progress.cancel();
+                    exit = true;
+                }
+                Some(_) => send_error(to_gui, "Still loading, cancel the load first.".to_string()).await,
+            },
+        }
+    }
+}
 
 async fn handle_command(
     command: Command,
     kernel: Arc<Mutex<Kernel>>,
-    current_node: Arc<Mutex<FileSystemNode>>,
+    current_node: NodeId,
     to_gui: &mpsc::Sender<BackendResponse>,
-) -> Arc<Mutex<FileSystemNode>> {
+    scanner: Arc<dyn Scanner>,
+    scan_options: &ScanOptions,
+) -> NodeId {
     println!("Handling command async");
 
     match command {
-        Command::Del(index) => {
-            {
-            let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.mark_for_deletion(current_node.clone(), index);
+        Command::Del(target) => {
+            let marked = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                mark_selection(&mut kernel_guard, current_node, &target)
+            };
+            match marked {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(err) => send_error(to_gui, err).await,
             }
-            send_response(to_gui, format!("Marked index {} for deletion.", index)).await;
         }
         Command::Create(path, is_file) => {
-            {
-            let mut kernel_guard = kernel.lock().unwrap();
-            
-            kernel_guard.create(current_node.clone(), path.clone(), is_file);
+            let created = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.create(current_node, path.clone(), is_file)
+            };
+            match created {
+                Ok(()) => send_response(to_gui, format!("Created {}.", path)).await,
+                Err(err) => send_error(to_gui, err).await,
             }
-            send_response(to_gui, format!("Created {}.", path)).await;
         }
         Command::Move(original_path, new_path) => {
-            {
+            let moved = {
                 let mut kernel_guard = kernel.lock().unwrap();
             
                 // Get absolute paths
-                let abs_original_path = if original_path.starts_with('/') {
-                    original_path.to_string()
-                } else {
-                    let mut abs_path = current_node.lock().unwrap().get_path().to_string_lossy().to_string();
-                    abs_path.push('/');
-                    abs_path.push_str(&original_path);
-                    abs_path
-                };
-            
-                let abs_new_path = if new_path.starts_with('/') {
-                    new_path.to_string()
-                } else {
-                    let mut abs_path = current_node.lock().unwrap().get_path().to_string_lossy().to_string();
-                    abs_path.push('/');
-                    abs_path.push_str(&new_path);
-                    abs_path
-                };
+                let abs_original_paths = selected_paths(&kernel_guard, current_node, &original_path);
+                let abs_new_path = absolute_path(&kernel_guard, current_node, &new_path);
             
                 // Perform the move
-                kernel_guard.move_item(abs_original_path.clone(), abs_new_path.clone());
-            }
+                abs_original_paths.map(|paths| {
+                    paths
+                        .into_iter()
+                        .map(|abs_original_path| kernel_guard.move_item(abs_original_path, abs_new_path.clone()))
+                        .collect::<Vec<_>>()
+                })
+            };
 
             println!("Asdf");
         
             // Send response
-            send_response(to_gui, format!("Moved {} to {}.", original_path, new_path)).await;
+            match moved.map(|results| move_report(&original_path, &new_path, results)) {
+                Ok(Ok(report)) => send_response(to_gui, report).await,
+                Ok(Err(err)) | Err(err) => send_error(to_gui, err).await,
+            }
         }
         
         Command::Undo(index) => {
-            {let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.undo_deletion(index);}
-            send_response(to_gui, format!("Undid deletion of index {}.", index)).await;
+            let undone = kernel.lock().unwrap().undo_deletion(index);
+            match undone {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
         }
-        Command::Display => {
+        Command::Display(options) => {
             let display = {
-                let kernel_guard = kernel.lock().unwrap();
-                kernel_guard.display(current_node.clone())
+                let mut kernel_guard = kernel.lock().unwrap();
+                let display = kernel_guard.display(current_node, &options);
+                kernel_guard.set_display_options(options);
+                display
             };
             send_response(to_gui, display).await;
         }
         Command::Up => {
             let (response, new_node) = {
                 let kernel_guard = kernel.lock().unwrap(); // Lock the kernel
-                if let Some(parent) = kernel_guard.get_parent(current_node.clone()) {
-                    if let Some(upgraded) = parent.upgrade() {
-                        ("Moved up to parent directory.".to_string(), Some(upgraded)) // Success case
-                    } else {
-                        ("Error upgrading parent reference.".to_string(), None) // Failed to upgrade
-                    }
+                if let Some(parent) = kernel_guard.get_parent(current_node) {
+                    ("Moved up to parent directory.".to_string(), Some(parent)) // Success case
                 } else {
                     ("Already at the root directory.".to_string(), None) // No parent exists
                 }
@@ -196,13 +602,13 @@ async fn handle_command(
             }
         
         }
-        Command::Down(index) => {
+        Command::Down(target) => {
             let (response, child) = {
                 let kernel_guard = kernel.lock().unwrap();
-                if let Some(child_node) = kernel_guard.get_child(current_node.clone(), index) {
-                    ("Moved down to child directory.".to_string(), Some(child_node))
+                if let Some(child_node) = kernel_guard.get_directory(current_node, &target) {
+                    (format!("Moved to {}.", kernel_guard.tree().get_path(child_node).to_string_lossy()), Some(child_node))
                 } else {
-                    ("Invalid child index.".to_string(), None)
+                    (format!("Cannot move to {}.", target), None)
                 }
             };
 
@@ -213,10 +619,39 @@ async fn handle_command(
                 send_error(to_gui, response).await;
             }
         }
-        Command::Commit => {
-            {let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.commit_actions();}
-            send_response(to_gui, "Committed all actions.".to_string()).await;
+        Command::Commit(force) => {
+            let result = kernel.lock().unwrap().commit_actions(force);
+            match result {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(report) => send_error(to_gui, report).await,
+            }
+        }
+        Command::Check => {
+            let result = kernel.lock().unwrap().check();
+            match result {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(report) => send_error(to_gui, report).await,
+            }
+        }
+        Command::Restore(target) => {
+            let result = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                match target {
+                    Some(target) => kernel_guard.restore(current_node, &target, scanner.as_ref(), scan_options),
+                    None => kernel_guard.trashed(),
+                }
+            };
+            match result {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
+        }
+        Command::Purge(older_than) => {
+            let result = kernel.lock().unwrap().purge(older_than);
+            match result {
+                Ok(report) => send_response(to_gui, report).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
         }
         Command::Status => {
             let status = {
@@ -228,8 +663,8 @@ async fn handle_command(
         Command::GoTo(path) => {
             let (response, node) = {
                 let kernel_guard = kernel.lock().unwrap();
-                if let Some(node) = kernel_guard.go_to(path.clone()) {
-                    (format!("Navigated to {}.", path), Some(node))
+                if let Some(node) = kernel_guard.get_directory(current_node, &path) {
+                    (format!("Navigated to {}.", kernel_guard.tree().get_path(node).to_string_lossy()), Some(node))
                 } else {
                     (format!("Invalid path: {}.", path), None)
                 }
@@ -246,9 +681,8 @@ async fn handle_command(
         Command::Find(name) => {
             let response =  {
                 let kernel_guard = kernel.lock().unwrap();
-                let full_name = format!("{}/{}", current_node.lock().unwrap().get_path().to_string_lossy().to_string(), name);
-                if let Some(index) = kernel_guard.get_index(current_node.clone(), full_name) {
-                    index.to_string()
+                if let Some(node) = kernel_guard.resolve_path(current_node, &name) {
+                    node.to_string()
                 } else {
                     format!("No such item name: {}.", name)
                 }
@@ -257,34 +691,112 @@ async fn handle_command(
             send_response(to_gui, response).await;
             
         }
-        Command::Open(index) => {
-            {let kernel_guard = kernel.lock().unwrap();
-            kernel_guard.open_file(current_node.clone(), index);}
-            send_response(to_gui, format!("Opened file at index {}.", index)).await;
+        Command::Open(target) => {
+            let opened = {
+                let kernel_guard = kernel.lock().unwrap();
+                kernel_guard.resolve(current_node, &target).map(|node| kernel_guard.open_file(node))
+            };
+            match opened {
+                Some(()) => send_response(to_gui, format!("Opened {}.", target)).await,
+                None => send_error(to_gui, format!("No such item: {}.", target)).await,
+            }
+        }
+        Command::SizeMode(size_mode) => {
+            {kernel.lock().unwrap().set_size_mode(size_mode);}
+            send_response(to_gui, format!("Showing {} sizes.", size_mode)).await;
+        }
+        Command::Errors => {
+            let errors = {
+                let kernel_guard = kernel.lock().unwrap();
+                kernel_guard.scan_errors(current_node)
+            };
+            send_response(to_gui, errors).await;
+        }
+        Command::Stats => {
+            let stats = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.stats(current_node)
+            };
+            send_response(to_gui, stats).await;
+        }
+        Command::Search(query) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.search(current_node, &query)
+            };
+            send_response(to_gui, results).await;
+        }
+        Command::Select(query) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.select(current_node, &query)
+            };
+            match results {
+                Ok(response) => send_response(to_gui, response).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
+        }
+        Command::RunQuery(name) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                run_saved_query(&mut kernel_guard, current_node, &name)
+            };
+            match results {
+                Ok(response) => send_response(to_gui, response).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
+        }
+        Command::SavedQueries => match edit_saved_queries(None) {
+            Ok(response) => send_response(to_gui, response).await,
+            Err(err) => send_error(to_gui, err).await,
+        },
+        Command::SaveQuery(name, query) => match edit_saved_queries(Some((&name, Some(&query)))) {
+            Ok(response) => send_response(to_gui, response).await,
+            Err(err) => send_error(to_gui, err).await,
+        },
+        Command::RemoveQuery(name) => match edit_saved_queries(Some((&name, None))) {
+            Ok(response) => send_response(to_gui, response).await,
+            Err(err) => send_error(to_gui, err).await,
+        },
+        Command::Rescan(path) => {
+            match rescan(kernel.clone(), current_node, path, scanner, scan_options).await {
+                Ok(response) => send_response(to_gui, response).await,
+                Err(err) => send_error(to_gui, err).await,
+            }
         }
         Command::Help => {
             let help_message = r#"
         Available Commands:
         1. `..` - Moves up one level.
-        2. `<index>` - Moves down to the child at the specified index.
-        3. `go to <path>` - Navigates to the specified path.
-        4. `commit` - Commits the current state.
-        5. `undo <index>` - Reverts to a specific commit index.
+        2. `<id>` - Moves into the directory with the ID `display` lists for it.
+        3. `go to <target>` - Navigates to a directory by ID, name, or absolute or relative path.
+        4. `commit [--force]` - Applies every queued action to disk, or none of them if one fails; `--force` also commits items that changed on disk since the scan.
+        5. `undo <index>` - Takes the queued action at that index out of the queue and out of the model.
         6. `status` - Displays the current status.
-        7. `display` - Displays content or structure at the current level.
+        7. `display [options]` - Lists the current level, e.g. `display --sort size --desc --top 20 --min 100M`.
         8. `create file <name>` - Creates a file with the specified name.
         9. `create folder <name>` - Creates a folder with the specified name.
-        10. `del <index>` - Deletes the item at the specified index.
-        11. `open <index>` - Opens the item at the specified index.
-        12. `move <source> > <destination>` - Moves an item from source to destination.
-        13. `help` - Displays this help message.
+        10. `del <target>` - Marks an item (ID, name or path), or search results such as `#all` or `#2-5`, for deletion.
+        11. `open <target>` - Opens an item (ID, name or path).
+        12. `move <source> > <destination>` - Moves an item, or search results such as `#all`, into a directory; both may be IDs, names or paths.
+        13. `size <apparent|allocated>` - Switches between logical size and size on disk.
+        14. `errors` - Lists paths below the current directory that could not be read.
+        15. `rescan [path]` - Scans a directory again (the current one by default) to pick up outside changes.
+        16. `stats` - Shows counts, depth, newest and oldest entries and the size by extension below the current directory.
+        17. `search <name> [options]` - Finds matching items anywhere below the current directory, e.g. `search *.mov --min 1G --sort size`; `#N` then names the Nth result.
+        18. `select where <condition> [under <path>] [order by <field> [asc|desc]] [limit N]` - Lists what a query picks, e.g. `select where ext in (mp4,mov) and size > 1G and mtime < 2024-01-01 under ~/Movies order by size desc`.
+        19. `query [name]` - Lists the saved queries, or runs one; `query save <name> select ...` and `query remove <name>` edit them.
+        20. `check` - Lists every queued action that would stop a commit, such as a missing source, an existing destination or an item changed since the scan.
+        21. `restore [number|path]` - Lists the trash, or puts back an item it lists, or what was deleted from a path.
+        22. `purge --older-than <age>` - Empties the trash of what was deleted at least that long ago, e.g. `purge --older-than 30d`; `purge --all` empties it.
+        23. `help` - Displays this help message.
         "#;
             send_response(to_gui, help_message.to_string()).await;
         }
         Command::AISuggestion(input) => {
             let context = {
                 let kernel_guard = kernel.lock().unwrap();
-                kernel_guard.display(current_node.clone())
+                kernel_guard.display(current_node, &DisplayOptions::default())
             };
             let response = ai::ask(input, context).await;
             
@@ -301,7 +813,7 @@ async fn handle_command(
 
         }
         Command::AIConfirm => {
-            {kernel.lock().unwrap().convert_suggestions(current_node.clone());}
+            {kernel.lock().unwrap().convert_suggestions(current_node);}
 
             let _ = to_gui.send(BackendResponse::AIResponse("Applied suggestions (Still needs to be committed)".to_string())).await;
             
@@ -309,6 +821,9 @@ async fn handle_command(
         Command::Exit => {
             send_response(to_gui, "Exiting backend.".to_string()).await;
         }
+        Command::Error(message) => {
+            send_error(to_gui, message).await;
+        }
         _ => {
             send_error(to_gui, "Unknown command.".to_string()).await;
         }
@@ -319,14 +834,63 @@ async fn handle_command(
 }
 
 
-async fn lone_run_backend(dry: bool, action_file: bool) {
+async fn lone_run_backend(config: BackendConfig) {
+    let BackendConfig { dry, action_file, trash, scanner_kind, scan_options, snapshot, watch } = config;
     let mut state = BackendState::Uninitialized;
+    let scanner: Arc<dyn Scanner> = scanner_for(scanner_kind).into();
+    let mut watch_events = None;
+
+    if let Some(snapshot_path) = snapshot.as_ref().filter(|path| path.exists()) {
+        match snapshot::load(snapshot_path) {
+            Ok(tree) => {
+                println!("Snapshot loaded: {} (revalidating in the background)", tree.root_path().display());
+                let current_node = tree.root();
+                if watch {
+                    watch_events = start_watching(&tree, &scan_options);
+                }
+                let kernel = Arc::new(Mutex::new(Kernel::new(tree, action_file, dry, trash.clone())));
+                spawn_revalidation(kernel.clone(), scanner.clone(), scan_options.clone(), snapshot_path.clone(), None);
+                state = BackendState::Initialized {
+                    kernel,
+                    current_node,
+                };
+            }
+            Err(err) => {
+                println!("Failed to load snapshot {}: {}", snapshot_path.display(), err);
+            }
+        }
+    }
     let stdin = tokio::io::stdin(); // Use tokio's async stdin
     let mut reader = BufReader::new(stdin).lines();
 
     println!("Backend is running. Enter commands:");
 
-    while let Some(input) = reader.next_line().await.unwrap_or_else(|_| None) {
+    loop {
+        let input = tokio::select! {
+            input = reader.next_line() => match input.unwrap_or(None) {
+                Some(input) => input,
+                None => break,
+            },
+            event = next_watch_event(&mut watch_events) => {
+                match (event, &mut state, watch_events.as_mut()) {
+                    (Some(event), BackendState::Initialized { kernel, current_node }, Some(events)) => {
+                        let changed = apply_watch_events(event, events, kernel.clone(), scanner.clone(), &scan_options).await;
+                        let current_path = {
+                            let kernel = kernel.lock().unwrap();
+                            *current_node = kernel.tree().nearest_existing(*current_node);
+                            kernel.tree().get_path(*current_node)
+                        };
+                        if changed.contains(&current_path) {
+                            println!("{} changed on disk, `display` to see it.", current_path.display());
+                        }
+                    }
+                    (None, _, _) => watch_events = None,
+                    _ => {}
+                }
+                continue;
+            }
+        };
+
         if input.is_empty() {
             continue; // Skip empty lines
         }
@@ -336,17 +900,37 @@ async fn lone_run_backend(dry: bool, action_file: bool) {
                 let command = Command::LoadDirectory(input);
                 match command {
                     Command::LoadDirectory(path) => {
-                        match build_fs_model(path.clone()).await {
-                            Some(fs_root) => {
-                                let kernel = Arc::new(Mutex::new(Kernel::new(fs_root.clone(), action_file, dry)));
+                        let progress = Arc::new(ScanProgress::new());
+                        let load = build_fs_model(path.clone(), scanner.clone(), &scan_options, progress.clone());
+                        tokio::pin!(load);
+                        // The first tick fires right away, so short scans print nothing
+                        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + Duration::from_secs(1), Duration::from_secs(1));
+                        let tree = loop {
+                            tokio::select! {
+                                tree = &mut load => break tree,
+                                _ = ticker.tick() => {
+                                    let snapshot = progress.snapshot();
+                                    println!("Scanning: {} (at {})", snapshot, snapshot.current_path);
+                                }
+                            }
+                        };
+                        match tree {
+                            Some(tree) => {
+                                println!("Directory loaded: {}", path);
+                                if let Some(snapshot_path) = &snapshot {
+                                    println!("{}", save_snapshot(&tree, snapshot_path));
+                                }
+                                if watch {
+                                    watch_events = start_watching(&tree, &scan_options);
+                                }
+                                let current_node = tree.root();
                                 state = BackendState::Initialized {
-                                    kernel: kernel.clone(),
-                                    current_node: fs_root,
+                                    kernel: Arc::new(Mutex::new(Kernel::new(tree, action_file, dry, trash.clone()))),
+                                    current_node,
                                 };
-                                println!("Directory loaded: {}", path);
                             }
                             None => {
-                                println!("{}", "Failed to load directory.");
+                                println!("Failed to load directory.");
                             }
                         }
                     }
@@ -373,10 +957,13 @@ async fn lone_run_backend(dry: bool, action_file: bool) {
                         let updated_node = sync_handle_command(
                             command,
                             kernel.clone(),
-                            current_node.clone(),
+                            *current_node,
+                            scanner.clone(),
+                            &scan_options,
                         )
                         .await;
-                        *current_node = updated_node;
+                        let kernel = kernel.lock().unwrap();
+                        *current_node = kernel.tree().nearest_existing(updated_node);
                     }
                 }
             }
@@ -386,77 +973,75 @@ async fn lone_run_backend(dry: bool, action_file: bool) {
 async fn sync_handle_command(
     command: Command,
     kernel: Arc<Mutex<Kernel>>,
-    current_node: Arc<Mutex<FileSystemNode>>,
-) -> Arc<Mutex<FileSystemNode>> {
+    current_node: NodeId,
+    scanner: Arc<dyn Scanner>,
+    scan_options: &ScanOptions,
+) -> NodeId {
     println!("Handling command sync");
 
     match command {
-        Command::Del(index) => {
-            {
-            let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.mark_for_deletion(current_node.clone(), index);
+        Command::Del(target) => {
+            let marked = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                mark_selection(&mut kernel_guard, current_node, &target)
+            };
+            match marked {
+                Ok(report) => println!("{}", report),
+                Err(err) => println!("{}", err),
             }
-            println!("Marked index {} for deletion.", index);
         }
         Command::Create(path, is_file) => {
-            {
-            let mut kernel_guard = kernel.lock().unwrap();
-            
-            kernel_guard.create(current_node.clone(), path.clone(), is_file);
+            let created = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.create(current_node, path.clone(), is_file)
+            };
+            match created {
+                Ok(()) => println!("Created {}.", path),
+                Err(err) => println!("{}", err),
             }
-            println!("Created {}.", path);
         }
         Command::Move(original_path, new_path) => {
-            {
+            let moved = {
                 let mut kernel_guard = kernel.lock().unwrap();
             
                 // Get absolute paths
-                let abs_original_path = if original_path.starts_with('/') {
-                    original_path.to_string()
-                } else {
-                    let mut abs_path = current_node.lock().unwrap().get_path().to_string_lossy().to_string();
-                    abs_path.push('/');
-                    abs_path.push_str(&original_path);
-                    abs_path
-                };
-            
-                let abs_new_path = if new_path.starts_with('/') {
-                    new_path.to_string()
-                } else {
-                    let mut abs_path = current_node.lock().unwrap().get_path().to_string_lossy().to_string();
-                    abs_path.push('/');
-                    abs_path.push_str(&new_path);
-                    abs_path
-                };
+                let abs_original_paths = selected_paths(&kernel_guard, current_node, &original_path);
+                let abs_new_path = absolute_path(&kernel_guard, current_node, &new_path);
             
                 // Perform the move
-                kernel_guard.move_item(abs_original_path.clone(), abs_new_path.clone());
-            }
+                abs_original_paths.map(|paths| {
+                    paths
+                        .into_iter()
+                        .map(|abs_original_path| kernel_guard.move_item(abs_original_path, abs_new_path.clone()))
+                        .collect::<Vec<_>>()
+                })
+            };
             
-            println!("Moved {} to {}.", original_path, new_path);
+            match moved.map(|results| move_report(&original_path, &new_path, results)) {
+                Ok(Ok(report)) | Ok(Err(report)) => println!("{}", report),
+                Err(err) => println!("{}", err),
+            }
         }
         
         Command::Undo(index) => {
-            {let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.undo_deletion(index);}
-            println!("Undid deletion of index {}.", index);
+            match kernel.lock().unwrap().undo_deletion(index) {
+                Ok(report) | Err(report) => println!("{}", report),
+            }
         }
-        Command::Display => {
+        Command::Display(options) => {
             let display = {
-                let kernel_guard = kernel.lock().unwrap();
-                kernel_guard.display(current_node.clone())
+                let mut kernel_guard = kernel.lock().unwrap();
+                let display = kernel_guard.display(current_node, &options);
+                kernel_guard.set_display_options(options);
+                display
             };
             println!("{}", display);
         }
         Command::Up => {
             let (response, new_node) = {
                 let kernel_guard = kernel.lock().unwrap(); // Lock the kernel
-                if let Some(parent) = kernel_guard.get_parent(current_node.clone()) {
-                    if let Some(upgraded) = parent.upgrade() {
-                        ("Moved up to parent directory.".to_string(), Some(upgraded)) // Success case
-                    } else {
-                        ("Error upgrading parent reference.".to_string(), None) // Failed to upgrade
-                    }
+                if let Some(parent) = kernel_guard.get_parent(current_node) {
+                    ("Moved up to parent directory.".to_string(), Some(parent)) // Success case
                 } else {
                     ("Already at the root directory.".to_string(), None) // No parent exists
                 }
@@ -470,13 +1055,13 @@ async fn sync_handle_command(
             }
         
         }
-        Command::Down(index) => {
+        Command::Down(target) => {
             let (response, child) = {
                 let kernel_guard = kernel.lock().unwrap();
-                if let Some(child_node) = kernel_guard.get_child(current_node.clone(), index) {
-                    ("Moved down to child directory.".to_string(), Some(child_node))
+                if let Some(child_node) = kernel_guard.get_directory(current_node, &target) {
+                    (format!("Moved to {}.", kernel_guard.tree().get_path(child_node).to_string_lossy()), Some(child_node))
                 } else {
-                    ("Invalid child index.".to_string(), None)
+                    (format!("Cannot move to {}.", target), None)
                 }
             };
 
@@ -487,10 +1072,34 @@ async fn sync_handle_command(
                 println!("{}", response);
             }
         }
-        Command::Commit => {
-            {let mut kernel_guard = kernel.lock().unwrap();
-            kernel_guard.commit_actions();}
-            println!("Committed all actions.");
+        Command::Commit(force) => {
+            match kernel.lock().unwrap().commit_actions(force) {
+                Ok(report) => println!("{}", report),
+                Err(report) => eprintln!("{}", report),
+            }
+        }
+        Command::Check => {
+            match kernel.lock().unwrap().check() {
+                Ok(report) => println!("{}", report),
+                Err(report) => eprintln!("{}", report),
+            }
+        }
+        Command::Restore(target) => {
+            let mut kernel_guard = kernel.lock().unwrap();
+            let result = match target {
+                Some(target) => kernel_guard.restore(current_node, &target, scanner.as_ref(), scan_options),
+                None => kernel_guard.trashed(),
+            };
+            match result {
+                Ok(report) => println!("{}", report),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+        Command::Purge(older_than) => {
+            match kernel.lock().unwrap().purge(older_than) {
+                Ok(report) => println!("{}", report),
+                Err(err) => eprintln!("{}", err),
+            }
         }
         Command::Status => {
             let status = {
@@ -502,8 +1111,8 @@ async fn sync_handle_command(
         Command::GoTo(path) => {
             let (response, node) = {
                 let kernel_guard = kernel.lock().unwrap();
-                if let Some(node) = kernel_guard.go_to(path.clone()) {
-                    (format!("Navigated to {}.", path), Some(node))
+                if let Some(node) = kernel_guard.get_directory(current_node, &path) {
+                    (format!("Navigated to {}.", kernel_guard.tree().get_path(node).to_string_lossy()), Some(node))
                 } else {
                     (format!("Invalid path: {}.", path), None)
                 }
@@ -519,38 +1128,118 @@ async fn sync_handle_command(
         }
         Command::Find(name) => {
             let kernel_guard = kernel.lock().unwrap();
-            if let Some(index) = kernel_guard.get_index(current_node.clone(), name) {
-                println!("{}", index);
+            if let Some(node) = kernel_guard.resolve_path(current_node, &name) {
+                println!("{}", node);
+            } else {
+                println!("No such item name: {}.", name);
             }
         }
-        Command::Open(index) => {
-            {let kernel_guard = kernel.lock().unwrap();
-            kernel_guard.open_file(current_node.clone(), index);}
-            println!("Opened file at index {}.", index);
+        Command::Open(target) => {
+            let opened = {
+                let kernel_guard = kernel.lock().unwrap();
+                kernel_guard.resolve(current_node, &target).map(|node| kernel_guard.open_file(node))
+            };
+            match opened {
+                Some(()) => println!("Opened {}.", target),
+                None => println!("No such item: {}.", target),
+            }
+        }
+        Command::SizeMode(size_mode) => {
+            {kernel.lock().unwrap().set_size_mode(size_mode);}
+            println!("Showing {} sizes.", size_mode);
+        }
+        Command::Errors => {
+            let errors = {
+                let kernel_guard = kernel.lock().unwrap();
+                kernel_guard.scan_errors(current_node)
+            };
+            println!("{}", errors);
+        }
+        Command::Stats => {
+            let stats = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.stats(current_node)
+            };
+            println!("{}", stats);
+        }
+        Command::Search(query) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.search(current_node, &query)
+            };
+            println!("{}", results);
+        }
+        Command::Select(query) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                kernel_guard.select(current_node, &query)
+            };
+            match results {
+                Ok(response) => println!("{}", response),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Command::RunQuery(name) => {
+            let results = {
+                let mut kernel_guard = kernel.lock().unwrap();
+                run_saved_query(&mut kernel_guard, current_node, &name)
+            };
+            match results {
+                Ok(response) => println!("{}", response),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Command::SavedQueries => match edit_saved_queries(None) {
+            Ok(response) => println!("{}", response),
+            Err(err) => println!("{}", err),
+        },
+        Command::SaveQuery(name, query) => match edit_saved_queries(Some((&name, Some(&query)))) {
+            Ok(response) => println!("{}", response),
+            Err(err) => println!("{}", err),
+        },
+        Command::RemoveQuery(name) => match edit_saved_queries(Some((&name, None))) {
+            Ok(response) => println!("{}", response),
+            Err(err) => println!("{}", err),
+        },
+        Command::Rescan(path) => {
+            match rescan(kernel.clone(), current_node, path, scanner, scan_options).await {
+                Ok(response) => println!("{}", response),
+                Err(err) => println!("{}", err),
+            }
         }
         Command::Help => {
             let help_message = r#"
         Available Commands:
         1. `..` - Moves up one level.
-        2. `<index>` - Moves down to the child at the specified index.
-        3. `go to <path>` - Navigates to the specified path.
-        4. `commit` - Commits the current state.
-        5. `undo <index>` - Reverts to a specific commit index.
+        2. `<id>` - Moves into the directory with the ID `display` lists for it.
+        3. `go to <target>` - Navigates to a directory by ID, name, or absolute or relative path.
+        4. `commit [--force]` - Applies every queued action to disk, or none of them if one fails; `--force` also commits items that changed on disk since the scan.
+        5. `undo <index>` - Takes the queued action at that index out of the queue and out of the model.
         6. `status` - Displays the current status.
-        7. `display` - Displays content or structure at the current level.
+        7. `display [options]` - Lists the current level, e.g. `display --sort size --desc --top 20 --min 100M`.
         8. `create file <name>` - Creates a file with the specified name.
         9. `create folder <name>` - Creates a folder with the specified name.
-        10. `del <index>` - Deletes the item at the specified index.
-        11. `open <index>` - Opens the item at the specified index.
-        12. `move <source> > <destination>` - Moves an item from source to destination.
-        13. `help` - Displays this help message.
+        10. `del <target>` - Marks an item (ID, name or path), or search results such as `#all` or `#2-5`, for deletion.
+        11. `open <target>` - Opens an item (ID, name or path).
+        12. `move <source> > <destination>` - Moves an item, or search results such as `#all`, into a directory; both may be IDs, names or paths.
+        13. `size <apparent|allocated>` - Switches between logical size and size on disk.
+        14. `errors` - Lists paths below the current directory that could not be read.
+        15. `rescan [path]` - Scans a directory again (the current one by default) to pick up outside changes.
+        16. `stats` - Shows counts, depth, newest and oldest entries and the size by extension below the current directory.
+        17. `search <name> [options]` - Finds matching items anywhere below the current directory, e.g. `search *.mov --min 1G --sort size`; `#N` then names the Nth result.
+        18. `select where <condition> [under <path>] [order by <field> [asc|desc]] [limit N]` - Lists what a query picks, e.g. `select where ext in (mp4,mov) and size > 1G and mtime < 2024-01-01 under ~/Movies order by size desc`.
+        19. `query [name]` - Lists the saved queries, or runs one; `query save <name> select ...` and `query remove <name>` edit them.
+        20. `check` - Lists every queued action that would stop a commit, such as a missing source, an existing destination or an item changed since the scan.
+        21. `restore [number|path]` - Lists the trash, or puts back an item it lists, or what was deleted from a path.
+        22. `purge --older-than <age>` - Empties the trash of what was deleted at least that long ago, e.g. `purge --older-than 30d`; `purge --all` empties it.
+        23. `help` - Displays this help message.
         "#;
-            println!("{}", help_message.to_string());
+            println!("{}", help_message);
         }
         Command::AISuggestion(input) => {
             let context = {
                 let kernel_guard = kernel.lock().unwrap();
-                kernel_guard.display(current_node.clone())
+                kernel_guard.display(current_node, &DisplayOptions::default())
             };
             let response = ai::ask(input, context).await;
             
@@ -567,13 +1256,16 @@ async fn sync_handle_command(
 
         }
         Command::AIConfirm => {
-            {kernel.lock().unwrap().convert_suggestions(current_node.clone());}
+            {kernel.lock().unwrap().convert_suggestions(current_node);}
 
             println!("Applied suggestions (Still needs to be committed)");
         }
         Command::Exit => {
             println!("Exiting backend.");
         }
+        Command::Error(message) => {
+            println!("{}", message);
+        }
         _ => {
             println!("Unknown command.");
         }
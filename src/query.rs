@@ -0,0 +1,690 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::exclude::glob_to_regex;
+use crate::listing::{self, DisplayOptions, SortKey};
+use crate::system::{FileSystemNode, FileSystemTree, NodeId, NodeKind};
+
+/// A parsed `select`, for example
+/// `select where ext in (mp4,mov) and size > 1G and mtime < 2024-01-01 under ~/Movies order by size desc`.
+///
+/// Conditions combine with `and`, `or`, `not` and parentheses. The fields are `name`, `path` and
+/// `ext`, compared with `=`, `!=`, `in (...)`, `contains`, `like` (a glob) or `matches` (a regular
+/// expression); `type`, compared with `=`, `!=` or `in` against `file`, `dir` or `symlink`; and
+/// `size`, `items`, `mtime`, `atime`, `ctime` and `age`, compared with `<`, `<=`, `>`, `>=`, `=` or
+/// `!=`. Sizes take the units `display --min` does, times are dates like `2024-01-01` or
+/// `2024-01-01T18:30` in local time, and `age` is the time since the last change, like `30d`.
+#[derive(Debug, Clone)]
+pub struct Query {
+    condition: Option<Condition>,
+    under: Option<String>,
+    options: DisplayOptions,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    Text(TextField, TextTest),
+    Number(NumberField, Comparison, i64),
+    Kind(Vec<NodeKind>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TextField {
+    Name,
+    Path,
+    Extension,
+}
+
+#[derive(Debug, Clone)]
+enum TextTest {
+    /// Equal to one of these, ignoring case; held in lowercase
+    OneOf(Vec<String>),
+    /// Held in lowercase
+    Contains(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NumberField {
+    Size,
+    Items,
+    Modified,
+    Accessed,
+    Changed,
+    Age,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn holds(self, left: i64, right: i64) -> bool {
+        match self {
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+        }
+    }
+}
+
+// What a node is checked against, worked out once per query
+struct Subject<'a> {
+    tree: &'a FileSystemTree,
+    size: &'a dyn Fn(&FileSystemNode) -> u64,
+    now: i64,
+}
+
+impl Condition {
+    fn holds(&self, subject: &Subject, id: NodeId) -> bool {
+        let node = subject.tree.node(id);
+        match self {
+            Condition::All(conditions) => conditions.iter().all(|condition| condition.holds(subject, id)),
+            Condition::Any(conditions) => conditions.iter().any(|condition| condition.holds(subject, id)),
+            Condition::Not(condition) => !condition.holds(subject, id),
+            Condition::Text(field, test) => {
+                let text = match field {
+                    TextField::Name => subject.tree.get_name(id).to_string(),
+                    TextField::Path => subject.tree.get_path(id).to_string_lossy().into_owned(),
+                    TextField::Extension => listing::extension(subject.tree.get_name(id)).unwrap_or_default(),
+                };
+                match test {
+                    TextTest::OneOf(values) => values.contains(&text.to_lowercase()),
+                    TextTest::Contains(value) => text.to_lowercase().contains(value.as_str()),
+                    TextTest::Regex(regex) => regex.is_match(&text),
+                }
+            }
+            Condition::Number(field, comparison, value) => {
+                let actual = match field {
+                    NumberField::Size => (subject.size)(node) as i64,
+                    NumberField::Items => node.children_len() as i64,
+                    NumberField::Modified => node.modified(),
+                    NumberField::Accessed => node.accessed(),
+                    NumberField::Changed => node.changed(),
+                    NumberField::Age => subject.now - node.modified(),
+                };
+                comparison.holds(actual, *value)
+            }
+            Condition::Kind(kinds) => kinds.contains(&node.kind()),
+        }
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        if !parser.eat_keyword("select") {
+            return Err("A query starts with `select`".to_string());
+        }
+
+        let mut query = Query { condition: None, under: None, options: DisplayOptions::default() };
+        while let Some(token) = parser.next() {
+            match keyword(&token).as_deref() {
+                Some("where") if query.condition.is_none() => query.condition = Some(parser.condition()?),
+                Some("under") if query.under.is_none() => query.under = Some(parser.value("a path after `under`")?),
+                Some("order") if query.options.sort.is_none() => {
+                    if !parser.eat_keyword("by") {
+                        return Err("Expected `by` after `order`".to_string());
+                    }
+                    let field = parser.value("a field after `order by`")?;
+                    let key = SortKey::parse(&field.to_lowercase())
+                        .ok_or_else(|| format!("Cannot order by '{}', use name, size, mtime, type or items", field))?;
+                    query.options.sort = Some(key);
+                    query.options.descending = if parser.eat_keyword("desc") {
+                        true
+                    } else if parser.eat_keyword("asc") {
+                        false
+                    } else {
+                        key.descending_by_default()
+                    };
+                }
+                Some("limit") if query.options.top.is_none() => {
+                    let count = parser.value("a count after `limit`")?;
+                    query.options.top = Some(count.parse().map_err(|_| format!("Invalid limit '{}'", count))?);
+                }
+                _ => return Err(format!("Unexpected '{}'", token.text())),
+            }
+        }
+        Ok(query)
+    }
+
+    /// The directory named after `under`, if the query has one, as written.
+    pub fn under(&self) -> Option<&str> {
+        self.under.as_deref()
+    }
+
+    /// The entries below `node` the query picks, in its order. Entries queued for deletion are left
+    /// out along with everything below them.
+    pub fn run(&self, tree: &FileSystemTree, node: NodeId, size: impl Fn(&FileSystemNode) -> u64) -> Vec<NodeId> {
+        let subject = Subject { tree, size: &size, now: listing::now() };
+        let mut found = tree.find_named(node, |_| true);
+        if let Some(condition) = &self.condition {
+            found.retain(|&id| condition.holds(&subject, id));
+        }
+        listing::arrange(tree, found, &self.options, &size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(text) | Token::Quoted(text) => text,
+            Token::Symbol(symbol) => symbol,
+        }
+    }
+}
+
+// Words are matched against keywords without regard to case; quoted text never is a keyword
+fn keyword(token: &Token) -> Option<String> {
+    match token {
+        Token::Word(word) => Some(word.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    const SYMBOLS: [&str; 9] = ["<=", ">=", "!=", "<", ">", "=", "(", ")", ","];
+
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            if !word.is_empty() {
+                tokens.push(Token::Word(mem::take(&mut word)));
+            }
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if c == '"' || c == '\'' {
+            if !word.is_empty() {
+                tokens.push(Token::Word(mem::take(&mut word)));
+            }
+            let end = rest[1..].find(c).ok_or_else(|| format!("Unclosed quote in '{}'", rest))?;
+            tokens.push(Token::Quoted(rest[1..=end].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            if c.is_whitespace() {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(mem::take(&mut word)));
+                }
+            } else {
+                word.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn eat_keyword(&mut self, expected: &str) -> bool {
+        let found = self.peek().and_then(keyword).is_some_and(|word| word == expected);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn eat_symbol(&mut self, expected: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(symbol)) if *symbol == expected);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn value(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(text)) | Some(Token::Quoted(text)) => Ok(text),
+            Some(Token::Symbol(symbol)) => Err(format!("Expected {}, found '{}'", what, symbol)),
+            None => Err(format!("Expected {}", what)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let mut any = vec![self.all()?];
+        while self.eat_keyword("or") {
+            any.push(self.all()?);
+        }
+        Ok(if any.len() == 1 { any.remove(0) } else { Condition::Any(any) })
+    }
+
+    fn all(&mut self) -> Result<Condition, String> {
+        let mut all = vec![self.negation()?];
+        while self.eat_keyword("and") {
+            all.push(self.negation()?);
+        }
+        Ok(if all.len() == 1 { all.remove(0) } else { Condition::All(all) })
+    }
+
+    fn negation(&mut self) -> Result<Condition, String> {
+        if self.eat_keyword("not") {
+            return Ok(Condition::Not(Box::new(self.negation()?)));
+        }
+        if self.eat_symbol("(") {
+            let condition = self.condition()?;
+            if !self.eat_symbol(")") {
+                return Err("Expected ')'".to_string());
+            }
+            return Ok(condition);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let field = self.value("a field")?;
+        let operator = match self.next() {
+            Some(Token::Symbol(symbol)) => symbol.to_string(),
+            Some(Token::Word(word)) => word.to_lowercase(),
+            _ => return Err(format!("Expected a comparison after '{}'", field)),
+        };
+        let values = if operator == "in" { self.list()? } else { vec![self.value(&format!("a value after '{}'", operator))?] };
+
+        let condition = match field.to_lowercase().as_str() {
+            "name" => text_condition(TextField::Name, &operator, values)?,
+            "path" => text_condition(TextField::Path, &operator, values)?,
+            "ext" | "extension" => {
+                let values = values.iter().map(|value| value.trim_start_matches('.').to_string()).collect();
+                text_condition(TextField::Extension, &operator, values)?
+            }
+            "type" => {
+                let kinds = values.iter().map(|value| listing::parse_kind(value)).collect::<Result<Vec<_>, _>>()?;
+                match operator.as_str() {
+                    "=" | "in" => Condition::Kind(kinds),
+                    "!=" => Condition::Not(Box::new(Condition::Kind(kinds))),
+                    _ => return Err(format!("`type` cannot be compared with '{}'", operator)),
+                }
+            }
+            "size" => number_condition(NumberField::Size, &operator, &values[0], listing::parse_size)?,
+            "items" => number_condition(NumberField::Items, &operator, &values[0], |value| {
+                value.parse::<u64>().map_err(|_| format!("Invalid count '{}'", value))
+            })?,
+            "mtime" | "modified" => number_condition(NumberField::Modified, &operator, &values[0], parse_date)?,
+            "atime" | "accessed" => number_condition(NumberField::Accessed, &operator, &values[0], parse_date)?,
+            "ctime" | "changed" => number_condition(NumberField::Changed, &operator, &values[0], parse_date)?,
+            "age" => number_condition(NumberField::Age, &operator, &values[0], listing::parse_age)?,
+            _ => return Err(format!("Unknown field '{}'", field)),
+        };
+        Ok(condition)
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        if !self.eat_symbol("(") {
+            return Err("Expected '(' after `in`".to_string());
+        }
+        let mut values = vec![self.value("a value in the list")?];
+        while self.eat_symbol(",") {
+            values.push(self.value("a value in the list")?);
+        }
+        if !self.eat_symbol(")") {
+            return Err("Expected ')' to close the list".to_string());
+        }
+        Ok(values)
+    }
+}
+
+fn text_condition(field: TextField, operator: &str, values: Vec<String>) -> Result<Condition, String> {
+    let pattern = |values: &[String]| values[0].clone();
+    let test = match operator {
+        "=" | "in" => TextTest::OneOf(values.iter().map(|value| value.to_lowercase()).collect()),
+        "!=" => return Ok(Condition::Not(Box::new(text_condition(field, "=", values)?))),
+        "contains" => TextTest::Contains(pattern(&values).to_lowercase()),
+        "like" => TextTest::Regex(
+            RegexBuilder::new(&format!("^{}$", glob_to_regex(&pattern(&values))))
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| format!("Invalid pattern '{}': {}", pattern(&values), err))?,
+        ),
+        "matches" => TextTest::Regex(Regex::new(&pattern(&values)).map_err(|err| format!("Invalid pattern '{}': {}", pattern(&values), err))?),
+        _ => return Err(format!("Text cannot be compared with '{}', use =, !=, in, contains, like or matches", operator)),
+    };
+    Ok(Condition::Text(field, test))
+}
+
+fn number_condition<T: TryInto<i64>>(
+    field: NumberField,
+    operator: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Condition, String> {
+    let comparison = match operator {
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        "=" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        _ => return Err(format!("Numbers and times cannot be compared with '{}', use <, <=, >, >=, = or !=", operator)),
+    };
+    let value = parse(value)?.try_into().map_err(|_| format!("'{}' is too large", value))?;
+    Ok(Condition::Number(field, comparison, value))
+}
+
+/// Reads `2024-01-01` or `2024-01-01T18:30`, in local time, into seconds since the Unix epoch.
+pub fn parse_date(text: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid date '{}', use YYYY-MM-DD or YYYY-MM-DDTHH:MM", text);
+    let (date, time) = text.split_once('T').unwrap_or((text, "00:00"));
+    let numbers = |part: &str, separator: char| -> Result<Vec<i32>, String> {
+        part.split(separator).map(|number| number.parse().map_err(|_| invalid())).collect()
+    };
+    let (date, time) = (numbers(date, '-')?, numbers(time, ':')?);
+    let (&[year, month, day], &[hour, minute, ..]) = (date.as_slice(), time.as_slice()) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+        return Err(invalid());
+    }
+
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    tm.tm_year = year - 1900;
+    tm.tm_mon = month - 1;
+    tm.tm_mday = day;
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = time.get(2).copied().unwrap_or(0);
+    // Let the C library work out whether daylight saving time applies
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => Err(invalid()),
+        time => Ok(time),
+    }
+}
+
+/// Queries kept under a name in `~/.config/fsm/queries`, one `name = select ...` per line. A `=`
+/// or `\` in a name, or a `#` it starts with, is written with a `\` in front.
+#[derive(Debug, Default)]
+pub struct SavedQueries {
+    path: PathBuf,
+    queries: Vec<(String, String)>,
+}
+
+impl SavedQueries {
+    /// `$XDG_CONFIG_HOME/fsm/queries`, falling back on `~/.config/fsm/queries`.
+    pub fn default_path() -> PathBuf {
+        let config = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"));
+        config.join("fsm").join("queries")
+    }
+
+    /// Reads the saved queries; a missing file has none. Lines that are blank or start with `#`
+    /// are skipped.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut saved = SavedQueries { path, queries: Vec::new() };
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            match split_saved(line) {
+                Some((name, query)) => saved.set(name.trim(), query.trim()),
+                None => eprintln!("{}: expected `name = select ...`, found '{}'", saved.path.display(), line),
+            }
+        }
+        Ok(saved)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.queries.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.queries.iter().map(|(name, query)| (name.as_str(), query.as_str()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.queries.iter().find(|(saved, _)| saved == name).map(|(_, query)| query.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, query: &str) {
+        match self.queries.iter_mut().find(|(saved, _)| saved == name) {
+            Some((_, saved)) => *saved = query.to_string(),
+            None => self.queries.push((name.to_string(), query.to_string())),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.queries.len();
+        self.queries.retain(|(saved, _)| saved != name);
+        self.queries.len() != before
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents: String = self.queries.iter().map(|(name, query)| format!("{} = {}\n", escape_name(name), query)).collect();
+        fs::write(&self.path, contents)
+    }
+}
+
+fn escape_name(name: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c == '=' || c == '\\' || (i == 0 && c == '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Splits a line at the first `=` that is not escaped, undoing the escapes in the name
+fn split_saved(line: &str) -> Option<(String, &str)> {
+    let mut name = String::new();
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => name.push(chars.next().map_or('\\', |(_, escaped)| escaped)),
+            '=' => return Some((name, &line[i + 1..])),
+            c => name.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{MemoryScanner, ScanEntry, ScanOptions, ScanProgress};
+    use crate::system::{build_fs_model_blocking, FileMetadata};
+
+    fn file(path: &str, size: u64, modified: &str) -> ScanEntry {
+        let metadata = FileMetadata { modified: parse_date(modified).unwrap(), ..FileMetadata::default() };
+        ScanEntry::new(format!("/memory/{}", path), size, true).with_metadata(metadata)
+    }
+
+    fn tree() -> FileSystemTree {
+        let entries = vec![
+            ScanEntry::new("/memory/Movies".to_string(), 0, false),
+            file("Movies/big.mp4", 2 << 30, "2023-06-01"),
+            file("Movies/clip.MOV", 3 << 30, "2023-02-01"),
+            file("Movies/new.mp4", 4 << 30, "2024-03-01"),
+            file("Movies/small.mov", 1 << 10, "2023-06-01"),
+            file("Movies/notes.txt", 5 << 30, "2023-06-01"),
+            file("my file.txt", 10, "2023-06-01"),
+            file("and", 20, "2023-06-01"),
+        ];
+        build_fs_model_blocking("/memory".to_string(), &MemoryScanner::new(entries), ScanOptions::default(), &ScanProgress::new()).unwrap()
+    }
+
+    // Names of what `text` picks below the root, in its order
+    fn run(tree: &FileSystemTree, text: &str) -> Vec<String> {
+        let query = Query::parse(text).unwrap();
+        let found = query.run(tree, tree.root(), |node| node.size());
+        found.into_iter().map(|id| tree.get_name(id).to_string()).collect()
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn the_example_query() {
+        let tree = tree();
+        let text = "select where ext in (mp4,mov) and size > 1G and mtime < 2024-01-01 under ~/Movies order by size desc";
+        assert_eq!(Query::parse(text).unwrap().under(), Some("~/Movies"));
+        assert_eq!(run(&tree, text), ["clip.MOV", "big.mp4"]);
+        assert_eq!(run(&tree, "SELECT WHERE ext IN (.mp4, .mov) AND size > 1G ORDER BY size ASC"), ["big.mp4", "clip.MOV", "new.mp4"]);
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_tighter_than_and() {
+        let tree = tree();
+        // name = and or (ext = mov and size > 1G)
+        assert_eq!(sorted(run(&tree, "select where name = and or ext = mov and size > 1G")), ["and", "clip.MOV"]);
+        assert_eq!(run(&tree, "select where (name = and or ext = mov) and size > 1G"), ["clip.MOV"]);
+        // (not ext = txt) and type = file
+        assert_eq!(sorted(run(&tree, "select where not ext = txt and type = file and size < 1K")), ["and"]);
+        assert_eq!(sorted(run(&tree, "select where not (ext = txt or type = dir) and size <= 1K")), ["and", "small.mov"]);
+        assert_eq!(run(&tree, "select where not not name = big.mp4"), ["big.mp4"]);
+    }
+
+    #[test]
+    fn quoted_values_keep_spaces_and_are_never_keywords() {
+        let tree = tree();
+        assert_eq!(run(&tree, r#"select where name = "my file.txt""#), ["my file.txt"]);
+        assert_eq!(run(&tree, "select where name = 'and' and size = 20"), ["and"]);
+        assert_eq!(run(&tree, r#"select where path contains "movies/big""#), ["big.mp4"]);
+        assert_eq!(sorted(run(&tree, "select where name like '*.mp4'")), ["big.mp4", "new.mp4"]);
+        assert_eq!(run(&tree, "select where name matches '^c.*V$'"), ["clip.MOV"]);
+        assert!(Query::parse(r#"select where name = "unclosed"#).unwrap_err().contains("Unclosed quote"));
+    }
+
+    #[test]
+    fn size_and_date_literals() {
+        let tree = tree();
+        assert_eq!(run(&tree, "select where size = 1K"), ["small.mov"]);
+        assert_eq!(run(&tree, "select where size = 1KiB"), ["small.mov"]);
+        assert_eq!(run(&tree, "select where type = file and size >= 4.5GB"), ["notes.txt"]);
+        assert_eq!(run(&tree, "select where type = file and mtime < 2023-03-01"), ["clip.MOV"]);
+        assert_eq!(run(&tree, "select where type = file and mtime >= 2024-03-01T00:00"), ["new.mp4"]);
+
+        let midnight = parse_date("2024-01-01").unwrap();
+        assert_eq!(parse_date("2024-01-01T18:30").unwrap() - midnight, 18 * 3600 + 30 * 60);
+        assert_eq!(parse_date("2024-01-01T00:00:30").unwrap() - midnight, 30);
+        for invalid in ["2024-13-01", "2024-01-32", "2024-01", "2024-01-01T24:00", "yesterday"] {
+            assert!(parse_date(invalid).is_err(), "{} parsed", invalid);
+        }
+        assert!(Query::parse("select where size > 1X").unwrap_err().contains("Invalid size"));
+        assert!(Query::parse("select where mtime < 2024/01/01").unwrap_err().contains("Invalid date"));
+        assert!(Query::parse("select where age > 30x").is_err());
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        let errors = [
+            ("where size > 1G", "starts with `select`"),
+            ("select where", "Expected a field"),
+            ("select where owner = me", "Unknown field 'owner'"),
+            ("select where size like 1G", "cannot be compared with 'like'"),
+            ("select where name > a", "Text cannot be compared with '>'"),
+            ("select where type > file", "`type` cannot be compared"),
+            ("select where type = pipe", "Unknown type 'pipe'"),
+            ("select where (name = a", "Expected ')'"),
+            ("select where ext in mp4", "Expected '(' after `in`"),
+            ("select where ext in (mp4, mov", "Expected ')' to close the list"),
+            ("select where name =", "Expected a value after '='"),
+            ("select where name matches '('", "Invalid pattern"),
+            ("select order size", "Expected `by` after `order`"),
+            ("select order by owner", "Cannot order by 'owner'"),
+            ("select limit many", "Invalid limit 'many'"),
+            ("select where name = a where name = b", "Unexpected 'where'"),
+            ("select under", "Expected a path after `under`"),
+        ];
+        for (text, message) in errors {
+            let err = Query::parse(text).expect_err(text);
+            assert!(err.contains(message), "{}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn saved_queries_round_trip_through_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fsm").join("queries");
+        let mut saved = SavedQueries::load(path.clone()).unwrap();
+        assert_eq!(saved.names().count(), 0);
+
+        let queries = [
+            ("movies", "select where ext in (mp4,mov) and size > 1G under ~/Movies order by size desc"),
+            ("a=b", "select where name = 'x=y'"),
+            ("=", "select where size = 0"),
+            (r"back\slash", "select limit 3"),
+            ("#tag", "select where type = dir"),
+        ];
+        for (name, query) in queries {
+            saved.set(name, query);
+        }
+        saved.save().unwrap();
+
+        let loaded = SavedQueries::load(path.clone()).unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), queries);
+
+        let mut loaded = loaded;
+        assert!(loaded.remove("a=b"));
+        assert!(!loaded.remove("a=b"));
+        loaded.set("movies", "select limit 1");
+        loaded.save().unwrap();
+        let reloaded = SavedQueries::load(path).unwrap();
+        assert_eq!(reloaded.get("movies"), Some("select limit 1"));
+        assert_eq!(reloaded.get("a=b"), None);
+        assert_eq!(reloaded.names().count(), 4);
+    }
+
+    #[test]
+    fn saved_queries_file_edited_by_hand() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queries");
+        fs::write(&path, "# my queries\n\n  big = select where size > 1G  \nnot a query\nold=select where age > 1y\n").unwrap();
+        let saved = SavedQueries::load(path).unwrap();
+        assert_eq!(saved.iter().collect::<Vec<_>>(), [("big", "select where size > 1G"), ("old", "select where age > 1y")]);
+    }
+}
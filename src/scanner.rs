@@ -0,0 +1,674 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::collections::BTreeMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use rayon::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use walkdir::WalkDir;
+
+use crate::exclude::{relative_to, ExcludeSet};
+use crate::system::{FileMetadata, SymlinkInfo};
+
+#[cfg(target_os = "macos")]
+use crate::mac;
+#[cfg(target_os = "linux")]
+use crate::linux;
+
+/// A single entry found by a scanner, addressed by its absolute path.
+#[derive(Debug, Clone)]
+pub struct ScanEntry {
+    pub path: String,
+    /// Logical length in bytes
+    pub size: u64,
+    /// Bytes actually reserved on disk, which is what deleting the entry frees
+    pub allocated_size: u64,
+    pub is_file: bool,
+    pub metadata: FileMetadata,
+    pub symlink: Option<SymlinkInfo>,
+}
+
+impl ScanEntry {
+    pub fn new(path: String, size: u64, is_file: bool) -> Self {
+        Self { path, size, allocated_size: size, is_file, metadata: FileMetadata::default(), symlink: None }
+    }
+
+    pub fn with_symlink(mut self, symlink: SymlinkInfo) -> Self {
+        self.symlink = Some(symlink);
+        self
+    }
+
+    pub fn with_allocated_size(mut self, allocated_size: u64) -> Self {
+        self.allocated_size = allocated_size;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: FileMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// Knobs shared by every scanner.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Descend into directories reached through symbolic links. Each (device, inode) is still
+    /// only entered once, which is what keeps link loops from recursing forever.
+    pub follow_symlinks: bool,
+    /// Entries matching these patterns are left out, and excluded directories are never opened
+    pub exclude: ExcludeSet,
+    /// Stop at mount points instead of descending into other filesystems
+    pub one_file_system: bool,
+}
+
+/// Entries a scan found directly inside one directory.
+#[derive(Debug, Default)]
+pub struct DirectoryListing {
+    pub entries: Vec<ScanEntry>,
+    /// Paths left out by the exclude patterns; their contents were not looked at
+    pub excluded: Vec<String>,
+    /// Paths that could not be read, so whatever is below them is missing from `entries`
+    pub errors: Vec<ScanError>,
+}
+
+/// Receives a scan while it runs, one directory at a time, from whichever thread listed it.
+pub trait ScanSink: Sync {
+    /// Takes entries found directly in `directory`, which is the scan root or a directory
+    /// already handed over as an entry. A directory may arrive in several batches.
+    fn add(&self, directory: &str, listing: DirectoryListing);
+
+    /// Nothing more will be added to `directory`, because it was listed or will not be entered.
+    fn finish(&self, directory: &str);
+
+    /// A directory listed in one go.
+    fn directory(&self, directory: &str, listing: DirectoryListing) {
+        self.add(directory, listing);
+        self.finish(directory);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanErrorKind {
+    /// The directory could not be opened
+    Open,
+    /// The directory was opened but listing it failed part way
+    Read,
+    /// The entry was listed but its metadata could not be fetched
+    Stat,
+    /// The kernel returned a record that could not be parsed
+    Malformed,
+}
+
+impl fmt::Display for ScanErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ScanErrorKind::Open => "cannot open directory",
+            ScanErrorKind::Read => "cannot read directory",
+            ScanErrorKind::Stat => "cannot read metadata",
+            ScanErrorKind::Malformed => "malformed directory entry",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A path the scanner could not fully read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanError {
+    pub path: String,
+    pub kind: ScanErrorKind,
+    pub errno: Option<i32>,
+}
+
+impl ScanError {
+    pub fn new(path: String, kind: ScanErrorKind, errno: Option<i32>) -> Self {
+        Self { path, kind, errno }
+    }
+
+    /// Builds the error from `errno` as the last failed call left it.
+    pub fn last_os_error(path: String, kind: ScanErrorKind) -> Self {
+        Self::new(path, kind, io::Error::last_os_error().raw_os_error())
+    }
+
+    /// True when running with more privileges would likely get past it.
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self.errno, Some(libc::EACCES) | Some(libc::EPERM))
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.kind)?;
+        if let Some(errno) = self.errno {
+            write!(f, " ({})", io::Error::from_raw_os_error(errno))?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters a scanner bumps as it goes, read from another thread to report progress.
+/// Setting `cancel` makes the scanner stop at the next directory.
+#[derive(Debug, Default)]
+pub struct ScanProgress {
+    directories: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    current_path: parking_lot::Mutex<String>,
+    cancelled: AtomicBool,
+}
+
+/// A point-in-time copy of a `ScanProgress`.
+#[derive(Debug, Clone, Default)]
+pub struct ScanSnapshot {
+    pub directories: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub current_path: String,
+}
+
+impl fmt::Display for ScanSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} directories, {} entries, {}",
+            self.directories,
+            self.entries,
+            humansize::format_size(self.bytes, humansize::BINARY)
+        )
+    }
+}
+
+impl ScanProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_directory(&self, path: &str, entries: &[ScanEntry]) {
+        self.directories.fetch_add(1, Ordering::Relaxed);
+        self.record_entries(entries);
+        *self.current_path.lock() = path.to_string();
+    }
+
+    pub fn record_entries(&self, entries: &[ScanEntry]) {
+        let bytes: u64 = entries.iter().map(|entry| entry.size).sum();
+        self.entries.fetch_add(entries.len() as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> ScanSnapshot {
+        ScanSnapshot {
+            directories: self.directories.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            current_path: self.current_path.lock().clone(),
+        }
+    }
+}
+
+/// A backend able to enumerate everything below a root directory.
+/// The root itself is never handed to the sink as an entry.
+pub trait Scanner: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn scan(&self, path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink);
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScannerKind {
+    /// getattrlistbulk on macOS, getdents64/statx on Linux
+    #[default]
+    Native,
+    /// Portable walkdir traversal
+    Walkdir,
+}
+
+pub fn scanner_for(kind: ScannerKind) -> Box<dyn Scanner> {
+    match kind {
+        ScannerKind::Native => Box::new(NativeScanner),
+        ScannerKind::Walkdir => Box::new(WalkDirScanner),
+    }
+}
+
+/// The platform's fastest scanner.
+pub struct NativeScanner;
+
+#[cfg(target_os = "macos")]
+impl Scanner for NativeScanner {
+    fn name(&self) -> &'static str {
+        "getattrlistbulk"
+    }
+
+    fn scan(&self, path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+        mac::fetch_file_system_with_getattrlistbulk_parallel(path, options, progress, sink)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Scanner for NativeScanner {
+    fn name(&self) -> &'static str {
+        "getdents64"
+    }
+
+    fn scan(&self, path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+        linux::fetch_file_system_with_getdents_parallel(path, options, progress, sink)
+    }
+}
+
+pub struct WalkDirScanner;
+
+impl Scanner for WalkDirScanner {
+    fn name(&self) -> &'static str {
+        "walkdir"
+    }
+
+    fn scan(&self, path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+        let excluded = Mutex::new(Vec::new());
+        // The directories the walk is inside of, innermost last, with their depth. Entries found
+        // in the innermost one are batched until the walk enters or leaves a directory.
+        let mut open = vec![(path.to_string(), 0)];
+        let mut batch = Vec::new();
+        let mut errors = Vec::new();
+
+        // The walk itself is sequential, the per-entry lstat calls of a batch are spread over rayon.
+        // When following links walkdir refuses to re-enter an ancestor, which breaks loops.
+        let walker = WalkDir::new(path)
+            .min_depth(1)
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system)
+            .into_iter()
+            .filter_entry(|entry| {
+                let entry_path = entry.path().to_string_lossy();
+                let is_excluded = options.exclude.is_excluded(relative_to(path, &entry_path), entry.file_type().is_dir());
+                if is_excluded {
+                    excluded.lock().unwrap().push(entry_path.into_owned());
+                }
+                !is_excluded
+            });
+
+        for entry in walker {
+            if progress.is_cancelled() {
+                return;
+            }
+            // Whatever was filtered out sits in a directory the walk has not left yet
+            for excluded_path in excluded.lock().unwrap().drain(..) {
+                let directory = Path::new(&excluded_path).parent().map(|parent| parent.to_string_lossy().into_owned()).unwrap_or_default();
+                sink.add(&directory, DirectoryListing { excluded: vec![excluded_path], ..DirectoryListing::default() });
+            }
+
+            let (entry_path, depth, entry) = match entry {
+                Ok(entry) => (entry.path().to_path_buf(), entry.depth(), Ok(entry)),
+                // Broken and looping links come back as errors, but they are still entries
+                Err(err) => {
+                    let Some(error_path) = err.path() else {
+                        continue;
+                    };
+                    let errno = err.io_error().and_then(|err| err.raw_os_error());
+                    match fs::symlink_metadata(error_path) {
+                        Ok(metadata) if metadata.file_type().is_symlink() => (error_path.to_path_buf(), err.depth(), Err(errno)),
+                        // The directory itself was already listed, this is reading its contents failing
+                        link_metadata => {
+                            let kind = if link_metadata.is_ok() { ScanErrorKind::Open } else { ScanErrorKind::Stat };
+                            errors.push(ScanError::new(error_path.to_string_lossy().into_owned(), kind, errno));
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            // Coming back up to this depth means everything below the deeper directories was seen
+            while open.last().is_some_and(|(_, open_depth)| *open_depth >= depth) {
+                let (directory, _) = open.pop().unwrap();
+                hand_over(&directory, &mut batch, &mut errors, progress, sink);
+                sink.finish(&directory);
+            }
+
+            let is_directory = matches!(&entry, Ok(entry) if entry.file_type().is_dir());
+            batch.push((entry_path, entry));
+            if is_directory {
+                let directory = batch.last().unwrap().0.to_string_lossy().into_owned();
+                hand_over(&open.last().unwrap().0, &mut batch, &mut errors, progress, sink);
+                open.push((directory, depth));
+            }
+        }
+
+        if progress.is_cancelled() {
+            return;
+        }
+        for excluded_path in excluded.into_inner().unwrap() {
+            let directory = Path::new(&excluded_path).parent().map(|parent| parent.to_string_lossy().into_owned()).unwrap_or_default();
+            sink.add(&directory, DirectoryListing { excluded: vec![excluded_path], ..DirectoryListing::default() });
+        }
+        while let Some((directory, _)) = open.pop() {
+            hand_over(&directory, &mut batch, &mut errors, progress, sink);
+            sink.finish(&directory);
+        }
+    }
+}
+
+type WalkedEntry = (PathBuf, Result<walkdir::DirEntry, Option<i32>>);
+
+// Stats a batch of walked entries and hands them to the sink as part of `directory`
+fn hand_over(directory: &str, batch: &mut Vec<WalkedEntry>, errors: &mut Vec<ScanError>, progress: &ScanProgress, sink: &dyn ScanSink) {
+    if batch.is_empty() && errors.is_empty() {
+        return;
+    }
+
+    let entries = batch
+        .par_drain(..)
+        .map(|(entry_path, entry)| {
+            let link_metadata = fs::symlink_metadata(&entry_path).ok();
+            let is_symlink = link_metadata.as_ref().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+            let (is_file, metadata) = match &entry {
+                Ok(entry) => (!entry.file_type().is_dir(), entry.metadata().ok()),
+                Err(_) => (true, link_metadata),
+            };
+
+            let size = match &metadata {
+                Some(metadata) if is_file => metadata.len(),
+                _ => 0,
+            };
+            let mut scan_entry = ScanEntry::new(entry_path.to_string_lossy().into_owned(), size, is_file);
+            if !is_file {
+                progress.record_directory(&scan_entry.path, &[]);
+            }
+            if is_symlink {
+                if let Ok(target) = fs::read_link(&entry_path) {
+                    let broken = fs::metadata(&entry_path).is_err();
+                    scan_entry = scan_entry.with_symlink(SymlinkInfo { target, broken });
+                }
+            }
+            let scan_entry = match metadata {
+                Some(metadata) => scan_entry
+                    .with_allocated_size(metadata.blocks() * 512)
+                    .with_metadata(FileMetadata::from(&metadata)),
+                None => scan_entry,
+            };
+            progress.record_entries(std::slice::from_ref(&scan_entry));
+            scan_entry
+        })
+        .collect();
+
+    sink.add(directory, DirectoryListing { entries, excluded: Vec::new(), errors: mem::take(errors) });
+}
+
+/// Serves a fixed set of entries, so the tree builder can be exercised without touching the disk.
+#[derive(Default)]
+pub struct MemoryScanner {
+    entries: Vec<ScanEntry>,
+}
+
+impl MemoryScanner {
+    pub fn new(entries: Vec<ScanEntry>) -> Self {
+        Self { entries }
+    }
+
+    pub fn add(&mut self, path: &str, size: u64, is_file: bool) {
+        self.entries.push(ScanEntry::new(path.to_string(), size, is_file));
+    }
+}
+
+impl Scanner for MemoryScanner {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn scan(&self, path: &str, options: &ScanOptions, progress: &ScanProgress, sink: &dyn ScanSink) {
+        let root = Path::new(path);
+        let mut listings: BTreeMap<(usize, &Path), DirectoryListing> = BTreeMap::new();
+
+        for entry in &self.entries {
+            if progress.is_cancelled() {
+                return;
+            }
+            let entry_path = Path::new(&entry.path);
+            if entry_path == root || !entry_path.starts_with(root) {
+                continue;
+            }
+            // Anything below an excluded directory would never have been reached
+            let hidden = entry_path
+                .ancestors()
+                .skip(1)
+                .take_while(|ancestor| *ancestor != root)
+                .any(|ancestor| options.exclude.is_excluded(relative_to(path, &ancestor.to_string_lossy()), true));
+            if hidden {
+                continue;
+            }
+            let Some(directory) = entry_path.parent() else {
+                continue;
+            };
+            let listing = listings.entry((directory.components().count(), directory)).or_default();
+            if options.exclude.is_excluded(relative_to(path, &entry.path), !entry.is_file) {
+                listing.excluded.push(entry.path.clone());
+                continue;
+            }
+            progress.record_entries(std::slice::from_ref(entry));
+            listing.entries.push(entry.clone());
+        }
+
+        // Shallowest first, so every directory reaches the sink as an entry before its own listing
+        let directories: Vec<String> = listings.keys().map(|(_, directory)| directory.to_string_lossy().into_owned()).collect();
+        for ((_, directory), listing) in listings {
+            sink.add(&directory.to_string_lossy(), listing);
+        }
+        for directory in directories.iter().rev() {
+            sink.finish(directory);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{build_fs_model_blocking, FileSystemTree, NodeKind};
+
+    fn scan(scanner: &dyn Scanner, root: &Path, options: ScanOptions) -> FileSystemTree {
+        build_fs_model_blocking(root.to_string_lossy().into_owned(), scanner, options, &ScanProgress::new()).unwrap()
+    }
+
+    #[test]
+    fn scanners_record_metadata_per_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), b"data").unwrap();
+        fs::hard_link(root.join("sub/file"), root.join("second")).unwrap();
+
+        for scanner in [&NativeScanner as &dyn Scanner, &WalkDirScanner] {
+            let tree = scan(scanner, root, ScanOptions::default());
+            let sub = tree.child_named(tree.root(), "sub").unwrap();
+            let file = tree.child_named(sub, "file").unwrap();
+            for (id, path) in [(sub, root.join("sub")), (file, root.join("sub/file"))] {
+                let expected = fs::symlink_metadata(&path).unwrap();
+                let node = tree.node(id);
+                assert_eq!(node.modified(), expected.mtime(), "{}", scanner.name());
+                assert_eq!(node.accessed(), expected.atime(), "{}", scanner.name());
+                assert_eq!(node.changed(), expected.ctime(), "{}", scanner.name());
+                assert_eq!((node.uid(), node.gid()), (expected.uid(), expected.gid()), "{}", scanner.name());
+                assert_eq!(node.mode(), expected.mode(), "{}", scanner.name());
+                assert_eq!((node.device(), node.inode()), (expected.dev(), expected.ino()), "{}", scanner.name());
+            }
+            assert_eq!(tree.node(file).nlink(), 2, "{}", scanner.name());
+        }
+    }
+
+    #[test]
+    fn sparse_files_allocate_less_than_their_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let length = 16 * 1024 * 1024;
+        fs::File::create(root.join("sparse")).unwrap().set_len(length).unwrap();
+
+        for scanner in [&NativeScanner as &dyn Scanner, &WalkDirScanner] {
+            let tree = scan(scanner, root, ScanOptions::default());
+            let sparse = tree.node(tree.child_named(tree.root(), "sparse").unwrap());
+            let expected = fs::metadata(root.join("sparse")).unwrap().blocks() * 512;
+            assert_eq!((sparse.size(), sparse.allocated_size()), (length, expected), "{}", scanner.name());
+            assert!(sparse.allocated_size() < length, "{}", scanner.name());
+            assert_eq!(tree.node(tree.root()).size(), length, "{}", scanner.name());
+            assert!(tree.node(tree.root()).allocated_size() < length, "{}", scanner.name());
+        }
+    }
+
+    #[test]
+    fn symlinks_are_followed_only_when_asked_and_never_in_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("real/inner")).unwrap();
+        fs::write(root.join("real/inner/file"), b"12345").unwrap();
+        std::os::unix::fs::symlink("real", root.join("alias")).unwrap();
+        std::os::unix::fs::symlink(root, root.join("real/inner/up")).unwrap();
+        std::os::unix::fs::symlink("nowhere", root.join("broken")).unwrap();
+
+        // The native scanners enter each (device, inode) once, walkdir only refuses to re-enter an ancestor
+        for (scanner, entered_once) in [(&NativeScanner as &dyn Scanner, true), (&WalkDirScanner, false)] {
+            let tree = scan(scanner, root, ScanOptions::default());
+            let alias = tree.child_named(tree.root(), "alias").unwrap();
+            assert_eq!(tree.node(alias).kind(), NodeKind::Symlink, "{}", scanner.name());
+            assert_eq!(tree.node(alias).symlink().unwrap().target, Path::new("real"), "{}", scanner.name());
+            assert!(!tree.node(alias).symlink().unwrap().broken, "{}", scanner.name());
+            assert_eq!(tree.node(alias).children_len(), 0, "{}", scanner.name());
+            let broken = tree.child_named(tree.root(), "broken").unwrap();
+            assert!(tree.node(broken).symlink().unwrap().broken, "{}", scanner.name());
+
+            let options = ScanOptions { follow_symlinks: true, ..ScanOptions::default() };
+            let tree = scan(scanner, root, options);
+            let named = |name: &str| tree.walk(tree.root()).filter(|&id| tree.get_name(id) == name).collect::<Vec<_>>();
+            let alias = tree.child_named(tree.root(), "alias").unwrap();
+            assert_eq!(tree.node(alias).kind(), NodeKind::Symlink, "{}", scanner.name());
+            assert_eq!(named("file").len(), if entered_once { 1 } else { 2 }, "{}", scanner.name());
+            // The link back up to the root is listed but never entered
+            let ups = named("up");
+            assert!(!ups.is_empty(), "{}", scanner.name());
+            assert!(ups.iter().all(|&up| tree.node(up).children_len() == 0), "{}", scanner.name());
+        }
+    }
+
+    // /proc is always a filesystem of its own, so a followed link into it crosses a device
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_file_system_lists_other_devices_without_entering_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("local")).unwrap();
+        fs::write(root.join("local/file"), b"x").unwrap();
+        std::os::unix::fs::symlink("/proc/self/fdinfo", root.join("proc")).unwrap();
+
+        for scanner in [&NativeScanner as &dyn Scanner, &WalkDirScanner] {
+            let options = ScanOptions { follow_symlinks: true, ..ScanOptions::default() };
+            let tree = scan(scanner, root, options);
+            let proc = tree.child_named(tree.root(), "proc").unwrap();
+            assert!(tree.node(proc).children_len() > 0, "{}", scanner.name());
+
+            let options = ScanOptions { follow_symlinks: true, one_file_system: true, ..ScanOptions::default() };
+            let tree = scan(scanner, root, options);
+            let proc = tree.child_named(tree.root(), "proc").unwrap();
+            assert_eq!(tree.node(proc).children_len(), 0, "{}", scanner.name());
+            let local = tree.child_named(tree.root(), "local").unwrap();
+            assert!(tree.child_named(local, "file").is_some(), "{}", scanner.name());
+        }
+    }
+
+    #[test]
+    fn progress_counts_what_the_scan_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/one"), [0; 100]).unwrap();
+        fs::write(root.join("a/b/two"), [0; 20]).unwrap();
+        fs::write(root.join("three"), [0; 3]).unwrap();
+
+        let progress = ScanProgress::new();
+        build_fs_model_blocking(root.to_string_lossy().into_owned(), &NativeScanner, ScanOptions::default(), &progress).unwrap();
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.directories, snapshot.entries, snapshot.bytes), (3, 5, 123));
+        assert!(snapshot.current_path.starts_with(&*root.to_string_lossy()));
+        assert_eq!(snapshot.to_string(), "3 directories, 5 entries, 123 B");
+    }
+
+    // Cancels the scan it is handed as soon as the first directory comes in
+    struct Cancelling<'a> {
+        progress: &'a ScanProgress,
+        directories: Mutex<Vec<String>>,
+    }
+
+    impl ScanSink for Cancelling<'_> {
+        fn add(&self, directory: &str, _listing: DirectoryListing) {
+            self.directories.lock().unwrap().push(directory.to_string());
+            self.progress.cancel();
+        }
+
+        fn finish(&self, _directory: &str) {}
+    }
+
+    #[test]
+    fn cancelling_stops_the_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/b/c/d/e")).unwrap();
+        let path = root.to_string_lossy().into_owned();
+
+        for scanner in [&NativeScanner as &dyn Scanner, &WalkDirScanner] {
+            let progress = ScanProgress::new();
+            let sink = Cancelling { progress: &progress, directories: Mutex::new(Vec::new()) };
+            scanner.scan(&path, &ScanOptions::default(), &progress, &sink);
+            assert_eq!(sink.directories.into_inner().unwrap().len(), 1, "{}", scanner.name());
+
+            // A cancelled load builds no tree at all
+            let progress = ScanProgress::new();
+            progress.cancel();
+            assert!(build_fs_model_blocking(path.clone(), scanner, ScanOptions::default(), &progress).is_none(), "{}", scanner.name());
+        }
+    }
+
+    // Nests directories below `root` until their full path is longer than PATH_MAX, which is as
+    // far as opening them by path goes, and returns how many levels down that is
+    fn nest_past_path_max(root: &Path) -> usize {
+        let name = std::ffi::CString::new("d".repeat(255)).unwrap();
+        let root = std::ffi::CString::new(root.as_os_str().as_encoded_bytes()).unwrap();
+        let mut levels = 0;
+        unsafe {
+            let mut fd = libc::open(root.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY);
+            while levels * 256 <= libc::PATH_MAX as usize {
+                assert_eq!(libc::mkdirat(fd, name.as_ptr(), 0o755), 0);
+                let below = libc::openat(fd, name.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY);
+                libc::close(fd);
+                fd = below;
+                levels += 1;
+            }
+            libc::close(fd);
+        }
+        levels
+    }
+
+    #[test]
+    fn unreadable_directories_are_kept_as_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("readable"), b"x").unwrap();
+        let levels = nest_past_path_max(root);
+
+        for scanner in [&NativeScanner as &dyn Scanner, &WalkDirScanner] {
+            let tree = scan(scanner, root, ScanOptions::default());
+            let errors: Vec<&ScanError> = tree.walk(tree.root()).flat_map(|id| tree.node(id).scan_errors()).collect();
+            assert!(!errors.is_empty(), "{}", scanner.name());
+            assert!(errors.iter().all(|error| error.errno == Some(libc::ENAMETOOLONG)), "{}: {:?}", scanner.name(), errors);
+
+            // Everything from the unreadable directory up is flagged, what was read is not
+            let deepest = tree.walk(tree.root()).max_by_key(|&id| tree.get_path(id).components().count()).unwrap();
+            assert!(tree.get_path(deepest).components().count() < root.components().count() + levels, "{}", scanner.name());
+            assert!(tree.node(deepest).is_incomplete(), "{}", scanner.name());
+            assert!(tree.node(tree.root()).is_incomplete(), "{}", scanner.name());
+            assert!(!tree.node(tree.child_named(tree.root(), "readable").unwrap()).is_incomplete(), "{}", scanner.name());
+        }
+    }
+}
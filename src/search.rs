@@ -0,0 +1,138 @@
+use regex::{Regex, RegexBuilder};
+
+use crate::exclude::glob_to_regex;
+use crate::listing::DisplayOptions;
+
+/// How `search` compares names.
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    /// Anywhere in the name, ignoring case; held in lowercase
+    Substring(String),
+    /// The whole name, such as `*.mp4` or `IMG_????.jpg`, ignoring case
+    Glob(Regex),
+    /// Anywhere in the name, exactly as written
+    Regex(Regex),
+}
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Substring(text) => name.to_lowercase().contains(text.as_str()),
+            NamePattern::Glob(regex) | NamePattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// What `search` looks for: a name, if one was given, and the same filters and order `display`
+/// takes.
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub pattern: Option<NamePattern>,
+    pub options: DisplayOptions,
+}
+
+impl SearchQuery {
+    /// Reads the arguments of `search`, for example `holiday --ext jpg,png --newer 30d`. The
+    /// words before the first option are the name to look for. It is a glob when it has `*`, `?`
+    /// or `[` in it, a regular expression with `--regex`, and otherwise a piece of the name.
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let options_start = words.iter().position(|word| word.starts_with("--")).unwrap_or(words.len());
+        let text = words[..options_start].join(" ");
+
+        let mut is_regex = false;
+        let flags: Vec<&str> = words[options_start..]
+            .iter()
+            .copied()
+            .filter(|&word| {
+                if word == "--regex" {
+                    is_regex = true;
+                }
+                word != "--regex"
+            })
+            .collect();
+        let options = DisplayOptions::parse(&flags.join(" "))?;
+
+        let pattern = if text.is_empty() {
+            if is_regex {
+                return Err("--regex needs a pattern before it".to_string());
+            }
+            if !options.is_filtered() {
+                return Err("Give a name to look for or at least one filter, e.g. `search report --ext pdf`".to_string());
+            }
+            None
+        } else if is_regex {
+            Some(NamePattern::Regex(Regex::new(&text).map_err(|err| format!("Invalid pattern '{}': {}", text, err))?))
+        } else if text.contains(['*', '?', '[']) {
+            let regex = RegexBuilder::new(&format!("^{}$", glob_to_regex(&text)))
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| format!("Invalid pattern '{}': {}", text, err))?;
+            Some(NamePattern::Glob(regex))
+        } else {
+            Some(NamePattern::Substring(text.to_lowercase()))
+        };
+
+        Ok(SearchQuery { pattern, options })
+    }
+
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| pattern.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::SortKey;
+
+    fn matches(args: &str, name: &str) -> bool {
+        SearchQuery::parse(args).unwrap().matches_name(name)
+    }
+
+    #[test]
+    fn plain_words_match_anywhere_in_the_name_ignoring_case() {
+        assert!(matches("Report", "annual_report.PDF"));
+        assert!(matches("report", "REPORT"));
+        assert!(!matches("report", "rep.pdf"));
+        assert!(matches("my   file", "My File.txt"));
+        assert!(!matches("my file", "my_file.txt"));
+    }
+
+    #[test]
+    fn globs_match_the_whole_name_ignoring_case() {
+        assert!(matches("*.mp4", "clip.MP4"));
+        assert!(!matches("*.mp4", "clip.mp4.part"));
+        assert!(matches("IMG_????.jpg", "img_0001.jpg"));
+        assert!(!matches("IMG_????.jpg", "IMG_01.jpg"));
+        assert!(matches("[ab]*", "beta"));
+        assert!(!matches("[ab]*", "gamma"));
+    }
+
+    #[test]
+    fn regexes_match_anywhere_and_keep_case() {
+        assert!(matches("^IMG_\\d+ --regex", "IMG_12.jpg"));
+        assert!(!matches("^IMG_\\d+ --regex", "img_12.jpg"));
+        assert!(matches("\\d{4} --regex", "taxes-2024-final"));
+        // Without --regex the same text is a glob or a piece of the name
+        assert!(!matches("\\d{4}", "taxes-2024-final"));
+        assert!(SearchQuery::parse("( --regex").is_err());
+        assert!(SearchQuery::parse("--regex").is_err());
+    }
+
+    #[test]
+    fn names_combine_with_the_display_filters() {
+        let query = SearchQuery::parse("report --regex --ext PDF --min 1K --sort size").unwrap();
+        assert!(matches!(query.pattern, Some(NamePattern::Regex(_))));
+        assert_eq!(query.options.extensions, ["pdf"]);
+        assert_eq!(query.options.min_size, Some(1024));
+        assert_eq!((query.options.sort, query.options.descending), (Some(SortKey::Size), true));
+
+        // A filter on its own is enough, and lets every name through
+        let query = SearchQuery::parse("--type dir").unwrap();
+        assert!(query.pattern.is_none() && query.matches_name("anything"));
+        assert!(SearchQuery::parse("").is_err());
+        assert!(SearchQuery::parse("--sort size").is_err());
+        assert!(SearchQuery::parse("report --colour red").is_err());
+    }
+}
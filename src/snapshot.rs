@@ -0,0 +1,472 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::exclude::relative_to;
+use crate::kernel::Kernel;
+use crate::scanner::{ScanError, ScanErrorKind, ScanOptions, Scanner};
+use crate::system::{build_node, FileMetadata, FileSystemNode, FileSystemTree, MountInfo, NodeId, SymlinkInfo};
+
+// A snapshot is the magic and version, then every node in pre-order: its name (the full path for
+// the root), sizes, metadata, optional link/mount/error records and the number of children.
+// Integers are little-endian and strings are length-prefixed UTF-8.
+const MAGIC: &[u8; 8] = b"FSMSNAP\0";
+const VERSION: u32 = 1;
+// Nothing legitimate comes close; a bigger length means the file is damaged
+const MAX_STRING: usize = 1 << 20;
+
+const FLAG_FILE: u8 = 1;
+const FLAG_SYMLINK: u8 = 1 << 1;
+const FLAG_BROKEN: u8 = 1 << 2;
+const FLAG_MOUNT: u8 = 1 << 3;
+const FLAG_INCOMPLETE: u8 = 1 << 4;
+
+/// Writes the tree below `root` to `path`. The file is replaced atomically, so a crash midway
+/// leaves the previous snapshot intact.
+pub fn save(tree: &FileSystemTree, path: &Path) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(MAGIC)?;
+        write_u32(&mut writer, VERSION)?;
+        write_node(&mut writer, tree, tree.root())?;
+        writer.flush()?;
+    }
+    fs::rename(&temporary, path)
+}
+
+/// Reads a tree written by `save`. Sizes are stored already aggregated, so it is ready to use.
+pub fn load(path: &Path) -> io::Result<FileSystemTree> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a snapshot file"));
+    }
+    let version = read_u32(&mut reader)?;
+    if version != VERSION {
+        return Err(invalid_data(&format!("unsupported snapshot version {}", version)));
+    }
+
+    let (name, root, child_count) = read_node(&mut reader)?;
+    let mut tree = FileSystemTree::new(PathBuf::from(name), root);
+    let root = tree.root();
+    read_children(&mut reader, &mut tree, root, child_count)?;
+    Ok(tree)
+}
+
+fn write_node<W: Write>(writer: &mut W, tree: &FileSystemTree, id: NodeId) -> io::Result<()> {
+    let node = tree.node(id);
+
+    if id == tree.root() {
+        write_string(writer, &tree.root_path().to_string_lossy())?;
+    } else {
+        write_string(writer, tree.get_name(id))?;
+    }
+
+    let mut flags = 0;
+    if node.is_file() {
+        flags |= FLAG_FILE;
+    }
+    if let Some(link) = node.symlink() {
+        flags |= FLAG_SYMLINK;
+        if link.broken {
+            flags |= FLAG_BROKEN;
+        }
+    }
+    if node.mount().is_some() {
+        flags |= FLAG_MOUNT;
+    }
+    if node.is_incomplete() {
+        flags |= FLAG_INCOMPLETE;
+    }
+    writer.write_all(&[flags])?;
+
+    write_u64(writer, node.size())?;
+    write_u64(writer, node.allocated_size())?;
+    write_u64(writer, node.excluded_count())?;
+
+    let metadata = node.metadata();
+    write_i64(writer, metadata.modified)?;
+    write_i64(writer, metadata.accessed)?;
+    write_i64(writer, metadata.changed)?;
+    write_u32(writer, metadata.uid)?;
+    write_u32(writer, metadata.gid)?;
+    write_u32(writer, metadata.mode)?;
+    write_u64(writer, metadata.inode)?;
+    write_u64(writer, metadata.device)?;
+    write_u64(writer, metadata.nlink)?;
+
+    if let Some(link) = node.symlink() {
+        write_string(writer, &link.target.to_string_lossy())?;
+    }
+    if let Some(mount) = node.mount() {
+        write_string(writer, &mount.file_system)?;
+        write_u64(writer, mount.total_space)?;
+        write_u64(writer, mount.available_space)?;
+    }
+
+    write_u32(writer, node.scan_errors().len() as u32)?;
+    for error in node.scan_errors() {
+        write_string(writer, &error.path)?;
+        writer.write_all(&[error_kind_to_byte(error.kind)])?;
+        write_i32(writer, error.errno.unwrap_or(0))?;
+    }
+
+    write_u32(writer, node.children_len() as u32)?;
+    for &child in node.children() {
+        write_node(writer, tree, child)?;
+    }
+    Ok(())
+}
+
+fn read_children<R: Read>(reader: &mut R, tree: &mut FileSystemTree, parent: NodeId, child_count: u32) -> io::Result<()> {
+    for _ in 0..child_count {
+        let (name, node, grandchild_count) = read_node(reader)?;
+        let child = tree.insert(Some(parent), &name, node).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        read_children(reader, tree, child, grandchild_count)?;
+    }
+    Ok(())
+}
+
+// Reads one node record: its name, the node itself and how many children follow it
+fn read_node<R: Read>(reader: &mut R) -> io::Result<(String, FileSystemNode, u32)> {
+    let name = read_string(reader)?;
+
+    let flags = read_u8(reader)?;
+    let size = read_u64(reader)?;
+    let allocated_size = read_u64(reader)?;
+    let excluded = read_u64(reader)?;
+    let metadata = FileMetadata {
+        modified: read_i64(reader)?,
+        accessed: read_i64(reader)?,
+        changed: read_i64(reader)?,
+        uid: read_u32(reader)?,
+        gid: read_u32(reader)?,
+        mode: read_u32(reader)?,
+        inode: read_u64(reader)?,
+        device: read_u64(reader)?,
+        nlink: read_u64(reader)?,
+    };
+
+    let mut node = FileSystemNode::new(flags & FLAG_FILE != 0, size);
+    node.set_allocated_size(allocated_size);
+    node.set_excluded_count(excluded);
+    node.set_metadata(metadata);
+    node.set_incomplete(flags & FLAG_INCOMPLETE != 0);
+
+    if flags & FLAG_SYMLINK != 0 {
+        node.set_symlink(Some(SymlinkInfo {
+            target: PathBuf::from(read_string(reader)?),
+            broken: flags & FLAG_BROKEN != 0,
+        }));
+    }
+    if flags & FLAG_MOUNT != 0 {
+        node.set_mount(Some(MountInfo {
+            file_system: read_string(reader)?,
+            total_space: read_u64(reader)?,
+            available_space: read_u64(reader)?,
+        }));
+    }
+
+    let error_count = read_u32(reader)?;
+    let mut scan_errors = Vec::new();
+    for _ in 0..error_count {
+        let error_path = read_string(reader)?;
+        let kind = error_kind_from_byte(read_u8(reader)?)?;
+        let errno = read_i32(reader)?;
+        scan_errors.push(ScanError::new(error_path, kind, (errno != 0).then_some(errno)));
+    }
+    node.set_scan_errors(scan_errors);
+
+    let child_count = read_u32(reader)?;
+    Ok((name, node, child_count))
+}
+
+fn error_kind_to_byte(kind: ScanErrorKind) -> u8 {
+    match kind {
+        ScanErrorKind::Open => 0,
+        ScanErrorKind::Read => 1,
+        ScanErrorKind::Stat => 2,
+        ScanErrorKind::Malformed => 3,
+    }
+}
+
+fn error_kind_from_byte(byte: u8) -> io::Result<ScanErrorKind> {
+    match byte {
+        0 => Ok(ScanErrorKind::Open),
+        1 => Ok(ScanErrorKind::Read),
+        2 => Ok(ScanErrorKind::Stat),
+        3 => Ok(ScanErrorKind::Malformed),
+        _ => Err(invalid_data("unknown scan error kind")),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i32<W: Write>(writer: &mut W, value: i32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i64<W: Write>(writer: &mut W, value: i64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_i64<R: Read>(reader: &mut R) -> io::Result<i64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = read_u32(reader)? as usize;
+    if length > MAX_STRING {
+        return Err(invalid_data("string length out of range"));
+    }
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("string is not valid UTF-8"))
+}
+
+/// What a revalidation pass changed in the model.
+#[derive(Debug, Default)]
+pub struct Revalidation {
+    pub directories_checked: u64,
+    pub directories_changed: u64,
+    pub added: u64,
+    pub removed: u64,
+    pub updated: u64,
+}
+
+impl Revalidation {
+    pub fn is_unchanged(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.updated == 0
+    }
+}
+
+impl fmt::Display for Revalidation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "checked {} directories, {} changed: {} added, {} removed, {} updated",
+            self.directories_checked, self.directories_changed, self.added, self.removed, self.updated
+        )
+    }
+}
+
+/// Brings a tree loaded from a snapshot up to date. Every directory is re-stat'ed, and the ones
+/// whose mtime moved are listed again: entries that appeared are scanned, entries that went away
+/// are dropped, and files whose size, mtime or inode changed are refreshed. The kernel is only
+/// locked while a directory is patched, so the model stays usable while this runs.
+pub fn revalidate(kernel: &Mutex<Kernel>, scanner: &dyn Scanner, options: &ScanOptions) -> Revalidation {
+    let (root_path, directories) = {
+        let kernel = kernel.lock().unwrap();
+        let tree = kernel.tree();
+        let directories: Vec<(NodeId, PathBuf, i64)> = tree
+            .walk(tree.root())
+            .filter(|id| !tree.node(*id).is_file())
+            .map(|id| (id, tree.get_path(id), tree.node(id).modified()))
+            .collect();
+        (tree.root_path().to_string_lossy().into_owned(), directories)
+    };
+
+    let mut report = Revalidation::default();
+    for (id, path, modified) in directories {
+        // A directory that is gone is dropped by its parent, which changed as well
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        report.directories_checked += 1;
+
+        if metadata.mtime() != modified {
+            report.directories_changed += 1;
+            patch_directory(kernel, id, &path, &root_path, scanner, options, &mut report);
+            let mut kernel = kernel.lock().unwrap();
+            if still_at(kernel.tree(), id, &path) {
+                kernel.tree_mut().node_mut(id).set_metadata(FileMetadata::from(&metadata));
+            }
+        }
+    }
+    report
+}
+
+// The directory list is taken up front, and a node removed since then may have given its slot
+// to another entry
+fn still_at(tree: &FileSystemTree, id: NodeId, path: &Path) -> bool {
+    tree.contains(id) && tree.get_path(id) == path
+}
+
+fn patch_directory(
+    kernel: &Mutex<Kernel>,
+    id: NodeId,
+    path: &Path,
+    root_path: &str,
+    scanner: &dyn Scanner,
+    options: &ScanOptions,
+    report: &mut Revalidation,
+) {
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    // DirEntry::metadata does not follow links, which matches what the scanners record
+    let listing: HashMap<String, fs::Metadata> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.file_name().to_string_lossy().into_owned(), entry.metadata().ok()?)))
+        .collect();
+    // Whether the scanners would have descended into the entry
+    let is_directory = |name: &str, metadata: &fs::Metadata| {
+        metadata.is_dir() || (options.follow_symlinks && metadata.is_symlink() && path.join(name).is_dir())
+    };
+
+    let mut known = HashSet::new();
+    let pending = {
+        let mut kernel = kernel.lock().unwrap();
+        // Left as the queued actions made them, the way a rescan leaves them
+        let pending = kernel.pending_paths();
+        let tree = kernel.tree_mut();
+        if !still_at(tree, id, path) {
+            return;
+        }
+
+        for child in tree.node(id).children().to_vec() {
+            let name = tree.get_name(child).to_string();
+            let (is_file, size, old_metadata) = {
+                let node = tree.node(child);
+                (node.is_file(), node.size(), *node.metadata())
+            };
+
+            match listing.get(&name) {
+                Some(metadata) if is_file != is_directory(&name, metadata) => {
+                    known.insert(name);
+                    if is_file && (metadata.len() != size || metadata.mtime() != old_metadata.modified || metadata.ino() != old_metadata.inode) {
+                        tree.resize(child, metadata.len(), metadata.blocks() * 512, FileMetadata::from(metadata));
+                        report.updated += 1;
+                    }
+                }
+                // Created or moved in by a queued action, so not on disk yet
+                None if pending.keeps(&path.join(&name)) => {
+                    known.insert(name);
+                }
+                // Gone, or replaced by something of the other kind, which is picked up as an addition
+                _ => {
+                    tree.remove(child);
+                    report.removed += 1;
+                }
+            }
+        }
+        pending
+    };
+
+    for (name, metadata) in listing {
+        if known.contains(&name) {
+            continue;
+        }
+        let entry_path = path.join(&name);
+        // Moved away by a queued action, so the model no longer has it here
+        if pending.skips(&entry_path) {
+            continue;
+        }
+        if options.exclude.is_excluded(relative_to(root_path, &entry_path.to_string_lossy()), is_directory(&name, &metadata)) {
+            continue;
+        }
+
+        // New subtrees are scanned without the lock and grafted in afterwards
+        if let Some(subtree) = build_node(&entry_path, &metadata, scanner, options) {
+            let mut kernel = kernel.lock().unwrap();
+            let tree = kernel.tree_mut();
+            if still_at(tree, id, path) && tree.child_named(id, &name).is_none() {
+                if let Err(err) = tree.graft(id, &name, &subtree) {
+                    eprintln!("Cannot add {}: {}", entry_path.display(), err);
+                    return;
+                }
+                report.added += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::{NativeScanner, ScanProgress};
+    use crate::system::build_fs_model_blocking;
+
+    #[test]
+    fn revalidation_leaves_queued_actions_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("moved"), b"moved").unwrap();
+        let root = dir.path().to_string_lossy().into_owned();
+        let options = ScanOptions::default();
+        let tree = build_fs_model_blocking(root.clone(), &NativeScanner, options.clone(), &ScanProgress::new()).unwrap();
+
+        let mut kernel = Kernel::new(tree, false, true, None);
+        kernel.move_item(format!("{}/moved", root), format!("{}/a", root)).unwrap();
+        let root_id = kernel.tree().root();
+        kernel.create(root_id, "created".to_string(), true).unwrap();
+        // Something appears on disk as well, and both directories look changed since the scan
+        fs::write(dir.path().join("appeared"), b"new").unwrap();
+        let a = kernel.tree().child_named(root_id, "a").unwrap();
+        for id in [root_id, a] {
+            let mut metadata = *kernel.tree().node(id).metadata();
+            metadata.modified -= 1;
+            kernel.tree_mut().node_mut(id).set_metadata(metadata);
+        }
+
+        let kernel = Mutex::new(kernel);
+        let report = revalidate(&kernel, &NativeScanner, &options);
+        assert_eq!((report.added, report.removed), (1, 0));
+
+        let kernel = kernel.into_inner().unwrap();
+        let tree = kernel.tree();
+        assert!(tree.child_named(root_id, "appeared").is_some());
+        assert!(tree.child_named(root_id, "created").is_some());
+        assert!(tree.child_named(a, "moved").is_some());
+        // Still on disk where it was, but the queued move already took it away
+        assert!(tree.child_named(root_id, "moved").is_none());
+    }
+}
@@ -1,59 +1,281 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, Weak};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::mem;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use sysinfo::{DiskExt, System, SystemExt};
 
-use crate::mac;
+use crate::exclude::IGNORE_FILE;
+use crate::listing;
+use crate::scanner::{DirectoryListing, ScanEntry, ScanError, ScanOptions, ScanProgress, ScanSink, Scanner};
+
+/// Inode level metadata reported by the scanners. Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub modified: i64,
+    pub accessed: i64,
+    pub changed: i64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub inode: u64,
+    pub device: u64,
+    pub nlink: u64,
+}
+
+impl From<&fs::Metadata> for FileMetadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Self {
+            modified: metadata.mtime(),
+            accessed: metadata.atime(),
+            changed: metadata.ctime(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode(),
+            inode: metadata.ino(),
+            device: metadata.dev(),
+            nlink: metadata.nlink(),
+        }
+    }
+}
+
+/// Where a symbolic link points, as stored in the link itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymlinkInfo {
+    pub target: PathBuf,
+    /// The target does not resolve (missing, or part of a link loop)
+    pub broken: bool,
+}
+
+/// The volume mounted at a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub file_system: String,
+    pub total_space: u64,
+    pub available_space: u64,
+}
+
+impl MountInfo {
+    // Pseudo and network filesystems are not in sysinfo's disk list, so fall back on statvfs
+    fn from_statvfs(path: &Path) -> Self {
+        let mut info = MountInfo {
+            file_system: "unknown".to_string(),
+            total_space: 0,
+            available_space: 0,
+        };
+        if let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) {
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } == 0 {
+                info.total_space = stat.f_blocks as u64 * stat.f_frsize as u64;
+                info.available_space = stat.f_bavail as u64 * stat.f_frsize as u64;
+            }
+        }
+        info
+    }
+}
+
+pub fn mounted_volumes() -> HashMap<PathBuf, MountInfo> {
+    let mut system = System::new();
+    system.refresh_disks_list();
+    let mut volumes: HashMap<PathBuf, MountInfo> = system
+        .disks()
+        .iter()
+        .map(|disk| {
+            (
+                disk.mount_point().to_path_buf(),
+                MountInfo {
+                    file_system: String::from_utf8_lossy(disk.file_system()).into_owned(),
+                    total_space: disk.total_space(),
+                    available_space: disk.available_space(),
+                },
+            )
+        })
+        .collect();
+
+    // The mount table also has the pseudo filesystems, which is where their type comes from
+    #[cfg(target_os = "linux")]
+    if let Ok(mounts) = fs::read_to_string("/proc/self/mounts") {
+        for line in mounts.lines() {
+            let mut fields = line.split_whitespace().skip(1);
+            if let (Some(mount_point), Some(file_system)) = (fields.next(), fields.next()) {
+                // Spaces and tabs in mount points are written as octal escapes
+                let mount_point = PathBuf::from(mount_point.replace("\\040", " ").replace("\\011", "\t"));
+                volumes.entry(mount_point.clone()).or_insert_with(|| MountInfo {
+                    file_system: file_system.to_string(),
+                    ..MountInfo::from_statvfs(&mount_point)
+                });
+            }
+        }
+    }
+
+    volumes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Position of a node in its `FileSystemTree`. IDs are never handed out twice, so an ID keeps
+/// naming the same entry after that entry has been removed. This is the number `display` lists
+/// and commands accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(NodeId)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct NameId(u32);
+
+// Each distinct name is stored once. Real trees repeat a few thousand names (`index.js`, `.git`,
+// `Contents`) across millions of entries.
+#[derive(Debug, Default)]
+struct NameTable {
+    names: Vec<Arc<str>>,
+    ids: HashMap<Arc<str>, NameId>,
+}
+
+impl NameTable {
+    fn intern(&mut self, name: &str) -> NameId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = NameId(self.names.len() as u32);
+        let name: Arc<str> = Arc::from(name);
+        self.names.push(name.clone());
+        self.ids.insert(name, id);
+        id
+    }
+
+    fn lookup(&self, name: &str) -> Option<NameId> {
+        self.ids.get(name).copied()
+    }
+
+    fn get(&self, id: NameId) -> &str {
+        &self.names[id.0 as usize]
+    }
+}
+
+// What only a few nodes have, kept out of line so the common node stays small
+#[derive(Debug, Clone, Default)]
+struct NodeExtra {
+    symlink: Option<SymlinkInfo>,
+    mount: Option<MountInfo>,
+    scan_errors: Vec<ScanError>,
+}
+
+impl NodeExtra {
+    fn is_empty(&self) -> bool {
+        self.symlink.is_none() && self.mount.is_none() && self.scan_errors.is_empty()
+    }
+}
 
 #[derive(Debug)]
 pub struct FileSystemNode {
-    name: String,
-    path: PathBuf,
+    name: NameId,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
     is_file: bool,
-    size: u64,
-    parent: Option<Weak<Mutex<FileSystemNode>>>,
-    children: Vec<Arc<Mutex<FileSystemNode>>>,
     to_be_deleted: bool,
+    /// Set when this node or anything below it could not be read, so its size is a lower bound
+    incomplete: bool,
+    /// Taken out of the tree; the slot is never given to another entry, so IDs held elsewhere
+    /// cannot name one
+    removed: bool,
+    size: u64,
+    allocated_size: u64,
+    excluded: u64,
+    metadata: FileMetadata,
+    extra: Option<Box<NodeExtra>>,
 }
 
 impl FileSystemNode {
 
-    pub fn new(
-        name: String,
-        path: PathBuf,
-        is_file: bool,
-        size: u64,
-        parent: Option<Weak<Mutex<FileSystemNode>>>, // Use Rc<RefCell<>> for parent
-        children: Vec<Arc<Mutex<FileSystemNode>>>,  // Use Rc<RefCell<>> for children
-        to_be_deleted: bool) -> Self {
-            Self {
-                name,
-                path,
-                is_file,
-                size,
-                parent,
-                children,
-                to_be_deleted
-            }
+    /// A node that is not in any tree yet; `FileSystemTree::insert` gives it a name and a place.
+    pub fn new(is_file: bool, size: u64) -> Self {
+        Self {
+            name: NameId(0),
+            parent: None,
+            children: Vec::new(),
+            is_file,
+            to_be_deleted: false,
+            incomplete: false,
+            removed: false,
+            size,
+            allocated_size: 0,
+            excluded: 0,
+            metadata: FileMetadata::default(),
+            extra: None,
+        }
+    }
+
+    /// A node for something a scanner found.
+    pub fn from_scan_entry(entry: &ScanEntry) -> Self {
+        let mut node = Self::new(entry.is_file, entry.size);
+        node.allocated_size = entry.allocated_size;
+        node.metadata = entry.metadata;
+        node.set_symlink(entry.symlink.clone());
+        node
     }
 
-    pub fn get_name(&self) -> &str {
-        &self.name
+    fn update_extra(&mut self, update: impl FnOnce(&mut NodeExtra)) {
+        let mut extra = self.extra.take().unwrap_or_default();
+        update(&mut extra);
+        if !extra.is_empty() {
+            self.extra = Some(extra);
+        }
     }
 
-    pub fn set_name(&mut self, name: String) {
-        self.name = name;
+    pub fn is_file(&self) -> bool {
+        self.is_file
     }
 
-    pub fn get_path(&self) -> &PathBuf {
-        &self.path
+    pub fn kind(&self) -> NodeKind {
+        if self.is_symlink() {
+            NodeKind::Symlink
+        } else if self.is_file {
+            NodeKind::File
+        } else {
+            NodeKind::Directory
+        }
     }
 
-    pub fn set_path(&mut self, path: String) {
-        self.path = PathBuf::from(path);
+    pub fn set_symlink(&mut self, symlink: Option<SymlinkInfo>) {
+        self.update_extra(|extra| extra.symlink = symlink);
     }
 
-    pub fn is_file(&self) -> bool {
-        self.is_file
+    pub fn is_symlink(&self) -> bool {
+        self.symlink().is_some()
+    }
+
+    pub fn symlink(&self) -> Option<&SymlinkInfo> {
+        self.extra.as_ref().and_then(|extra| extra.symlink.as_ref())
     }
 
     pub fn size(&self) -> u64 {
@@ -64,69 +286,118 @@ impl FileSystemNode {
         self.size = size;
     }
 
-    pub fn get_parent(&self) -> Option<Weak<Mutex<FileSystemNode>>> {
-        self.parent.clone()
+    pub fn allocated_size(&self) -> u64 {
+        self.allocated_size
     }
 
-    pub fn set_parent(&mut self, parent: Option<Weak<Mutex<FileSystemNode>>>) {
-        self.parent = parent;
+    pub fn set_allocated_size(&mut self, allocated_size: u64) {
+        self.allocated_size = allocated_size;
     }
 
+    /// Set when another filesystem is mounted at this directory.
+    pub fn mount(&self) -> Option<&MountInfo> {
+        self.extra.as_ref().and_then(|extra| extra.mount.as_ref())
+    }
 
-    pub fn add_child(&mut self, child: Arc<Mutex<FileSystemNode>>){
-        self.children.push(child);
+    pub fn set_mount(&mut self, mount: Option<MountInfo>) {
+        self.update_extra(|extra| extra.mount = mount);
     }
-    pub fn for_each_child<F>(&self, mut function: F)
-    where
-        F: FnMut(usize, &Arc<Mutex<FileSystemNode>>),
-    {
-        for (i, child) in self.children.iter().enumerate() {
-            function(i, child);
-        }
+
+    pub fn is_mount_point(&self) -> bool {
+        self.mount().is_some()
     }
 
-    pub fn get_child(&self, index: usize) -> Option<Arc<Mutex<FileSystemNode>>> { 
-        if index >= self.children.len() {
-            return None;
-        }
+    /// Entries below this node that the exclude patterns kept out of the scan.
+    pub fn excluded_count(&self) -> u64 {
+        self.excluded
+    }
 
-        let child = self.children[index].clone();
-        Some(child)
+    pub fn set_excluded_count(&mut self, excluded: u64) {
+        self.excluded = excluded;
     }
 
-    pub fn remove_child(&mut self, path: String) {
-        // Find the index of the child to remove
-        let mut index = -1;
-        let mut i = 0;
-        while i < self.children_len() {
-            let s = self.children[i].lock().unwrap().get_path().to_string_lossy().to_string();
-            if s == path {
-                index = i as i16;
-                break;
-            }
-            i+=1;
-        }
+    /// What went wrong reading this very entry.
+    pub fn scan_errors(&self) -> &[ScanError] {
+        self.extra.as_ref().map(|extra| extra.scan_errors.as_slice()).unwrap_or(&[])
+    }
 
-        // Remove the child if found
-        if index != -1{
-            let size = self.children[index as usize].lock().unwrap().size();
-            self.children.remove(index as usize);
-            self.size -= size;
-        } else {
-            println!("Child with path '{}' not found.", path);
-        }
+    pub fn set_scan_errors(&mut self, scan_errors: Vec<ScanError>) {
+        self.update_extra(|extra| extra.scan_errors = scan_errors);
     }
 
-    pub fn go_to(&self, name: &str) -> Option<Arc<Mutex<FileSystemNode>>> {
-        for child in &self.children {
-            if child.lock().unwrap().get_name() == name {
-                return Some(child.clone());
-            }
-        }
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    pub fn set_incomplete(&mut self, incomplete: bool) {
+        self.incomplete = incomplete;
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: FileMetadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn modified(&self) -> i64 {
+        self.metadata.modified
+    }
+
+    pub fn accessed(&self) -> i64 {
+        self.metadata.accessed
+    }
+
+    pub fn changed(&self) -> i64 {
+        self.metadata.changed
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.metadata.uid
+    }
 
-        None
+    pub fn gid(&self) -> u32 {
+        self.metadata.gid
     }
 
+    pub fn mode(&self) -> u32 {
+        self.metadata.mode
+    }
+
+    pub fn inode(&self) -> u64 {
+        self.metadata.inode
+    }
+
+    pub fn device(&self) -> u64 {
+        self.metadata.device
+    }
+
+    pub fn nlink(&self) -> u64 {
+        self.metadata.nlink
+    }
+
+    /// (device, inode) pair identifying the data behind this node, shared by all of its hard links.
+    pub fn inode_key(&self) -> (u64, u64) {
+        (self.metadata.device, self.metadata.inode)
+    }
+
+    /// A file whose data is also reachable through another name.
+    pub fn is_hard_linked(&self) -> bool {
+        self.is_file && self.metadata.nlink > 1
+    }
+
+    pub fn get_parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    pub fn get_child(&self, index: usize) -> Option<NodeId> {
+        self.children.get(index).copied()
+    }
 
     pub fn is_marked(&self) -> bool {
         self.to_be_deleted
@@ -144,107 +415,1197 @@ impl FileSystemNode {
         self.to_be_deleted = false
     }
 }
-pub fn disown(node: Arc<Mutex<FileSystemNode>>) {
-    // Lock the node once to access its parent and path
-    let (parent_weak, node_path) = {
-        let node_ref = node.lock().unwrap();
-        (node_ref.parent.clone(), node_ref.get_path().to_string_lossy().to_string())
-    };
-
-    // If the parent exists, remove the node from the parent's children
-    if let Some(parent_rc) = parent_weak.and_then(|weak| weak.upgrade()) {
-        let mut parent_ref = parent_rc.lock().unwrap();
-        parent_ref.remove_child(node_path);
-    }
-}
-
-pub fn prune(node: Arc<Mutex<FileSystemNode>>) {
-    // Recursively prune children
-    let children = node.lock().unwrap().children.drain(..).collect::<Vec<_>>();
-    for child in children {
-        prune(child);
-    }
-    
-}
-
-pub async fn build_fs_model(path: String) -> Option<Arc<Mutex<FileSystemNode>>> {
-    // Initialize a map to store nodes by path
-    let mut nodes: HashMap<String, Arc<Mutex<FileSystemNode>>> = HashMap::new();
-    let root = Arc::new(Mutex::new(FileSystemNode {
-        name: path.clone(),
-        path: PathBuf::from(path.clone()),
-        is_file: false,
-        size: 0,
-        parent: None,
-        children: vec![],
-        to_be_deleted: false,
-    }));
-
-    nodes.insert(path.clone(), root.clone());
-    // Fetch the filesystem structure in parallel
-    let mut all_results = Vec::new();
-    let results = mac::fetch_file_system_with_getattrlistbulk_parallel(&path);
-    all_results.extend(results);
-    
-
-    // Populate nodes map with results
-    for (entry_path, size, is_file) in all_results {
-        let path_buf = PathBuf::from(&entry_path);
-        let name = path_buf
-            .file_name()
-            .map(|n| n.to_string_lossy().into_owned())
-            .unwrap_or_else(|| "/".to_string());
-
-        let node = Arc::new(Mutex::new(FileSystemNode {
-            name,
-            path: path_buf.clone(),
-            is_file,
-            size,
-            parent: None,
-            children: vec![],
-            to_be_deleted: false,
-        }));
 
-        nodes.insert(entry_path.clone(), node);
+/// The loaded model. Nodes live in one vector and refer to their parent and children by
+/// `NodeId`; paths are not stored but rebuilt from the names up to the root, whose name is the
+/// full path it was loaded from.
+#[derive(Debug)]
+pub struct FileSystemTree {
+    nodes: Vec<FileSystemNode>,
+    names: NameTable,
+    root_path: PathBuf,
+    // Worked out for the directories someone asked about, and dropped for every directory above
+    // a node as soon as that node changes
+    stats: HashMap<NodeId, DirectoryStats>,
+    // Children by parent and name, complete for every directory with more than `INDEXED_CHILDREN`
+    // entries. Smaller directories are cheaper to search than to index, but entries left over from
+    // when they were bigger stay correct.
+    index: HashMap<(NodeId, NameId), NodeId>,
+}
+
+const INDEXED_CHILDREN: usize = 256;
+
+/// Totals over everything below a directory.
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryStats {
+    pub files: u64,
+    /// Directories below, not counting the directory itself
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Levels below the directory: 0 when it is empty, 1 when nothing it holds has entries of its own
+    pub max_depth: u32,
+    /// The most and least recently modified entries below, with their modification time
+    pub newest: Option<(i64, NodeId)>,
+    pub oldest: Option<(i64, NodeId)>,
+    /// Files below by lowercase extension, `""` for names without one
+    pub extensions: HashMap<String, ExtensionTotals>,
+    // Hard-linked inodes below, with the extension and sizes they were charged to. Like the
+    // directory sizes, the totals charge an inode once however many names it has.
+    linked: HashMap<(u64, u64), (String, u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtensionTotals {
+    pub files: u64,
+    pub size: u64,
+    pub allocated_size: u64,
+}
+
+impl DirectoryStats {
+    fn add_modified(&mut self, modified: i64, id: NodeId) {
+        // Entries created from the prompt have no time of their own yet
+        if modified == 0 {
+            return;
+        }
+        if self.newest.is_none_or(|(newest, _)| modified > newest) {
+            self.newest = Some((modified, id));
+        }
+        if self.oldest.is_none_or(|(oldest, _)| modified < oldest) {
+            self.oldest = Some((modified, id));
+        }
+    }
+
+    fn add_file(&mut self, name: &str, node: &FileSystemNode) {
+        let extension = listing::extension(name).unwrap_or_default();
+        self.files += 1;
+        let totals = self.extensions.entry(extension.clone()).or_default();
+        totals.files += 1;
+        if node.is_hard_linked() {
+            if self.linked.contains_key(&node.inode_key()) {
+                return;
+            }
+            self.linked.insert(node.inode_key(), (extension, node.size, node.allocated_size));
+        }
+        totals.size += node.size;
+        totals.allocated_size += node.allocated_size;
+    }
+
+    fn add_directory(&mut self, below: &DirectoryStats) {
+        self.files += below.files;
+        self.directories += below.directories;
+        self.symlinks += below.symlinks;
+        self.max_depth = self.max_depth.max(below.max_depth + 1);
+        for (modified, id) in [below.newest, below.oldest].into_iter().flatten() {
+            self.add_modified(modified, id);
+        }
+        for (extension, totals) in &below.extensions {
+            let entry = self.extensions.entry(extension.clone()).or_default();
+            entry.files += totals.files;
+            entry.size += totals.size;
+            entry.allocated_size += totals.allocated_size;
+        }
+        for (key, linked) in &below.linked {
+            if !self.linked.contains_key(key) {
+                self.linked.insert(*key, linked.clone());
+                continue;
+            }
+            let (extension, size, allocated_size) = linked;
+            if let Some(entry) = self.extensions.get_mut(extension) {
+                entry.size -= size;
+                entry.allocated_size -= allocated_size;
+            }
+        }
+    }
+}
+
+impl FileSystemTree {
+    pub fn new(root_path: PathBuf, mut root: FileSystemNode) -> Self {
+        let mut names = NameTable::default();
+        root.name = names.intern(&root_path.to_string_lossy());
+        root.parent = None;
+        Self { nodes: vec![root], names, root_path, stats: HashMap::new(), index: HashMap::new() }
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Nodes ever created, removed ones included.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// False once the node has been removed from the tree.
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.get(id.index()).is_some_and(|node| !node.removed)
+    }
+
+    pub fn node(&self, id: NodeId) -> &FileSystemNode {
+        &self.nodes[id.index()]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut FileSystemNode {
+        self.forget_stats(id);
+        &mut self.nodes[id.index()]
+    }
+
+    // Whatever changes a node changes the totals of every directory above it
+    fn forget_stats(&mut self, id: NodeId) {
+        if self.stats.is_empty() {
+            return;
+        }
+        let mut current = Some(id);
+        while let Some(id) = current {
+            self.stats.remove(&id);
+            current = self.nodes[id.index()].parent;
+        }
+    }
+
+    /// Counts, depth, modification times and the per-extension breakdown below `id`. They are
+    /// worked out bottom-up the first time and kept until something below changes.
+    pub fn stats(&mut self, id: NodeId) -> &DirectoryStats {
+        // Every directory from `id` down that has no totals yet, each before what it contains
+        let mut pending = Vec::new();
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            if self.stats.contains_key(&current) {
+                continue;
+            }
+            pending.push(current);
+            stack.extend(self.node(current).children.iter().copied().filter(|&child| !self.node(child).is_file));
+        }
+
+        for &directory in pending.iter().rev() {
+            let mut stats = DirectoryStats::default();
+            for &child in &self.node(directory).children {
+                let node = self.node(child);
+                stats.max_depth = stats.max_depth.max(1);
+                stats.add_modified(node.modified(), child);
+                if node.is_symlink() {
+                    stats.symlinks += 1;
+                } else if !node.is_file {
+                    stats.directories += 1;
+                }
+                if !node.is_file {
+                    stats.add_directory(&self.stats[&child]);
+                } else if !node.is_symlink() {
+                    stats.add_file(self.get_name(child), node);
+                }
+            }
+            self.stats.insert(directory, stats);
+        }
+        &self.stats[&id]
+    }
+
+    pub fn get_name(&self, id: NodeId) -> &str {
+        self.names.get(self.node(id).name)
+    }
+
+    pub fn set_name(&mut self, id: NodeId, name: &str) {
+        let name = self.names.intern(name);
+        self.unindex(id);
+        self.node_mut(id).name = name;
+        if let Some(parent) = self.node(id).parent {
+            self.index_child(parent, id);
+        }
+    }
+
+    pub fn get_path(&self, id: NodeId) -> PathBuf {
+        let mut names = Vec::new();
+        let mut current = id;
+        while let Some(parent) = self.node(current).parent {
+            names.push(self.node(current).name);
+            current = parent;
+        }
+
+        let mut path = if current == self.root() {
+            self.root_path.clone()
+        } else {
+            PathBuf::from(self.get_name(current))
+        };
+        for name in names.iter().rev() {
+            path.push(self.names.get(*name));
+        }
+        path
+    }
+
+    pub fn get_parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn child_named(&self, id: NodeId, name: &str) -> Option<NodeId> {
+        let name = self.names.lookup(name)?;
+        let children = &self.node(id).children;
+        if children.len() > INDEXED_CHILDREN {
+            return self.index.get(&(id, name)).copied();
+        }
+        // A name queued for deletion can be taken by a new entry before the commit, which is then
+        // the one meant; it was added later, so the index holds it already
+        let mut named = children.iter().copied().filter(|child| self.node(*child).name == name);
+        let first = named.next()?;
+        if !self.node(first).is_marked() {
+            return Some(first);
+        }
+        Some(named.find(|child| !self.node(*child).is_marked()).unwrap_or(first))
+    }
+
+    // Call after adding `child` to the end of `parent`'s children
+    fn index_child(&mut self, parent: NodeId, child: NodeId) {
+        match self.nodes[parent.index()].children.len() {
+            len if len <= INDEXED_CHILDREN => {}
+            len if len == INDEXED_CHILDREN + 1 => self.reindex(parent),
+            _ => {
+                self.index.insert((parent, self.nodes[child.index()].name), child);
+            }
+        }
+    }
+
+    // Indexes every child of `id` if it has enough of them to need it
+    fn reindex(&mut self, id: NodeId) {
+        let children = &self.nodes[id.index()].children;
+        if children.len() > INDEXED_CHILDREN {
+            for &child in children {
+                self.index.insert((id, self.nodes[child.index()].name), child);
+            }
+        }
+    }
+
+    // Drops the entry for `id` under its current parent and name, if there is one
+    fn unindex(&mut self, id: NodeId) {
+        if self.index.is_empty() {
+            return;
+        }
+        let node = &self.nodes[id.index()];
+        if let Some(parent) = node.parent {
+            let key = (parent, node.name);
+            if self.index.get(&key) == Some(&id) {
+                self.index.remove(&key);
+            }
+        }
+    }
+
+    /// The closest node at or above `id` that is still in the tree.
+    pub fn nearest_existing(&self, mut id: NodeId) -> NodeId {
+        while !self.contains(id) {
+            match self.node(id).parent {
+                Some(parent) => id = parent,
+                None => return self.root(),
+            }
+        }
+        id
+    }
+
+    /// Every node from `id` down, each directory before what it contains.
+    pub fn walk(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        let mut stack = vec![id];
+        std::iter::from_fn(move || {
+            let current = stack.pop()?;
+            stack.extend(self.node(current).children.iter().rev());
+            Some(current)
+        })
+    }
+
+    /// Every node below `id` whose name `matches` accepts, each directory before what it
+    /// contains. Entries queued for deletion are left out along with everything below them.
+    /// Each distinct name is only tested once, however many entries share it.
+    pub fn find_named(&self, id: NodeId, matches: impl Fn(&str) -> bool) -> Vec<NodeId> {
+        let accepted: Vec<bool> = self.names.names.iter().map(|name| matches(name)).collect();
+        let mut found = Vec::new();
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            if current != id && accepted[self.node(current).name.0 as usize] {
+                found.push(current);
+            }
+            stack.extend(self.node(current).children.iter().rev().filter(|&&child| !self.node(child).to_be_deleted));
+        }
+        found
+    }
+
+    /// Adds `node` below `parent`, or on its own, leaving every size as it is. Meant for building
+    /// trees whose sizes are aggregated afterwards or were stored aggregated.
+    pub fn insert(&mut self, parent: Option<NodeId>, name: &str, mut node: FileSystemNode) -> Result<NodeId, String> {
+        node.name = self.names.intern(name);
+        node.parent = parent;
+        let id = self.allocate(node)?;
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(id);
+            self.index_child(parent, id);
+        }
+        Ok(id)
+    }
+
+    // Puts `node` in a new slot as long as there are IDs left
+    fn allocate(&mut self, node: FileSystemNode) -> Result<NodeId, String> {
+        let id = u32::try_from(self.nodes.len())
+            .map(NodeId)
+            .map_err(|_| format!("The model cannot hold more than {} entries.", u32::MAX))?;
+        self.nodes.push(node);
+        Ok(id)
+    }
+
+    fn link(&mut self, parent: NodeId, child: NodeId) {
+        self.node_mut(child).parent = Some(parent);
+        self.node_mut(parent).children.push(child);
+        self.index_child(parent, child);
+    }
+
+    // Hard-linked inodes from `id` down with the (size, allocated size) of their first link
+    fn linked_below(&self, id: NodeId) -> HashMap<(u64, u64), (u64, u64)> {
+        let mut linked = HashMap::new();
+        for current in self.walk(id) {
+            let node = self.node(current);
+            if node.is_file && node.is_hard_linked() {
+                linked.entry(node.inode_key()).or_insert((node.size, node.allocated_size));
+            }
+        }
+        linked
+    }
+
+    // Adds the totals of `id` to every directory above it, or takes them back when `sign` is -1.
+    // As in a scan, an inode that also has a link elsewhere below a directory is charged there already.
+    fn charge(&mut self, id: NodeId, sign: i64) {
+        let mut linked = self.linked_below(id);
+        let (mut size, mut allocated_size) = (self.node(id).size, self.node(id).allocated_size);
+        let mut below = id;
+        let mut current = self.node(id).parent;
+        while let Some(directory) = current {
+            if !linked.is_empty() {
+                for &other in self.node(directory).children.iter().filter(|&&child| child != below) {
+                    for link in self.walk(other) {
+                        let node = self.node(link);
+                        if !node.is_file || !node.is_hard_linked() {
+                            continue;
+                        }
+                        if let Some((linked_size, linked_allocated_size)) = linked.remove(&node.inode_key()) {
+                            size -= linked_size;
+                            allocated_size -= linked_allocated_size;
+                        }
+                    }
+                }
+            }
+            let node = self.node_mut(directory);
+            node.size = node.size.saturating_add_signed(sign * size as i64);
+            node.allocated_size = node.allocated_size.saturating_add_signed(sign * allocated_size as i64);
+            below = directory;
+            current = node.parent;
+        }
+    }
+
+    /// Gives the file `id` a new size and metadata and moves the totals above it by the difference.
+    /// Every other link to the same inode changes with it, and each directory is moved once.
+    pub fn resize(&mut self, id: NodeId, size: u64, allocated_size: u64, metadata: FileMetadata) {
+        let node = self.node(id);
+        let key = node.inode_key();
+        // A different inode now, or the only link to it
+        if !node.is_hard_linked() || (metadata.device, metadata.inode) != key {
+            self.charge(id, -1);
+            let node = self.node_mut(id);
+            (node.size, node.allocated_size, node.metadata) = (size, allocated_size, metadata);
+            self.charge(id, 1);
+            return;
+        }
+
+        let (size_delta, allocated_delta) = (size as i64 - node.size as i64, allocated_size as i64 - node.allocated_size as i64);
+        let links: Vec<NodeId> = self
+            .walk(self.root())
+            .filter(|&other| self.node(other).is_file && self.node(other).inode_key() == key)
+            .collect();
+        let mut above = HashSet::new();
+        for &link in &links {
+            let mut current = self.node(link).parent;
+            while let Some(directory) = current.filter(|&directory| above.insert(directory)) {
+                current = self.node(directory).parent;
+            }
+        }
+        for directory in above {
+            let node = self.node_mut(directory);
+            node.size = node.size.saturating_add_signed(size_delta);
+            node.allocated_size = node.allocated_size.saturating_add_signed(allocated_delta);
+        }
+        for link in links {
+            let node = self.node_mut(link);
+            (node.size, node.allocated_size, node.metadata) = (size, allocated_size, metadata);
+        }
+    }
+
+    /// Adds the unattached `child` below `parent` and charges its sizes to every directory up to the root.
+    pub fn attach(&mut self, parent: NodeId, child: NodeId) {
+        self.link(parent, child);
+        self.charge(child, 1);
+    }
+
+    /// Unlinks `id` from its parent, along with its sizes from every directory up to the root.
+    /// The node keeps what is below it and can be attached somewhere else.
+    pub fn detach(&mut self, id: NodeId) {
+        if let Some(parent) = self.node(id).parent {
+            self.unlink(id, parent);
+            self.node_mut(id).parent = None;
+        }
+    }
+
+    fn unlink(&mut self, id: NodeId, parent: NodeId) {
+        self.charge(id, -1);
+        self.unindex(id);
+        self.node_mut(parent).children.retain(|child| *child != id);
+    }
+
+    /// Takes `id` and everything below it out of the tree for good, with its sizes. The node
+    /// remembers its parent so its path can still be reported.
+    pub fn remove(&mut self, id: NodeId) {
+        if let Some(parent) = self.node(id).parent {
+            self.unlink(id, parent);
+        }
+        self.discard(id);
+    }
+
+    // Marks a subtree removed and frees what its nodes own, leaving everything above it alone
+    fn discard(&mut self, id: NodeId) {
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            self.unindex(current);
+            let node = self.node_mut(current);
+            node.removed = true;
+            node.extra = None;
+            stack.extend(mem::take(&mut node.children));
+        }
+    }
+
+    /// Copies `id` and everything below it from `other` into this tree, unattached.
+    pub fn adopt(&mut self, other: &FileSystemTree, id: NodeId) -> Result<NodeId, String> {
+        self.adopt_named(other, id, other.get_name(id))
+    }
+
+    /// Copies all of `other` below `parent`, naming its root `name`, and charges its sizes to
+    /// every directory up to the root.
+    pub fn graft(&mut self, parent: NodeId, name: &str, other: &FileSystemTree) -> Result<NodeId, String> {
+        let id = self.adopt_named(other, other.root(), name)?;
+        self.attach(parent, id);
+        Ok(id)
+    }
+
+    // A copy that runs out of IDs part way is removed again, so nothing is left half done
+    fn adopt_named(&mut self, other: &FileSystemTree, id: NodeId, name: &str) -> Result<NodeId, String> {
+        let top = self.copy_node(other, id, name, None)?;
+        let mut stack = vec![(id, top)];
+        while let Some((from, to)) = stack.pop() {
+            for &child in other.node(from).children.iter() {
+                let copy = match self.copy_node(other, child, other.get_name(child), Some(to)) {
+                    Ok(copy) => copy,
+                    Err(err) => {
+                        self.discard(top);
+                        return Err(err);
+                    }
+                };
+                self.node_mut(to).children.push(copy);
+                self.index_child(to, copy);
+                stack.push((child, copy));
+            }
+        }
+        Ok(top)
+    }
+
+    fn copy_node(&mut self, other: &FileSystemTree, id: NodeId, name: &str, parent: Option<NodeId>) -> Result<NodeId, String> {
+        let source = other.node(id);
+        let node = FileSystemNode {
+            name: self.names.intern(name),
+            parent,
+            children: Vec::with_capacity(source.children.len()),
+            is_file: source.is_file,
+            to_be_deleted: source.to_be_deleted,
+            incomplete: source.incomplete,
+            removed: false,
+            size: source.size,
+            allocated_size: source.allocated_size,
+            excluded: source.excluded,
+            metadata: source.metadata,
+            extra: source.extra.clone(),
+        };
+        self.allocate(node)
+    }
+}
+
+/// A tree holding only `path`, which is on disk with `metadata`. Directories are scanned in full.
+pub fn build_node(path: &Path, metadata: &fs::Metadata, scanner: &dyn Scanner, options: &ScanOptions) -> Option<FileSystemTree> {
+    let path_string = path.to_string_lossy().into_owned();
+    path.file_name()?;
+
+    if metadata.is_dir() || (options.follow_symlinks && metadata.is_symlink() && path.is_dir()) {
+        return build_fs_model_blocking(path_string, scanner, options.clone(), &ScanProgress::new());
+    }
+
+    let mut entry = ScanEntry::new(path_string, metadata.len(), true)
+        .with_allocated_size(metadata.blocks() * 512)
+        .with_metadata(FileMetadata::from(metadata));
+    if metadata.is_symlink() {
+        if let Ok(target) = fs::read_link(path) {
+            entry = entry.with_symlink(SymlinkInfo { target, broken: fs::metadata(path).is_err() });
+        }
+    }
+    Some(FileSystemTree::new(path.to_path_buf(), FileSystemNode::from_scan_entry(&entry)))
+}
+
+/// Paths where the model and the disk are expected to disagree because of queued actions.
+#[derive(Debug, Default)]
+pub struct PendingPaths {
+    /// Created or moved in by a queued action, so not on disk yet
+    pub model_only: Vec<PathBuf>,
+    /// Moved away by a queued action, so still only on disk
+    pub disk_only: Vec<PathBuf>,
+}
+
+impl PendingPaths {
+    /// A node missing from disk stays when a queued action put it, or something below it, there
+    pub fn keeps(&self, path: &Path) -> bool {
+        self.model_only.iter().any(|pending| pending.starts_with(path))
+    }
+
+    /// Whatever the disk still has below a path a queued move takes away is not brought back
+    pub fn skips(&self, path: &Path) -> bool {
+        self.disk_only.iter().any(|pending| path.starts_with(pending))
+    }
+}
+
+/// What `reconcile` changed.
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    pub added: u64,
+    pub removed: u64,
+    pub updated: u64,
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} added, {} removed, {} updated", self.added, self.removed, self.updated)
+    }
+}
+
+/// Folds `fresh`, a new scan of the same directory, into `existing`. Nodes whose path did not
+/// change keep their IDs and are updated in place, so anything holding on to them stays valid.
+/// Entries in `pending` are left as the queued actions made them, and the ancestors of
+/// `existing` are adjusted by however much its totals moved.
+pub fn reconcile(tree: &mut FileSystemTree, existing: NodeId, fresh: &FileSystemTree, pending: &PendingPaths) -> Result<Reconciliation, String> {
+    // The old totals come off the ancestors and the new ones go back on, hard links counted as a scan counts them
+    tree.charge(existing, -1);
+    let mut report = Reconciliation::default();
+    let reconciled = reconcile_node(tree, existing, fresh, fresh.root(), fresh.root_path(), pending, true, &mut report);
+    tree.charge(existing, 1);
+    reconciled.map(|_| report)
+}
+
+// Returns the totals `existing` ends up with: the fresh ones, corrected for the children that
+// were kept from the model or skipped from the scan
+#[allow(clippy::too_many_arguments)]
+fn reconcile_node(
+    tree: &mut FileSystemTree,
+    existing: NodeId,
+    fresh: &FileSystemTree,
+    fresh_id: NodeId,
+    fresh_path: &Path,
+    pending: &PendingPaths,
+    is_top: bool,
+    report: &mut Reconciliation,
+) -> Result<(u64, u64), String> {
+    let fresh_node = fresh.node(fresh_id);
+    let mut size = fresh_node.size;
+    let mut allocated_size = fresh_node.allocated_size;
+
+    let mut fresh_children: Vec<Option<NodeId>> = Vec::new();
+    let mut fresh_names: HashMap<&str, usize> = HashMap::new();
+    for &child in fresh_node.children.iter() {
+        let name = fresh.get_name(child);
+        if pending.skips(&fresh_path.join(name)) {
+            size = size.saturating_sub(fresh.node(child).size);
+            allocated_size = allocated_size.saturating_sub(fresh.node(child).allocated_size);
+            continue;
+        }
+        fresh_names.insert(name, fresh_children.len());
+        fresh_children.push(Some(child));
+    }
+
+    let existing_path = tree.get_path(existing);
+    let existing_children = tree.node(existing).children.clone();
+    let mut children = Vec::new();
+
+    for child in existing_children {
+        let name = tree.get_name(child).to_string();
+        let (is_file, child_size, child_allocated_size) = {
+            let node = tree.node(child);
+            (node.is_file, node.size, node.allocated_size)
+        };
+        let matching = fresh_names.get(name.as_str()).and_then(|&position| fresh_children[position].take());
+
+        match matching {
+            Some(fresh_child) if fresh.node(fresh_child).is_file == is_file => {
+                let (fresh_size, fresh_allocated_size) = (fresh.node(fresh_child).size, fresh.node(fresh_child).allocated_size);
+                let (new_size, new_allocated_size) =
+                    reconcile_node(tree, child, fresh, fresh_child, &fresh_path.join(&name), pending, false, report)?;
+                size = size.saturating_sub(fresh_size) + new_size;
+                allocated_size = allocated_size.saturating_sub(fresh_allocated_size) + new_allocated_size;
+                children.push(child);
+            }
+            // Replaced by something of the other kind
+            Some(fresh_child) => {
+                tree.discard(child);
+                let copy = tree.adopt(fresh, fresh_child)?;
+                tree.node_mut(copy).parent = Some(existing);
+                children.push(copy);
+                report.removed += 1;
+                report.added += 1;
+            }
+            None if pending.keeps(&existing_path.join(&name)) => {
+                size += child_size;
+                allocated_size += child_allocated_size;
+                children.push(child);
+            }
+            None => {
+                tree.discard(child);
+                report.removed += 1;
+            }
+        }
+    }
+
+    for fresh_child in fresh_children.into_iter().flatten() {
+        let copy = tree.adopt(fresh, fresh_child)?;
+        tree.node_mut(copy).parent = Some(existing);
+        children.push(copy);
+        report.added += 1;
+    }
+
+    let node = tree.node_mut(existing);
+    let changed = node.size != size
+        || node.metadata.modified != fresh_node.metadata.modified
+        || node.metadata.inode != fresh_node.metadata.inode;
+    if changed && node.is_file {
+        report.updated += 1;
     }
 
-    // Link children to their parent nodes
-    for (entry_path, node) in &nodes {
-        let path_buf = PathBuf::from(entry_path);
-        if let Some(parent_path) = path_buf.parent() {
-            let parent_path_str = parent_path.to_string_lossy().to_string();
-            if let Some(parent_node) = nodes.get(&parent_path_str) {
-                let mut parent_lock = parent_node.lock().unwrap();
-                let mut node_lock = node.lock().unwrap();
-                node_lock.parent = Some(Arc::downgrade(parent_node));
-                parent_lock.children.push(Arc::clone(node));
+    node.size = size;
+    node.allocated_size = allocated_size;
+    node.metadata = fresh_node.metadata;
+    node.excluded = fresh_node.excluded;
+    node.incomplete = fresh_node.incomplete;
+    node.set_symlink(fresh_node.symlink().cloned());
+    node.set_scan_errors(fresh_node.scan_errors().to_vec());
+    // The top of a rescan has no parent in the fresh tree to compare devices with
+    if !is_top || fresh_node.mount().is_some() {
+        node.set_mount(fresh_node.mount().cloned());
+    }
+    node.children = children;
+    tree.reindex(existing);
+
+    Ok((size, allocated_size))
+}
+
+/// Scans `path` on a blocking thread and builds the tree from what was found. The scan reports
+/// into `progress`, and cancelling it makes this return `None`.
+pub async fn build_fs_model(
+    path: String,
+    scanner: Arc<dyn Scanner>,
+    options: &ScanOptions,
+    progress: Arc<ScanProgress>,
+) -> Option<FileSystemTree> {
+    let options = options.clone();
+    tokio::task::spawn_blocking(move || build_fs_model_blocking(path, scanner.as_ref(), options, &progress))
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Scan task failed: {}", err);
+            None
+        })
+}
+
+/// `build_fs_model` on the calling thread.
+pub fn build_fs_model_blocking(path: String, scanner: &dyn Scanner, mut options: ScanOptions, progress: &ScanProgress) -> Option<FileSystemTree> {
+    // Patterns from the root's own ignore file come after the ones given on the command line
+    let ignore_file = Path::new(&path).join(IGNORE_FILE);
+    if ignore_file.is_file() {
+        if let Err(err) = options.exclude.add_file(&ignore_file) {
+            eprintln!("Failed to read {}: {}", ignore_file.display(), err);
+        }
+    }
+
+    // Fetch the filesystem structure in parallel, building the tree as directories come in
+    let started = Instant::now();
+    let builder = Mutex::new(TreeBuilder::new(&path));
+    scanner.scan(&path, &options, progress, &builder);
+    if progress.is_cancelled() {
+        println!("Scan of {} cancelled after {:.2?}", path, started.elapsed());
+        return None;
+    }
+
+    let builder = builder.into_inner().unwrap();
+    println!(
+        "Scanned {} entries with {} in {:.2?} ({} excluded)",
+        builder.entries,
+        scanner.name(),
+        started.elapsed(),
+        builder.excluded
+    );
+    if builder.errors > 0 {
+        println!("{} paths could not be read, run `errors` to list them", builder.errors);
+    }
+    Some(builder.finish_all())
+}
+
+// Builds the tree while a scan runs. A directory completes once the scanner is done with it and
+// every directory below it has completed, which is when its totals are rolled up into its parent.
+struct TreeBuilder {
+    tree: FileSystemTree,
+    // Directories the scanner may still add to, by path
+    paths: HashMap<String, NodeId>,
+    pending: HashMap<NodeId, PendingDirectory>,
+    volumes: HashMap<PathBuf, MountInfo>,
+    entries: usize,
+    excluded: usize,
+    errors: usize,
+    // Set once the tree has run out of IDs
+    full: bool,
+}
+
+#[derive(Default)]
+struct PendingDirectory {
+    finished: bool,
+    // Child directories that have not completed yet
+    remaining: usize,
+    // Hard-linked inodes below this directory with their (size, allocated size). Every total
+    // charges such an inode once, however many links it contains.
+    linked: HashMap<(u64, u64), (u64, u64)>,
+}
+
+impl TreeBuilder {
+    fn new(path: &str) -> Self {
+        let root_metadata = fs::symlink_metadata(path).ok();
+        let mut root = FileSystemNode::new(false, 0);
+        root.allocated_size = root_metadata.as_ref().map(|metadata| metadata.blocks() * 512).unwrap_or(0);
+        root.metadata = root_metadata.as_ref().map(FileMetadata::from).unwrap_or_default();
+
+        let volumes = mounted_volumes();
+        if let Some(volume) = volumes.get(Path::new(path)) {
+            root.set_mount(Some(volume.clone()));
+        }
+
+        let tree = FileSystemTree::new(PathBuf::from(path), root);
+        let root = tree.root();
+        Self {
+            tree,
+            paths: HashMap::from([(path.to_string(), root)]),
+            pending: HashMap::from([(root, PendingDirectory::default())]),
+            volumes,
+            entries: 0,
+            excluded: 0,
+            errors: 0,
+            full: false,
+        }
+    }
+
+    fn add(&mut self, directory: &str, listing: DirectoryListing) {
+        let Some(&parent) = self.paths.get(directory) else {
+            return;
+        };
+        self.entries += listing.entries.len();
+        self.excluded += listing.excluded.len();
+        self.tree.node_mut(parent).excluded += listing.excluded.len() as u64;
+
+        for entry in listing.entries {
+            let node = FileSystemNode::from_scan_entry(&entry);
+            let name = Path::new(&entry.path).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            let id = match self.tree.insert(Some(parent), &name, node) {
+                Ok(id) => id,
+                // The rest of the listing cannot be held either, so the directory stays a lower bound
+                Err(err) => {
+                    if !self.full {
+                        eprintln!("{} Entries past that are left out.", err);
+                        self.full = true;
+                    }
+                    self.tree.node_mut(parent).incomplete = true;
+                    break;
+                }
+            };
+
+            let node = self.tree.node(id);
+            if node.is_file {
+                let (size, allocated_size) = (node.size, node.allocated_size);
+                if node.is_hard_linked() {
+                    let key = node.inode_key();
+                    if self.pending.get_mut(&parent).unwrap().linked.insert(key, (size, allocated_size)).is_some() {
+                        continue;
+                    }
+                }
+                let parent = self.tree.node_mut(parent);
+                parent.size += size;
+                parent.allocated_size += allocated_size;
+                continue;
+            }
+
+            // A directory is a mount point when it is listed as one, or when it lives on another device
+            // than its parent. Checking the device as well catches the pseudo filesystems sysinfo skips.
+            if !node.is_symlink() {
+                let crosses_device = self.tree.node(parent).metadata.device != node.metadata.device;
+                if let Some(volume) = self.volumes.get(Path::new(&entry.path)) {
+                    self.tree.node_mut(id).set_mount(Some(volume.clone()));
+                } else if crosses_device {
+                    self.tree.node_mut(id).set_mount(Some(MountInfo::from_statvfs(Path::new(&entry.path))));
+                }
+            }
+            self.pending.get_mut(&parent).unwrap().remaining += 1;
+            self.pending.insert(id, PendingDirectory::default());
+            self.paths.insert(entry.path, id);
+        }
+
+        // Keep every error on the directory it concerns, or the one whose listing reported it
+        self.errors += listing.errors.len();
+        for error in listing.errors {
+            let id = self.paths.get(&error.path).copied().unwrap_or(parent);
+            self.tree.node_mut(id).update_extra(|extra| extra.scan_errors.push(error));
+        }
+    }
+
+    fn finish(&mut self, directory: &str) {
+        let Some(id) = self.paths.remove(directory) else {
+            return;
+        };
+        let pending = self.pending.get_mut(&id).unwrap();
+        pending.finished = true;
+        if pending.remaining == 0 {
+            self.complete(id);
+        }
+    }
+
+    // Rolls a completed directory into its parent, and on up for as long as that completes the parent too
+    fn complete(&mut self, id: NodeId) {
+        let mut current = id;
+        loop {
+            let completed = self.pending.remove(&current).unwrap();
+            let node = self.tree.node_mut(current);
+            node.incomplete |= !node.scan_errors().is_empty();
+            let (size, allocated_size, excluded, incomplete) = (node.size, node.allocated_size, node.excluded, node.incomplete);
+            let Some(parent) = node.parent else {
+                return;
+            };
+
+            let pending = self.pending.get_mut(&parent).unwrap();
+            let parent_node = self.tree.node_mut(parent);
+            parent_node.size += size;
+            parent_node.allocated_size += allocated_size;
+            parent_node.excluded += excluded;
+            parent_node.incomplete |= incomplete;
+
+            // The child's total already charged these once, so take back any we have seen before
+            for (key, sizes) in completed.linked {
+                match pending.linked.entry(key) {
+                    Entry::Occupied(_) => {
+                        parent_node.size -= sizes.0;
+                        parent_node.allocated_size -= sizes.1;
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(sizes);
+                    }
+                }
+            }
+
+            pending.remaining -= 1;
+            if !pending.finished || pending.remaining > 0 {
+                return;
             }
+            current = parent;
         }
     }
 
-    // Aggregate directory sizes
-    populate_size(root.clone());
-    Some(root)
+    // Directories the scanner never got to, like unreadable ones, count as empty
+    fn finish_all(mut self) -> FileSystemTree {
+        let unfinished: Vec<String> = self.paths.keys().cloned().collect();
+        for directory in unfinished {
+            self.finish(&directory);
+        }
+        // The entry count is only known now, so drop what growing the arena left spare
+        self.tree.nodes.shrink_to_fit();
+        self.tree
+    }
 }
 
-pub fn populate_size(node: Arc<Mutex<FileSystemNode>>) {
-    let mut total_size = node.lock().unwrap().size;
+impl ScanSink for Mutex<TreeBuilder> {
+    fn add(&self, directory: &str, listing: DirectoryListing) {
+        self.lock().unwrap().add(directory, listing);
+    }
+
+    fn finish(&self, directory: &str) {
+        self.lock().unwrap().finish(directory);
+    }
+
+    fn directory(&self, directory: &str, listing: DirectoryListing) {
+        let mut builder = self.lock().unwrap();
+        builder.add(directory, listing);
+        builder.finish(directory);
+    }
+}
 
-    // Lock the node once to get the children
-    let children: Vec<Arc<Mutex<FileSystemNode>>> = {
-        let locked_node = node.lock().unwrap();
-        locked_node.children.clone() // Clone the child references to avoid holding the lock
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::MemoryScanner;
 
-    // Recursively populate size for each child
-    for child in children {
-        populate_size(child.clone());
-        total_size += child.lock().unwrap().size; // Accumulate the size
+    fn file(path: &str, size: u64) -> ScanEntry {
+        ScanEntry::new(path.to_string(), size, true)
     }
 
-    // Lock the node again to update its size
-    {
-        let mut locked_node = node.lock().unwrap();
-        locked_node.size = total_size;
+    fn directory(path: &str) -> ScanEntry {
+        ScanEntry::new(path.to_string(), 0, false)
     }
-}
\ No newline at end of file
+
+    // A file whose data is shared with another name, as (device, inode) 1:42 with two links
+    fn linked(path: &str, size: u64) -> ScanEntry {
+        let metadata = FileMetadata { device: 1, inode: 42, nlink: 2, ..FileMetadata::default() };
+        file(path, size).with_metadata(metadata)
+    }
+
+    fn build(entries: Vec<ScanEntry>) -> FileSystemTree {
+        let scanner = MemoryScanner::new(entries);
+        build_fs_model_blocking("/memory".to_string(), &scanner, ScanOptions::default(), &ScanProgress::new()).unwrap()
+    }
+
+    fn lookup(tree: &FileSystemTree, path: &str) -> NodeId {
+        path.split('/').fold(tree.root(), |id, name| tree.child_named(id, name).unwrap())
+    }
+
+    #[test]
+    fn nested_directories_roll_their_sizes_up() {
+        let tree = build(vec![
+            directory("/memory/a"),
+            directory("/memory/a/b"),
+            file("/memory/a/b/deep", 300),
+            file("/memory/a/shallow", 20),
+            directory("/memory/empty"),
+            file("/memory/top", 1),
+        ]);
+
+        assert_eq!(tree.node(lookup(&tree, "a/b")).size(), 300);
+        assert_eq!(tree.node(lookup(&tree, "a")).size(), 320);
+        assert_eq!(tree.node(lookup(&tree, "empty")).size(), 0);
+        assert_eq!(tree.node(tree.root()).size(), 321);
+        assert_eq!(tree.node(tree.root()).allocated_size(), 321);
+        assert_eq!(tree.get_path(lookup(&tree, "a/b/deep")), PathBuf::from("/memory/a/b/deep"));
+    }
+
+    #[test]
+    fn removed_ids_are_never_reused() {
+        let mut tree = build(vec![
+            directory("/memory/a"),
+            directory("/memory/a/b"),
+            file("/memory/a/b/f", 1),
+            file("/memory/g", 2),
+        ]);
+        let (a, b, f, g) = (lookup(&tree, "a"), lookup(&tree, "a/b"), lookup(&tree, "a/b/f"), lookup(&tree, "g"));
+        let slots = tree.node_count();
+        tree.remove(a);
+        tree.remove(g);
+
+        let created: Vec<NodeId> = ["a", "g", "x", "y"]
+            .iter()
+            .map(|name| tree.insert(Some(tree.root()), name, FileSystemNode::new(true, 0)).unwrap())
+            .collect();
+        assert_eq!(tree.node_count(), slots + created.len());
+        for old in [a, b, f, g] {
+            assert!(!tree.contains(old));
+            assert!(!created.contains(&old));
+        }
+        // Removed nodes still know where they were
+        assert_eq!(tree.get_path(f), PathBuf::from("/memory/a/b/f"));
+        assert_eq!(tree.get_path(g), PathBuf::from("/memory/g"));
+    }
+
+    #[test]
+    fn hard_links_are_charged_once_per_total() {
+        let tree = build(vec![
+            directory("/memory/a"),
+            directory("/memory/b"),
+            linked("/memory/a/one", 100),
+            linked("/memory/a/two", 100),
+            linked("/memory/b/three", 100),
+            file("/memory/b/plain", 5),
+        ]);
+
+        // Both links in `a` share one inode, and `b` holds a third link to it
+        assert_eq!(tree.node(lookup(&tree, "a")).size(), 100);
+        assert_eq!(tree.node(lookup(&tree, "b")).size(), 105);
+        assert_eq!(tree.node(tree.root()).size(), 105);
+        assert_eq!(tree.node(tree.root()).allocated_size(), 105);
+    }
+
+    #[test]
+    fn stats_count_what_is_below_and_charge_each_inode_once() {
+        let modified = |path: &str, size: u64, modified: i64| file(path, size).with_metadata(FileMetadata { modified, ..FileMetadata::default() });
+        let entries = vec![
+            directory("/memory/docs"),
+            modified("/memory/docs/a.txt", 100, 10),
+            modified("/memory/docs/B.TXT", 50, 30),
+            directory("/memory/docs/deep"),
+            modified("/memory/docs/deep/raw", 7, 20),
+            file("/memory/docs/shortcut", 0).with_symlink(SymlinkInfo { target: PathBuf::from("/memory/docs/a.txt"), broken: false }),
+            directory("/memory/media"),
+            linked("/memory/media/x.bin", 1000),
+            directory("/memory/copies"),
+            linked("/memory/copies/y.bin", 1000),
+            directory("/memory/cache"),
+            file("/memory/cache/huge.iso", 5000),
+        ];
+        let mut options = ScanOptions::default();
+        options.exclude.add("cache").unwrap();
+        let mut tree = build_fs_model_blocking("/memory".to_string(), &MemoryScanner::new(entries), options, &ScanProgress::new()).unwrap();
+        let (root, media, a, b) = (tree.root(), lookup(&tree, "media"), lookup(&tree, "docs/a.txt"), lookup(&tree, "docs/B.TXT"));
+
+        let stats = tree.stats(root).clone();
+        assert_eq!((stats.files, stats.directories, stats.symlinks, stats.max_depth), (5, 4, 1, 3));
+        assert_eq!((stats.newest, stats.oldest), (Some((30, b)), Some((10, a))));
+        let totals = |extension: &str| stats.extensions.get(extension).map(|totals| (totals.files, totals.size, totals.allocated_size));
+        assert_eq!(totals("txt"), Some((2, 150, 150)));
+        assert_eq!(totals(""), Some((1, 7, 7)));
+        assert_eq!(totals("bin"), Some((2, 1000, 1000)));
+        assert_eq!(totals("iso"), None);
+        assert_eq!(stats.extensions.values().map(|totals| totals.size).sum::<u64>(), tree.node(root).size());
+
+        assert_eq!(tree.stats(media).extensions["bin"].size, 1000);
+        let docs = tree.stats(lookup(&tree, "docs")).clone();
+        assert_eq!((docs.files, docs.directories, docs.symlinks, docs.max_depth), (3, 1, 1, 2));
+        assert_eq!(tree.stats(lookup(&tree, "docs/deep")).max_depth, 1);
+
+        // Totals are worked out again once something below changes
+        tree.remove(a);
+        let raw = lookup(&tree, "docs/deep/raw");
+        let stats = tree.stats(root);
+        assert_eq!((stats.files, stats.oldest), (4, Some((20, raw))));
+        assert_eq!(stats.extensions["txt"].size, 50);
+    }
+
+    // Every child of every directory can be found by its name, through the index where there is one
+    fn assert_names_resolve(tree: &FileSystemTree) {
+        for directory in tree.walk(tree.root()).filter(|&id| !tree.node(id).is_file) {
+            for &child in tree.node(directory).children() {
+                assert_eq!(tree.child_named(directory, tree.get_name(child)), Some(child), "{}", tree.get_path(child).display());
+            }
+        }
+    }
+
+    #[test]
+    fn large_directories_stay_indexed_across_changes() {
+        let mut entries = vec![directory("/memory/big"), directory("/memory/small")];
+        entries.extend((0..300).map(|n| file(&format!("/memory/big/f{}", n), 1)));
+        let mut tree = build(entries);
+        let (big, small) = (lookup(&tree, "big"), lookup(&tree, "small"));
+        assert_names_resolve(&tree);
+
+        let created = tree.insert(Some(big), "new", FileSystemNode::new(true, 0)).unwrap();
+        assert_eq!(tree.child_named(big, "new"), Some(created));
+
+        let renamed = lookup(&tree, "big/f5");
+        tree.set_name(renamed, "renamed");
+        assert_eq!(tree.child_named(big, "f5"), None);
+        assert_eq!(tree.child_named(big, "renamed"), Some(renamed));
+
+        let moved = lookup(&tree, "big/f6");
+        tree.detach(moved);
+        tree.attach(small, moved);
+        assert_eq!(tree.child_named(big, "f6"), None);
+        assert_eq!(tree.child_named(small, "f6"), Some(moved));
+        assert_names_resolve(&tree);
+
+        // Down to the size that is searched rather than indexed, then past it again
+        for n in 7..51 {
+            tree.remove(lookup(&tree, &format!("big/f{}", n)));
+        }
+        assert_eq!(tree.node(big).children_len(), INDEXED_CHILDREN);
+        assert_eq!(tree.child_named(big, "f7"), None);
+        let shrunk = lookup(&tree, "big/f60");
+        tree.set_name(shrunk, "f7");
+        assert_names_resolve(&tree);
+
+        tree.detach(moved);
+        tree.attach(big, moved);
+        for n in 0..10 {
+            tree.insert(Some(big), &format!("g{}", n), FileSystemNode::new(true, 0)).unwrap();
+        }
+        assert_eq!(tree.node(big).children_len(), INDEXED_CHILDREN + 11);
+        assert_eq!(tree.child_named(big, "f7"), Some(shrunk));
+        assert_eq!(tree.child_named(big, "f60"), None);
+        assert_eq!(tree.child_named(big, "f6"), Some(moved));
+        assert_eq!(tree.child_named(small, "f6"), None);
+        assert_names_resolve(&tree);
+
+        // A whole indexed directory moved elsewhere keeps its index
+        tree.detach(big);
+        tree.attach(small, big);
+        assert_eq!(lookup(&tree, "small/big/g9"), tree.child_named(big, "g9").unwrap());
+        assert_names_resolve(&tree);
+    }
+
+    #[test]
+    fn grafted_hard_links_are_charged_once() {
+        let mut tree = build(vec![directory("/memory/a"), linked("/memory/a/one", 100), file("/memory/plain", 5)]);
+        let root = tree.root();
+        let other = build(vec![linked("/memory/two", 100), file("/memory/own", 1)]);
+
+        // The grafted directory holds the only link below itself, but the root already has the inode
+        let grafted = tree.graft(root, "b", &other).unwrap();
+        assert_eq!(tree.node(grafted).size(), 101);
+        assert_eq!(tree.node(root).size(), 106);
+
+        // Moving a link next to the other one leaves the totals above both alone
+        let two = lookup(&tree, "b/two");
+        tree.detach(two);
+        assert_eq!(tree.node(grafted).size(), 1);
+        assert_eq!(tree.node(root).size(), 106);
+        tree.attach(lookup(&tree, "a"), two);
+        assert_eq!(tree.node(lookup(&tree, "a")).size(), 100);
+        assert_eq!(tree.node(root).size(), 106);
+
+        tree.remove(lookup(&tree, "a/one"));
+        assert_eq!(tree.node(root).size(), 106);
+        tree.remove(two);
+        assert_eq!(tree.node(root).size(), 6);
+        assert_eq!(tree.node(root).allocated_size(), 6);
+    }
+
+    #[test]
+    fn resizing_a_hard_link_resizes_the_inode_once() {
+        let mut tree = build(vec![
+            directory("/memory/a"),
+            directory("/memory/b"),
+            linked("/memory/a/one", 100),
+            linked("/memory/b/two", 100),
+        ]);
+        let (one, two) = (lookup(&tree, "a/one"), lookup(&tree, "b/two"));
+        let metadata = *tree.node(one).metadata();
+        tree.resize(one, 250, 256, metadata);
+
+        assert_eq!((tree.node(one).size(), tree.node(two).size()), (250, 250));
+        assert_eq!(tree.node(lookup(&tree, "a")).size(), 250);
+        assert_eq!(tree.node(lookup(&tree, "b")).size(), 250);
+        assert_eq!(tree.node(tree.root()).size(), 250);
+        assert_eq!(tree.node(tree.root()).allocated_size(), 256);
+
+        // Replaced by a file of its own, the other link keeps the old inode
+        let replaced = FileMetadata { inode: 43, nlink: 1, ..metadata };
+        tree.resize(two, 10, 10, replaced);
+        assert_eq!(tree.node(lookup(&tree, "b")).size(), 10);
+        assert_eq!(tree.node(tree.root()).size(), 260);
+    }
+
+    #[test]
+    fn directories_on_another_device_are_mount_points() {
+        let on = |entry: ScanEntry, device: u64| entry.with_metadata(FileMetadata { device, ..FileMetadata::default() });
+        let tree = build(vec![
+            on(directory("/memory/disk"), 7),
+            on(directory("/memory/disk/inner"), 7),
+            on(file("/memory/disk/inner/data", 10), 7),
+            on(directory("/memory/local"), 0),
+        ]);
+
+        // The root of "/memory" does not exist, so it is on device 0 like `local`
+        assert!(tree.node(lookup(&tree, "disk")).is_mount_point());
+        assert!(!tree.node(lookup(&tree, "disk/inner")).is_mount_point());
+        assert!(!tree.node(lookup(&tree, "local")).is_mount_point());
+        assert_eq!(tree.node(lookup(&tree, "disk")).mount().unwrap(), &MountInfo::from_statvfs(Path::new("/memory/disk")));
+    }
+}
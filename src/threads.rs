@@ -1,23 +1,48 @@
 use tokio::sync::mpsc;
 
+use crate::kernel::SizeMode;
+use crate::listing::{parse_age, DisplayOptions};
+use crate::scanner::ScanSnapshot;
+use crate::search::SearchQuery;
+use crate::query::Query;
+use crate::system::NodeId;
+
 pub enum Command {
     LoadDirectory(String),
-    Del(usize),
+    CancelLoad,
+    Del(String),
     Move(String, String),
     Create(String, bool),
     Undo(usize),
-    Commit,
-    Display,
+    /// Set to commit even items that changed on disk since the scan
+    Commit(bool),
+    Check,
+    /// Lists the trash without a target
+    Restore(Option<String>),
+    /// Purges what is at least this many seconds old, or everything
+    Purge(Option<i64>),
+    Display(DisplayOptions),
     Up,
-    Down(usize),
+    Down(String),
     Status,
-    Open(usize),
+    Open(String),
     GoTo(String),
     Find(String),
+    Search(SearchQuery),
+    Select(Query),
+    SavedQueries,
+    RunQuery(String),
+    /// Name and the text of the query
+    SaveQuery(String, String),
+    RemoveQuery(String),
     Exit,
     Error(String),
     AISuggestion(String),
     AIConfirm,
+    SizeMode(SizeMode),
+    Errors,
+    Stats,
+    Rescan(Option<String>),
     Help,
 }
 
@@ -25,6 +50,10 @@ pub enum BackendResponse {
     Response(String),
     AIResponse(String),
     Error(String),
+    /// Sent periodically while a directory is being scanned
+    LoadProgress(ScanSnapshot),
+    Loaded(String),
+    LoadCancelled(String),
 }
 
 pub async fn send_response(to_gui: &mpsc::Sender<BackendResponse>, message: String) {
@@ -50,15 +79,15 @@ pub fn parse_command(input: &str) -> Command {
     if input == ".." {
         Command::Up
     } else if input == "commit" {
-        Command::Commit
-    } else if input.starts_with("del ") {
-        if let Ok(index) = input[4..].trim().parse::<usize>() {
-            Command::Del(index)
-        } else {
-            Command::Error("Invalid command".to_string())
-        }
-    } else if input.starts_with("undo ") {
-        if let Ok(index) = input[5..].trim().parse::<usize>() {
+        Command::Commit(false)
+    } else if input == "commit --force" {
+        Command::Commit(true)
+    } else if input == "check" {
+        Command::Check
+    } else if let Some(target) = input.strip_prefix("del ") {
+        Command::Del(target.trim().to_string())
+    } else if let Some(rest) = input.strip_prefix("undo ") {
+        if let Ok(index) = rest.trim().parse::<usize>() {
             Command::Undo(index)
         } else {
             Command::Error("Invalid command".to_string())
@@ -66,52 +95,93 @@ pub fn parse_command(input: &str) -> Command {
     } else if input == "status" {
         Command::Status
     } else if input == "display" {
-        Command::Display
-    } else if let Ok(index) = input.parse::<usize>() {
-        Command::Down(index)
-    } else if input.starts_with("open ") {
-        if let Ok(index) = input[5..].trim().parse::<usize>() {
-            Command::Open(index)
-        } else {
-            Command::Error("Invalid command".to_string())
+        Command::Display(DisplayOptions::default())
+    } else if let Some(args) = input.strip_prefix("display ") {
+        match DisplayOptions::parse(args) {
+            Ok(options) => Command::Display(options),
+            Err(err) => Command::Error(err),
         }
-    } else if input.starts_with("go to ") {
-        if let Ok(path) = input[6..].trim().parse::<String>() {
-            return Command::GoTo(path);
+    } else if input.parse::<NodeId>().is_ok() || input.strip_prefix('#').is_some_and(|number| number.parse::<usize>().is_ok()) {
+        Command::Down(input.to_string())
+    } else if let Some(target) = input.strip_prefix("open ") {
+        Command::Open(target.trim().to_string())
+    } else if let Some(path) = input.strip_prefix("go to ") {
+        Command::GoTo(path.trim().to_string())
+    } else if input.split_whitespace().next().is_some_and(|word| word.eq_ignore_ascii_case("select")) {
+        match Query::parse(input) {
+            Ok(query) => Command::Select(query),
+            Err(err) => Command::Error(err),
         }
-        Command::Error("Invalid command".to_string())
-        
-    } else if input.starts_with("find ") {
-        if let Ok(item_name) = input[5..].trim().parse::<String>() {
-            return Command::Find(item_name)
-        } 
-        Command::Error("Invalid command".to_string())
-        
-    } else if input.starts_with("create ") {
-        if let Ok(item_type) = input[7..].trim().parse::<String>() {
-            if item_type.starts_with("file ") {
-                if let Ok(file_name) = item_type[5..].trim().parse::<String>() {
-                    return Command::Create(file_name, true);
-                }
-            } else if item_type.starts_with("folder ") {
-                if let Ok(file_name) = item_type[7..].trim().parse::<String>() {
-                    return Command::Create(file_name, false);
-                }
+    } else if input == "query" {
+        Command::SavedQueries
+    } else if let Some(args) = input.strip_prefix("query ") {
+        let args = args.trim();
+        if let Some(args) = args.strip_prefix("save ") {
+            match args.trim().split_once(char::is_whitespace) {
+                Some((name, _)) if name.contains('=') => Command::Error("A query name cannot contain '='".to_string()),
+                Some((name, query)) => match Query::parse(query.trim()) {
+                    Ok(_) => Command::SaveQuery(name.to_string(), query.trim().to_string()),
+                    Err(err) => Command::Error(err),
+                },
+                None => Command::Error("Use `query save <name> select ...`".to_string()),
             }
+        } else if let Some(name) = args.strip_prefix("remove ") {
+            Command::RemoveQuery(name.trim().to_string())
+        } else {
+            Command::RunQuery(args.to_string())
+        }
+    } else if input == "search" || input.starts_with("search ") {
+        match SearchQuery::parse(&input["search".len()..]) {
+            Ok(query) => Command::Search(query),
+            Err(err) => Command::Error(err),
+        }
+    } else if let Some(item_name) = input.strip_prefix("find ") {
+        Command::Find(item_name.trim().to_string())
+    } else if let Some(item_type) = input.strip_prefix("create ") {
+        let item_type = item_type.trim();
+        if let Some(file_name) = item_type.strip_prefix("file ") {
+            return Command::Create(file_name.trim().to_string(), true);
+        } else if let Some(file_name) = item_type.strip_prefix("folder ") {
+            return Command::Create(file_name.trim().to_string(), false);
         }
         Command::Error("Invalid command".to_string())
         
-    } else if input.starts_with("move ") {
-        if let Ok(paths) = input[5..].trim().parse::<String>() {
-            let paths_vec: Vec<&str> = paths.split(">").collect();
-            if paths_vec.len() != 2 {
-                Command::Error("Invalid command".to_string())
-            } else {
-                Command::Move(paths_vec[0].trim().to_string(), paths_vec[1].trim().to_string())
-            }
-        } else {
+    } else if let Some(paths) = input.strip_prefix("move ") {
+        let paths_vec: Vec<&str> = paths.trim().split('>').collect();
+        if paths_vec.len() != 2 {
             Command::Error("Invalid command".to_string())
+        } else {
+            Command::Move(paths_vec[0].trim().to_string(), paths_vec[1].trim().to_string())
+        }
+    } else if let Some(mode) = input.strip_prefix("size ") {
+        match mode.trim() {
+            "apparent" => Command::SizeMode(SizeMode::Apparent),
+            "allocated" => Command::SizeMode(SizeMode::Allocated),
+            _ => Command::Error("Invalid command".to_string()),
+        }
+    } else if input == "rescan" {
+        Command::Rescan(None)
+    } else if let Some(path) = input.strip_prefix("rescan ") {
+        Command::Rescan(Some(path.trim().to_string()))
+    } else if input == "restore" {
+        Command::Restore(None)
+    } else if let Some(target) = input.strip_prefix("restore ") {
+        Command::Restore(Some(target.trim().to_string()))
+    } else if input == "purge --all" {
+        Command::Purge(None)
+    } else if let Some(age) = input.strip_prefix("purge --older-than ") {
+        match parse_age(age) {
+            Ok(seconds) => Command::Purge(Some(seconds)),
+            Err(err) => Command::Error(err),
         }
+    } else if input == "purge" || input.starts_with("purge ") {
+        Command::Error("Use `purge --older-than <age>`, e.g. `purge --older-than 30d`, or `purge --all`".to_string())
+    } else if input == "errors" {
+        Command::Errors
+    } else if input == "stats" {
+        Command::Stats
+    } else if input == "cancel" {
+        Command::CancelLoad
     } else if input == "help" {
         Command::Help   
     } else {
@@ -0,0 +1,542 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::trash::{Trash, TrashedItem};
+
+/// One queued action with the paths it touches on disk worked out.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Create { path: PathBuf, is_file: bool },
+    Move { from: PathBuf, to: PathBuf },
+    Delete { path: PathBuf },
+}
+
+/// What became of an action when the queue was committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    /// Applied, then undone because a later action failed
+    RolledBack,
+    /// Applied, and undoing it failed too
+    NotRolledBack(String),
+    Failed(String),
+    /// Not attempted because an earlier action failed
+    NotRun,
+    /// A deletion of something no longer on disk
+    AlreadyGone,
+    /// A deletion inside a directory that the action at this index deletes
+    Covered(usize),
+}
+
+impl Outcome {
+    /// Whether the disk was left as the action asked.
+    pub fn is_applied(&self) -> bool {
+        matches!(self, Outcome::Applied | Outcome::NotRolledBack(_) | Outcome::AlreadyGone | Outcome::Covered(_))
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Applied => write!(f, "done"),
+            Outcome::RolledBack => write!(f, "undone after a later action failed"),
+            Outcome::NotRolledBack(err) => write!(f, "done, and could not be undone: {}", err),
+            Outcome::Failed(err) => write!(f, "failed: {}", err),
+            Outcome::NotRun => write!(f, "not attempted"),
+            Outcome::AlreadyGone => write!(f, "already gone"),
+            Outcome::Covered(index) => write!(f, "removed along with [{}]", index),
+        }
+    }
+}
+
+// What an operation did to the disk, as far as checking the ones after it is concerned
+enum Effect {
+    Created { path: PathBuf, is_dir: bool },
+    Removed(PathBuf),
+    Moved { from: PathBuf, to: PathBuf },
+}
+
+// Whether `path` is a directory (`Some(true)`), something else (`Some(false)`) or missing once
+// `effects` have been applied to what is on disk now
+fn kind_after(effects: &[(usize, Effect)], path: &Path) -> Option<bool> {
+    for (i, (_, effect)) in effects.iter().enumerate().rev() {
+        match effect {
+            Effect::Created { path: created, is_dir } => {
+                if path == created {
+                    return Some(*is_dir);
+                }
+                if created.starts_with(path) {
+                    return Some(true);
+                }
+                if path.starts_with(created) {
+                    return None;
+                }
+            }
+            Effect::Removed(removed) if path.starts_with(removed) => return None,
+            Effect::Removed(_) => {}
+            Effect::Moved { from, to } => {
+                // Joining an empty rest would add a slash, which only a directory takes
+                if path == to {
+                    return kind_after(&effects[..i], from);
+                }
+                if let Ok(rest) = path.strip_prefix(to) {
+                    return kind_after(&effects[..i], &from.join(rest));
+                }
+                if path.starts_with(from) {
+                    return None;
+                }
+            }
+        }
+    }
+    fs::symlink_metadata(path).ok().map(|metadata| metadata.is_dir())
+}
+
+// The action that put whatever is at `path` there, if it was not on disk already
+fn placed_by(effects: &[(usize, Effect)], path: &Path) -> Option<usize> {
+    effects.iter().rev().find_map(|(index, effect)| match effect {
+        Effect::Created { path: created, .. } if path == created => Some(*index),
+        Effect::Moved { to, .. } if path.starts_with(to) => Some(*index),
+        _ => None,
+    })
+}
+
+fn exists_message(effects: &[(usize, Effect)], path: &Path) -> String {
+    match placed_by(effects, path) {
+        Some(index) => format!("{} already exists, [{}] puts it there", path.display(), index),
+        None => format!("{} already exists", path.display()),
+    }
+}
+
+// Whether entries can be added to and removed from `dir`. A directory the queue has yet to
+// create is judged by the closest one above it that is on disk.
+fn writable(dir: &Path) -> Result<(), String> {
+    let Some(existing) = dir.ancestors().find(|ancestor| fs::symlink_metadata(ancestor).is_ok()) else {
+        return Ok(());
+    };
+    let Ok(c_path) = CString::new(existing.as_os_str().as_bytes()) else {
+        return Ok(());
+    };
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK | libc::X_OK) } == 0 {
+        Ok(())
+    } else {
+        Err(format!("no permission to change {}", existing.display()))
+    }
+}
+
+/// The order operations run in: the order they were queued in, so that a deletion followed by a
+/// creation at the same path replaces the item. Deletions that a deletion of a directory around
+/// them takes care of are left out.
+pub fn order(operations: &[Operation]) -> Vec<usize> {
+    (0..operations.len()).filter(|&index| covering(operations, index).is_none()).collect()
+}
+
+// The deletion whose directory takes the deletion at `index` with it, if any. Nothing queued
+// between the two may touch what is deleted, or skipping one of them would change the result.
+fn covering(operations: &[Operation], index: usize) -> Option<usize> {
+    let Operation::Delete { path } = &operations[index] else {
+        return None;
+    };
+    let touches = |operation: &Operation| match operation {
+        Operation::Create { path: created, .. } => created.starts_with(path),
+        Operation::Move { from, to } => from.starts_with(path) || to.starts_with(path),
+        Operation::Delete { .. } => false,
+    };
+    operations.iter().enumerate().position(|(other, operation)| {
+        let covers = match operation {
+            Operation::Delete { path: other_path } => (other_path != path && path.starts_with(other_path)) || (other_path == path && other < index),
+            _ => false,
+        };
+        covers && !operations[index.min(other) + 1..index.max(other)].iter().any(touches)
+    })
+}
+
+/// Walks the queue in the order it would run against what is on disk now, and returns every
+/// problem that would stop an action or make it pointless, by action index. An action can have
+/// several.
+pub fn validate(operations: &[Operation]) -> Vec<(usize, String)> {
+    let mut effects: Vec<(usize, Effect)> = Vec::new();
+    let mut problems = Vec::new();
+
+    for index in order(operations) {
+        let mut problem = |problem: String| problems.push((index, problem));
+        match &operations[index] {
+            Operation::Create { path, is_file } => {
+                let parent = path.parent().unwrap_or(path);
+                match (kind_after(&effects, path), is_file) {
+                    (Some(_), true) => problem(exists_message(&effects, path)),
+                    (Some(false), false) => problem(format!("{}, and is not a directory", exists_message(&effects, path))),
+                    (None, true) if kind_after(&effects, parent) != Some(true) => {
+                        problem(format!("{} is not a directory", parent.display()))
+                    }
+                    (None, false) => {
+                        let blocking = path
+                            .ancestors()
+                            .skip(1)
+                            .find_map(|ancestor| kind_after(&effects, ancestor).map(|is_dir| (ancestor, is_dir)))
+                            .filter(|(_, is_dir)| !is_dir);
+                        if let Some((ancestor, _)) = blocking {
+                            problem(format!("{} is not a directory", ancestor.display()));
+                        }
+                    }
+                    _ => {}
+                }
+                if let Err(err) = writable(parent) {
+                    problem(err);
+                }
+            }
+            Operation::Move { from, to } => {
+                let parent = to.parent().unwrap_or(to);
+                if kind_after(&effects, from).is_none() {
+                    problem(format!("{} does not exist", from.display()));
+                }
+                if kind_after(&effects, to).is_some() {
+                    problem(exists_message(&effects, to));
+                }
+                if to.starts_with(from) {
+                    problem(format!("{} cannot be moved into itself", from.display()));
+                } else if kind_after(&effects, parent) != Some(true) {
+                    problem(format!("{} is not a directory", parent.display()));
+                }
+                for dir in [from.parent().unwrap_or(from), parent] {
+                    if let Err(err) = writable(dir) {
+                        problem(err);
+                    }
+                }
+            }
+            // Something already gone is no reason to hold the rest back, but taking along what
+            // earlier actions put there makes them pointless
+            Operation::Delete { path } => {
+                for (other, operation) in operations.iter().enumerate().take(index) {
+                    match operation {
+                        Operation::Create { path: created, .. } if created.starts_with(path) => {
+                            problem(format!("it also deletes {}, which [{}] creates", created.display(), other));
+                        }
+                        Operation::Move { to, .. } if to.starts_with(path) => {
+                            problem(format!("it also deletes {}, which [{}] moves there", to.display(), other));
+                        }
+                        _ => {}
+                    }
+                }
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if let Err(err) = writable(path.parent().unwrap_or(path)) {
+                        problem(err);
+                    } else if metadata.is_dir() {
+                        if let Err(err) = writable(path) {
+                            problem(err);
+                        }
+                    }
+                }
+            }
+        }
+
+        effects.push((index, match &operations[index] {
+            Operation::Create { path, is_file } => Effect::Created { path: path.clone(), is_dir: !is_file },
+            Operation::Move { from, to } => Effect::Moved { from: from.clone(), to: to.clone() },
+            Operation::Delete { path } => Effect::Removed(path.clone()),
+        }));
+    }
+
+    problems.sort_by_key(|(index, _)| *index);
+    problems
+}
+
+// How to take back an operation that went through
+enum Undo {
+    Irreversible,
+    Untrash(TrashedItem),
+    RemoveFile(PathBuf),
+    /// The directories a creation made, deepest first
+    RemoveDirectories(Vec<PathBuf>),
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+fn run(operation: &Operation, trash: Option<&Trash>) -> Result<Undo, String> {
+    match operation {
+        Operation::Create { path, is_file: true } => fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map(|_| Undo::RemoveFile(path.clone()))
+            .map_err(|err| format!("cannot create {}: {}", path.display(), err)),
+        Operation::Create { path, is_file: false } => {
+            let missing: Vec<PathBuf> = path
+                .ancestors()
+                .take_while(|ancestor| fs::symlink_metadata(ancestor).is_err())
+                .map(Path::to_path_buf)
+                .collect();
+            fs::create_dir_all(path)
+                .map(|()| Undo::RemoveDirectories(missing))
+                .map_err(|err| format!("cannot create {}: {}", path.display(), err))
+        }
+        Operation::Move { from, to } => fs::rename(from, to)
+            .map(|()| Undo::Rename { from: to.clone(), to: from.clone() })
+            .map_err(|err| format!("cannot move {} to {}: {}", from.display(), to.display(), err)),
+        Operation::Delete { path } => {
+            if let Some(trash) = trash {
+                return trash.put(path).map(Undo::Untrash);
+            }
+            let removed = match fs::symlink_metadata(path) {
+                Err(_) => return Ok(Undo::Irreversible),
+                // A link is removed on its own, never the directory it points at
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+                Ok(_) => fs::remove_file(path),
+            };
+            removed.map(|()| Undo::Irreversible).map_err(|err| format!("cannot delete {}: {}", path.display(), err))
+        }
+    }
+}
+
+fn undo(undo: Undo, trash: Option<&Trash>) -> Result<(), String> {
+    match undo {
+        Undo::Irreversible => Ok(()),
+        Undo::Untrash(item) => trash.map_or(Ok(()), |trash| trash.restore(&item)),
+        Undo::RemoveFile(path) => fs::remove_file(&path).map_err(|err| format!("cannot remove {}: {}", path.display(), err)),
+        Undo::RemoveDirectories(paths) => paths
+            .iter()
+            .try_for_each(|path| fs::remove_dir(path).map_err(|err| format!("cannot remove {}: {}", path.display(), err))),
+        Undo::Rename { from, to } => {
+            fs::rename(&from, &to).map_err(|err| format!("cannot move {} back to {}: {}", from.display(), to.display(), err))
+        }
+    }
+}
+
+/// Carries out the operations in `order`, stopping at the first failure and undoing, newest
+/// first, whatever had gone through and can be undone. Deletions move items into `trash` when
+/// there is one, which makes them undoable too. Returns the outcome of every operation.
+pub fn apply(operations: &[Operation], trash: Option<&Trash>) -> Vec<Outcome> {
+    let mut outcomes: Vec<Outcome> = (0..operations.len())
+        .map(|index| covering(operations, index).map_or(Outcome::NotRun, Outcome::Covered))
+        .collect();
+
+    let mut done: Vec<(usize, Undo)> = Vec::new();
+    for index in order(operations) {
+        if let Operation::Delete { path } = &operations[index] {
+            if fs::symlink_metadata(path).is_err() {
+                outcomes[index] = Outcome::AlreadyGone;
+                continue;
+            }
+        }
+        match run(&operations[index], trash) {
+            Ok(undo) => {
+                outcomes[index] = Outcome::Applied;
+                done.push((index, undo));
+            }
+            Err(err) => {
+                outcomes[index] = Outcome::Failed(err);
+                for (index, step) in done.into_iter().rev() {
+                    if matches!(step, Undo::Irreversible) {
+                        continue;
+                    }
+                    outcomes[index] = match undo(step, trash) {
+                        Ok(()) => Outcome::RolledBack,
+                        Err(err) => Outcome::NotRolledBack(err),
+                    };
+                }
+                break;
+            }
+        }
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(path: PathBuf, is_file: bool) -> Operation {
+        Operation::Create { path, is_file }
+    }
+
+    fn rename(from: PathBuf, to: PathBuf) -> Operation {
+        Operation::Move { from, to }
+    }
+
+    fn delete(path: PathBuf) -> Operation {
+        Operation::Delete { path }
+    }
+
+    #[test]
+    fn a_failure_part_way_undoes_the_moves_and_creations_before_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a"), b"a").unwrap();
+        fs::write(root.join("doomed"), b"d").unwrap();
+
+        let operations = [
+            create(root.join("new/deep"), false),
+            create(root.join("new/deep/file"), true),
+            rename(root.join("a"), root.join("new/a")),
+            rename(root.join("missing"), root.join("b")),
+            delete(root.join("doomed")),
+            create(root.join("after"), true),
+        ];
+        let outcomes = apply(&operations, None);
+
+        assert_eq!(outcomes[..3], [Outcome::RolledBack, Outcome::RolledBack, Outcome::RolledBack]);
+        assert!(matches!(&outcomes[3], Outcome::Failed(err) if err.starts_with(&format!("cannot move {}", root.join("missing").display()))));
+        assert_eq!(outcomes[4..], [Outcome::NotRun, Outcome::NotRun]);
+        // Everything is as it was, the directories the creation made included
+        assert!(!root.join("new").exists());
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"a");
+        assert!(root.join("doomed").exists());
+        assert!(!root.join("after").exists());
+    }
+
+    #[test]
+    fn operations_run_in_queue_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let trash = Trash::quarantine(root.join("trash"));
+        fs::create_dir(root.join("old")).unwrap();
+        fs::write(root.join("old/inside"), b"x").unwrap();
+        fs::write(root.join("replaced"), b"old").unwrap();
+
+        // A deletion and a creation at the same path replace the item
+        let replace = [delete(root.join("replaced")), create(root.join("replaced"), true)];
+        assert!(validate(&replace).is_empty());
+        assert_eq!(apply(&replace, Some(&trash)), [Outcome::Applied, Outcome::Applied]);
+        assert_eq!(fs::read(root.join("replaced")).unwrap(), b"");
+
+        let operations = [
+            delete(root.join("replaced")),
+            create(root.join("replaced"), false),
+            delete(root.join("old/inside")),
+            delete(root.join("old")),
+            create(root.join("nowhere/file"), true),
+        ];
+        assert_eq!(order(&operations), [0, 1, 3, 4]);
+        let expected = format!("{} is not a directory", root.join("nowhere").display());
+        assert_eq!(validate(&operations), [(4, expected)]);
+
+        // The creation fails, so the deletions before it are put back along with the rest
+        let outcomes = apply(&operations, Some(&trash));
+        assert_eq!(outcomes[..4], [Outcome::RolledBack, Outcome::RolledBack, Outcome::Covered(3), Outcome::RolledBack]);
+        assert!(matches!(outcomes[4], Outcome::Failed(_)));
+        assert!(root.join("replaced").is_file());
+        assert!(root.join("old/inside").exists());
+
+        // A deletion is only left to one around it when nothing in between touches what it deletes
+        let operations = [delete(root.join("old/inside")), rename(root.join("old/inside"), root.join("kept")), delete(root.join("old"))];
+        assert_eq!(order(&operations), [0, 1, 2]);
+        assert_eq!(validate(&operations).into_iter().map(|(index, _)| index).collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn outcomes_report_each_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("tree/below")).unwrap();
+        fs::write(root.join("file"), b"x").unwrap();
+
+        let operations = [
+            create(root.join("made"), false),
+            rename(root.join("file"), root.join("made/file")),
+            delete(root.join("tree/below")),
+            delete(root.join("tree")),
+            delete(root.join("gone")),
+        ];
+        let outcomes = apply(&operations, None);
+        assert_eq!(outcomes, [Outcome::Applied, Outcome::Applied, Outcome::Covered(3), Outcome::Applied, Outcome::AlreadyGone]);
+        assert!(outcomes.iter().all(Outcome::is_applied));
+        assert!(root.join("made/file").exists());
+        assert!(!root.join("tree").exists());
+
+        let shown: Vec<String> = outcomes.iter().map(Outcome::to_string).collect();
+        assert_eq!(shown, ["done", "done", "removed along with [3]", "done", "already gone"]);
+        assert_eq!(Outcome::RolledBack.to_string(), "undone after a later action failed");
+        assert_eq!(Outcome::NotRun.to_string(), "not attempted");
+        assert!(!Outcome::RolledBack.is_applied() && !Outcome::NotRun.is_applied());
+    }
+
+    #[test]
+    fn deletions_into_the_trash_are_undone_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let trash = Trash::quarantine(root.join("trash"));
+        fs::write(root.join("first"), b"1").unwrap();
+        // Its record in the trash would need a name longer than the filesystem allows
+        let long = root.join("x".repeat(250));
+        fs::write(&long, b"2").unwrap();
+
+        let outcomes = apply(&[delete(root.join("first")), delete(long.clone())], Some(&trash));
+        assert_eq!(outcomes[0], Outcome::RolledBack);
+        assert!(matches!(outcomes[1], Outcome::Failed(_)));
+        assert_eq!(fs::read(root.join("first")).unwrap(), b"1");
+        assert!(long.exists());
+        assert!(trash.items().unwrap().is_empty());
+    }
+
+    #[test]
+    fn two_moves_to_the_same_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for name in ["a", "b", "dest"] {
+            fs::create_dir(root.join(name)).unwrap();
+        }
+        fs::write(root.join("a/x"), b"a").unwrap();
+        fs::write(root.join("b/x"), b"b").unwrap();
+
+        let operations = [
+            rename(root.join("a/x"), root.join("dest/x")),
+            create(root.join("unrelated"), true),
+            rename(root.join("b/x"), root.join("dest/x")),
+        ];
+        let expected = format!("{} already exists, [0] puts it there", root.join("dest/x").display());
+        assert_eq!(validate(&operations), [(2, expected)]);
+    }
+
+    #[test]
+    fn deleting_a_directory_something_is_moved_into() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("parent")).unwrap();
+        fs::write(root.join("item"), b"x").unwrap();
+
+        let operations = [rename(root.join("item"), root.join("parent/item")), delete(root.join("parent"))];
+        let expected = format!("it also deletes {}, which [0] moves there", root.join("parent/item").display());
+        assert_eq!(validate(&operations), [(1, expected)]);
+    }
+
+    #[test]
+    fn creating_around_a_deletion_of_its_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("doomed")).unwrap();
+        let doomed = |path: &str| root.join("doomed").join(path).display().to_string();
+
+        // Created first, the entries would be deleted right after
+        let operations = [create(root.join("doomed/new"), true), create(root.join("doomed/sub/deeper"), false), delete(root.join("doomed"))];
+        assert_eq!(
+            validate(&operations),
+            [
+                (2, format!("it also deletes {}, which [0] creates", doomed("new"))),
+                (2, format!("it also deletes {}, which [1] creates", doomed("sub/deeper"))),
+            ]
+        );
+
+        // Created after, a file has no directory left to go in, while a directory makes its own
+        let operations = [delete(root.join("doomed")), create(root.join("doomed/new"), true), create(root.join("doomed/sub/deeper"), false)];
+        assert_eq!(validate(&operations), [(1, format!("{} is not a directory", root.join("doomed").display()))]);
+    }
+
+    #[test]
+    fn later_actions_are_checked_against_what_earlier_ones_leave() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("file"), b"x").unwrap();
+
+        let operations = [
+            create(root.join("made"), false),
+            rename(root.join("file"), root.join("made/file")),
+            rename(root.join("file"), root.join("again")),
+            create(root.join("made/file/below"), true),
+            rename(root.join("made"), root.join("made/inner")),
+        ];
+        let problems: Vec<usize> = validate(&operations).into_iter().map(|(index, _)| index).collect();
+        assert_eq!(problems, [2, 3, 4]);
+        assert!(validate(&operations[..2]).is_empty());
+    }
+}
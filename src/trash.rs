@@ -0,0 +1,400 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs::{self, DirBuilder};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::listing;
+use crate::query::parse_date;
+use crate::system::mounted_volumes;
+
+/// Where deleted items go instead of being removed for good. It is laid out as the
+/// freedesktop.org trash specification describes: the items themselves under `files`, and for
+/// each a `.trashinfo` file under `info` recording where it came from and when it was deleted.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    home: Bin,
+    /// Whether items on other filesystems go to a trash at the top of their own volume
+    volumes: bool,
+}
+
+/// One `files` and `info` pair. A trash at the top of a volume records origins relative to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bin {
+    files: PathBuf,
+    info: PathBuf,
+    top: Option<PathBuf>,
+}
+
+/// An item in the trash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashedItem {
+    /// Its name under the trash's `files` directory
+    pub name: String,
+    pub origin: PathBuf,
+    /// Seconds since the Unix epoch
+    pub deleted: i64,
+    bin: Bin,
+}
+
+impl Trash {
+    /// The user's own trash: `$XDG_DATA_HOME/Trash` on Linux, where items on other filesystems go
+    /// to `.Trash/$uid` or `.Trash-$uid` at the top of their volume instead. On macOS it is
+    /// `~/.Trash`, and since the Finder's own records are not open to other programs, the origin
+    /// of each item is kept in `~/Library/Application Support/fsm/Trash`.
+    pub fn platform() -> Result<Self, String> {
+        let home = env::var_os("HOME").map(PathBuf::from).ok_or_else(|| "HOME is not set, so there is no trash to use".to_string())?;
+        if cfg!(target_os = "macos") {
+            let info = home.join("Library").join("Application Support").join("fsm").join("Trash");
+            return Ok(Trash { home: Bin { files: home.join(".Trash"), info, top: None }, volumes: true });
+        }
+        let data = env::var_os("XDG_DATA_HOME").map(PathBuf::from).unwrap_or_else(|| home.join(".local").join("share"));
+        Ok(Trash { volumes: true, ..Self::quarantine(data.join("Trash")) })
+    }
+
+    /// A trash of its own in `dir`, laid out as the platform trash is on Linux.
+    pub fn quarantine(dir: PathBuf) -> Self {
+        Trash { home: Bin::at(dir, None), volumes: false }
+    }
+
+    /// Where the trashed items are.
+    pub fn location(&self) -> &Path {
+        &self.home.files
+    }
+
+    /// Moves `path` into the trash under a name no other item there has, after recording where
+    /// it came from. Nothing is copied: an item on another filesystem goes to the trash at the top
+    /// of its volume on Linux, and cannot be trashed otherwise.
+    pub fn put(&self, path: &Path) -> Result<TrashedItem, String> {
+        let bin = self.bin_for(path)?;
+        let fail = |err: io::Error| format!("cannot move {} to the trash in {}: {}", path.display(), bin.files.display(), err);
+        for dir in [&bin.files, &bin.info] {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(fail)?;
+        }
+
+        let base = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let deleted = listing::now();
+        let recorded = bin.top.as_ref().and_then(|top| path.strip_prefix(top).ok()).unwrap_or(path);
+        let contents = format!("[Trash Info]\nPath={}\nDeletionDate={}\n", encode(recorded), format_date(deleted));
+        for attempt in 1.. {
+            let name = if attempt == 1 { base.clone() } else { format!("{}.{}", base, attempt) };
+            // Creating the info file first claims the name, as the specification asks
+            let info_path = bin.info_path(&name);
+            let mut info = match fs::OpenOptions::new().write(true).create_new(true).open(&info_path) {
+                Ok(info) => info,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(fail(err)),
+            };
+            let target = bin.files.join(&name);
+            let moved = if fs::symlink_metadata(&target).is_ok() {
+                Err(io::Error::from(io::ErrorKind::AlreadyExists))
+            } else {
+                info.write_all(contents.as_bytes()).and_then(|()| fs::rename(path, &target))
+            };
+            match moved {
+                Ok(()) => return Ok(TrashedItem { name, origin: path.to_path_buf(), deleted, bin }),
+                Err(err) => {
+                    let _ = fs::remove_file(&info_path);
+                    if err.kind() == io::ErrorKind::AlreadyExists {
+                        continue;
+                    }
+                    if err.kind() == io::ErrorKind::CrossesDevices {
+                        return Err(format!(
+                            "cannot move {} to the trash in {}, which is on another filesystem; use a --quarantine directory on that filesystem",
+                            path.display(),
+                            bin.files.display()
+                        ));
+                    }
+                    return Err(fail(err));
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    /// Where `put` would move `path` to.
+    pub fn location_for(&self, path: &Path) -> Result<PathBuf, String> {
+        self.bin_for(path).map(|bin| bin.files)
+    }
+
+    // The home trash, unless `path` is on another volume and that volume can have a trash of its own
+    fn bin_for(&self, path: &Path) -> Result<Bin, String> {
+        if !self.volumes {
+            return Ok(self.home.clone());
+        }
+        let device = |path: &Path| path.ancestors().find_map(|ancestor| fs::symlink_metadata(ancestor).ok()).map(|metadata| metadata.dev());
+        if device(path) == device(&self.home.files) {
+            return Ok(self.home.clone());
+        }
+        if cfg!(target_os = "macos") {
+            return Err(format!(
+                "cannot move {} to the trash in {}, which is on another volume; use a --quarantine directory on that volume",
+                path.display(),
+                self.home.files.display()
+            ));
+        }
+        Ok(Bin::on_volume(&volume_top(path), true))
+    }
+
+    /// Puts an item back where it came from, making the directories above it again if they are
+    /// gone. Refuses to replace anything that took its place meanwhile.
+    pub fn restore(&self, item: &TrashedItem) -> Result<(), String> {
+        if fs::symlink_metadata(&item.origin).is_ok() {
+            return Err(format!("cannot restore {}, something else is there now", item.origin.display()));
+        }
+        if let Some(parent) = item.origin.parent() {
+            fs::create_dir_all(parent).map_err(|err| format!("cannot restore {}: {}", item.origin.display(), err))?;
+        }
+        fs::rename(item.bin.files.join(&item.name), &item.origin).map_err(|err| format!("cannot restore {}: {}", item.origin.display(), err))?;
+        fs::remove_file(item.bin.info_path(&item.name)).map_err(|err| format!("restored {}, but cannot remove its record: {}", item.origin.display(), err))
+    }
+
+    /// Removes an item from the trash for good.
+    pub fn purge(&self, item: &TrashedItem) -> Result<(), String> {
+        let path = item.bin.files.join(&item.name);
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(_) => Ok(()),
+        };
+        removed
+            .and_then(|()| fs::remove_file(item.bin.info_path(&item.name)))
+            .map_err(|err| format!("cannot purge {}: {}", item.origin.display(), err))
+    }
+
+    /// Everything in the trash that has a record of where it came from, oldest first, so that
+    /// the numbers `restore` shows stay put as more is deleted. For the platform trash on Linux
+    /// this takes in the trashes at the top of every mounted volume.
+    pub fn items(&self) -> Result<Vec<TrashedItem>, String> {
+        let mut items = self.home.items()?;
+        if self.volumes && cfg!(target_os = "linux") {
+            for top in mounted_volumes().into_keys() {
+                let bin = Bin::on_volume(&top, false);
+                if bin != self.home && bin.info.is_dir() {
+                    // Another user's or an unreadable volume trash is not ours to list
+                    items.extend(bin.items().unwrap_or_default());
+                }
+            }
+        }
+        items.sort_by(|a, b| a.deleted.cmp(&b.deleted).then_with(|| a.name.cmp(&b.name)));
+        Ok(items)
+    }
+}
+
+impl TrashedItem {
+    /// The `files` directory of the trash it is in.
+    pub fn location(&self) -> &Path {
+        &self.bin.files
+    }
+}
+
+impl Bin {
+    fn at(dir: PathBuf, top: Option<PathBuf>) -> Self {
+        Bin { files: dir.join("files"), info: dir.join("info"), top }
+    }
+
+    // `$top/.Trash/$uid` when an administrator set up `.Trash` as the specification asks, a sticky
+    // directory that is not a symlink. Otherwise `$top/.Trash-$uid`, which `put` makes as needed.
+    // Only the latter is taken when looking for existing items and `.Trash/$uid` is missing.
+    fn on_volume(top: &Path, creating: bool) -> Self {
+        let uid = unsafe { libc::getuid() };
+        let shared = top.join(".Trash");
+        let sticky = fs::symlink_metadata(&shared).is_ok_and(|metadata| metadata.is_dir() && metadata.mode() & 0o1000 != 0);
+        if sticky && (creating || shared.join(uid.to_string()).is_dir()) {
+            return Bin::at(shared.join(uid.to_string()), Some(top.to_path_buf()));
+        }
+        Bin::at(top.join(format!(".Trash-{}", uid)), Some(top.to_path_buf()))
+    }
+
+    fn info_path(&self, name: &str) -> PathBuf {
+        self.info.join(format!("{}.trashinfo", name))
+    }
+
+    fn items(&self) -> Result<Vec<TrashedItem>, String> {
+        let entries = match fs::read_dir(&self.info) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(format!("cannot read the trash in {}: {}", self.info.display(), err)),
+        };
+        Ok(entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().to_string_lossy().strip_suffix(".trashinfo")?.to_string();
+                let contents = fs::read_to_string(entry.path()).ok()?;
+                let field = |key: &str| contents.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('=')).map(str::trim);
+                let origin = decode(field("Path")?);
+                // Relative paths are below the top of the volume the trash is on
+                let origin = match &self.top {
+                    Some(top) if origin.is_relative() => top.join(origin),
+                    _ => origin,
+                };
+                let deleted = field("DeletionDate").and_then(|date| parse_date(date).ok()).unwrap_or(0);
+                Some(TrashedItem { name, origin, deleted, bin: self.clone() })
+            })
+            .collect())
+    }
+}
+
+// The outermost directory above `path` that is still on the same filesystem as the nearest
+// part of it that exists
+fn volume_top(path: &Path) -> PathBuf {
+    let mut top = path;
+    let mut device = None;
+    for ancestor in path.ancestors() {
+        match (fs::symlink_metadata(ancestor), device) {
+            (Ok(metadata), None) => device = Some(metadata.dev()),
+            (Ok(metadata), Some(device)) if metadata.dev() == device => {}
+            (Err(_), None) => continue,
+            _ => break,
+        }
+        top = ancestor;
+    }
+    top.to_path_buf()
+}
+
+// Local time without a zone, as `DeletionDate` is written
+fn format_date(timestamp: i64) -> String {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// `Path` is percent-encoded, keeping the slashes
+fn encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+fn decode(text: &str) -> PathBuf {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn paths_are_percent_encoded_byte_for_byte() {
+        let path = Path::new("/home/me/100% done/résumé #2.txt");
+        let encoded = encode(path);
+        assert_eq!(encoded, "/home/me/100%25%20done/r%C3%A9sum%C3%A9%20%232.txt");
+        assert_eq!(decode(&encoded), path);
+
+        let raw = PathBuf::from(OsString::from_vec(b"/tmp/not\xffutf8".to_vec()));
+        assert_eq!(encode(&raw), "/tmp/not%FFutf8");
+        assert_eq!(decode(&encode(&raw)), raw);
+
+        // A stray percent sign is kept as it is
+        assert_eq!(decode("/a%zz/b%4"), Path::new("/a%zz/b%4"));
+    }
+
+    #[test]
+    fn trashinfo_records_what_items_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("other")).unwrap();
+        for path in ["my notes.txt", "other/my notes.txt"] {
+            fs::write(root.join(path), b"x").unwrap();
+        }
+        let trash = Trash::quarantine(root.join("trash"));
+        let first = trash.put(&root.join("my notes.txt")).unwrap();
+        let second = trash.put(&root.join("other/my notes.txt")).unwrap();
+
+        // The second item gets a name of its own
+        assert_eq!(first.name, "my notes.txt");
+        assert_eq!(second.name, "my notes.txt.2");
+        let contents = fs::read_to_string(root.join("trash/info/my notes.txt.trashinfo")).unwrap();
+        let expected = format!("[Trash Info]\nPath={}\nDeletionDate={}\n", encode(&root.join("my notes.txt")), format_date(first.deleted));
+        assert_eq!(contents, expected);
+        assert!(root.join("trash/files/my notes.txt.2").exists());
+
+        assert_eq!(trash.items().unwrap(), [first, second]);
+    }
+
+    #[test]
+    fn restore_refuses_to_replace_and_makes_missing_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("gone/deep")).unwrap();
+        fs::write(root.join("taken"), b"old").unwrap();
+        fs::write(root.join("gone/deep/file"), b"x").unwrap();
+        let trash = Trash::quarantine(root.join("trash"));
+        let taken = trash.put(&root.join("taken")).unwrap();
+        let deep = trash.put(&root.join("gone/deep/file")).unwrap();
+
+        fs::write(root.join("taken"), b"new").unwrap();
+        let err = trash.restore(&taken).unwrap_err();
+        assert!(err.contains("something else is there now"), "{}", err);
+        assert_eq!(fs::read(root.join("taken")).unwrap(), b"new");
+        assert_eq!(trash.items().unwrap().len(), 2);
+
+        fs::remove_dir_all(root.join("gone")).unwrap();
+        trash.restore(&deep).unwrap();
+        assert!(root.join("gone/deep/file").is_file());
+        assert_eq!(trash.items().unwrap(), [taken]);
+    }
+
+    #[test]
+    fn volume_trash_records_paths_below_its_top() {
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path();
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(Bin::on_volume(top, true).files, top.join(format!(".Trash-{}", uid)).join("files"));
+
+        // `.Trash` only counts when it is sticky
+        fs::create_dir(top.join(".Trash")).unwrap();
+        assert_eq!(Bin::on_volume(top, true).files, top.join(format!(".Trash-{}", uid)).join("files"));
+        fs::set_permissions(top.join(".Trash"), fs::Permissions::from_mode(0o1777)).unwrap();
+        let bin = Bin::on_volume(top, true);
+        assert_eq!(bin.files, top.join(".Trash").join(uid.to_string()).join("files"));
+
+        fs::create_dir(top.join("sub")).unwrap();
+        fs::write(top.join("sub/file"), b"x").unwrap();
+        let trash = Trash { home: bin.clone(), volumes: false };
+        let item = trash.put(&top.join("sub/file")).unwrap();
+        let contents = fs::read_to_string(bin.info_path("file")).unwrap();
+        assert!(contents.contains("\nPath=sub/file\n"), "{}", contents);
+        assert_eq!(item.origin, top.join("sub/file"));
+        assert_eq!(trash.items().unwrap(), [item]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+use crate::scanner::ScanOptions;
+use crate::system::FileSystemTree;
+
+#[cfg(target_os = "macos")]
+use crate::mac;
+#[cfg(target_os = "linux")]
+use crate::linux;
+
+/// A change reported by the platform watcher.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WatchEvent {
+    /// The entry was created, written to or had its metadata changed
+    Changed(PathBuf),
+    /// The entry was deleted or renamed away
+    Removed(PathBuf),
+    /// Events were dropped, so everything below the path has to be scanned again
+    Rescan(PathBuf),
+}
+
+impl WatchEvent {
+    pub fn path(&self) -> &Path {
+        match self {
+            WatchEvent::Changed(path) | WatchEvent::Removed(path) | WatchEvent::Rescan(path) => path,
+        }
+    }
+}
+
+/// `first` and whatever else is already waiting in `events`, each distinct event once, in the
+/// order they arrived.
+pub fn coalesce(first: WatchEvent, events: &mut mpsc::UnboundedReceiver<WatchEvent>) -> Vec<WatchEvent> {
+    let mut seen = HashSet::new();
+    std::iter::once(first)
+        .chain(std::iter::from_fn(|| events.try_recv().ok()))
+        .filter(|event| seen.insert(event.clone()))
+        .collect()
+}
+
+/// Starts watching everything below the root of `tree` on a background thread: inotify on Linux, FSEvents
+/// on macOS. Events arrive in the order the kernel reported them and stop once the receiver
+/// is dropped.
+pub fn start(tree: &FileSystemTree, options: &ScanOptions) -> io::Result<mpsc::UnboundedReceiver<WatchEvent>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let root_path = tree.root_path().to_path_buf();
+
+    #[cfg(target_os = "linux")]
+    {
+        // inotify is not recursive, so every directory in the model gets its own watch
+        let directories = tree
+            .walk(tree.root())
+            .filter(|id| !tree.node(*id).is_file())
+            .map(|id| tree.get_path(id))
+            .collect();
+        linux::watch(root_path, directories, options.clone(), sender)?;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _ = options;
+        mac::watch(root_path, sender)?;
+    }
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_is_coalesced_in_arrival_order() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let changed = |name: &str| WatchEvent::Changed(PathBuf::from(name));
+        for event in [changed("b"), changed("a"), WatchEvent::Removed(PathBuf::from("a")), changed("b"), changed("c")] {
+            sender.send(event).unwrap();
+        }
+        let batch = coalesce(changed("a"), &mut receiver);
+        assert_eq!(batch, [changed("a"), changed("b"), WatchEvent::Removed(PathBuf::from("a")), changed("c")]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    // Collects events until `done` accepts what arrived so far, or gives up after a few seconds
+    #[cfg(target_os = "linux")]
+    fn wait_for(events: &mut mpsc::UnboundedReceiver<WatchEvent>, done: impl Fn(&[WatchEvent]) -> bool) -> Vec<WatchEvent> {
+        use std::time::{Duration, Instant};
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut received = Vec::new();
+        while !done(&received) && Instant::now() < deadline {
+            match events.try_recv() {
+                Ok(event) => received.push(event),
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        received
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn inotify_reports_changes_below_new_directories_too() {
+        use std::fs;
+        use crate::scanner::{NativeScanner, ScanProgress};
+        use crate::system::build_fs_model_blocking;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir(root.join("old")).unwrap();
+        fs::write(root.join("old/file"), b"x").unwrap();
+        let tree = build_fs_model_blocking(root.to_string_lossy().into_owned(), &NativeScanner, ScanOptions::default(), &ScanProgress::new()).unwrap();
+        let mut events = start(&tree, &ScanOptions::default()).unwrap();
+
+        fs::write(root.join("old/file"), b"longer").unwrap();
+        fs::create_dir(root.join("new")).unwrap();
+        // Give the watcher time to pick up the new directory before writing into it
+        let _ = wait_for(&mut events, |received| received.contains(&WatchEvent::Changed(root.join("new"))));
+        fs::write(root.join("new/inner"), b"y").unwrap();
+        fs::rename(root.join("old/file"), root.join("moved")).unwrap();
+
+        let expected = [
+            WatchEvent::Changed(root.join("new/inner")),
+            WatchEvent::Removed(root.join("old/file")),
+            WatchEvent::Changed(root.join("moved")),
+        ];
+        let received = wait_for(&mut events, |received| expected.iter().all(|event| received.contains(event)));
+        for event in &expected {
+            assert!(received.contains(event), "{:?} missing from {:?}", event, received);
+        }
+    }
+}